@@ -0,0 +1,575 @@
+use crate::error::{BodyTooLarge, IntegrityError};
+use crate::{ipfs, Config};
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzDecoder as GzWriteDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::body::HttpBody;
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use tracing::{debug, error};
+
+/// Where values over `config.mem_threshold` are offloaded to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobBackend {
+    #[default]
+    Ipfs,
+    Fs,
+    /// needs the `s3` cargo feature
+    S3,
+}
+
+/// Raw byte storage for offloaded values. Compression and integrity checks are
+/// handled by `add` and `get` here, so implementations only move bytes.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `payload` and returns the id to fetch it by.
+    async fn add(&self, payload: Vec<u8>, config: &Config) -> Result<String, Box<dyn Error>>;
+    async fn get(&self, id: &str, config: &Config) -> Result<Vec<u8>, Box<dyn Error>>;
+    async fn delete(&self, id: &str, config: &Config) -> Result<(), Box<dyn Error>>;
+
+    /// Like `add`, for a payload that arrives as a body. Backends that can't stream
+    /// buffer the whole payload.
+    async fn add_stream(&self, payload: Body, config: &Config) -> Result<String, Box<dyn Error>> {
+        let bytes = hyper::body::to_bytes(payload).await?;
+        self.add(bytes.to_vec(), config).await
+    }
+
+    /// The stored bytes as a body that can be sent on as it arrives. Backends that
+    /// can't stream buffer the whole payload.
+    async fn get_stream(&self, id: &str, config: &Config) -> Result<Body, Box<dyn Error>> {
+        let bytes = self.get(id, config).await?;
+        Ok(Body::from(bytes))
+    }
+
+    /// The number of bytes stored under `id`. Backends that can't ask for it fetch
+    /// the whole payload.
+    async fn stat(&self, id: &str, config: &Config) -> Result<usize, Box<dyn Error>> {
+        self.get(id, config).await.map(|bytes| bytes.len())
+    }
+
+    /// Makes sure `id`, added elsewhere, is kept here. Backends that can't fetch
+    /// content from anywhere else only check it is already stored.
+    async fn pin(&self, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+        self.stat(id, config).await.map(|_| ())
+    }
+}
+
+pub struct Ipfs;
+
+#[async_trait]
+impl BlobStore for Ipfs {
+    async fn add(&self, payload: Vec<u8>, config: &Config) -> Result<String, Box<dyn Error>> {
+        ipfs::add(payload, config).await
+    }
+
+    async fn get(&self, id: &str, config: &Config) -> Result<Vec<u8>, Box<dyn Error>> {
+        ipfs::get(id, config).await
+    }
+
+    async fn delete(&self, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+        ipfs::delete(id, config).await
+    }
+
+    async fn add_stream(&self, payload: Body, config: &Config) -> Result<String, Box<dyn Error>> {
+        ipfs::add_stream(payload, config).await
+    }
+
+    async fn get_stream(&self, id: &str, config: &Config) -> Result<Body, Box<dyn Error>> {
+        ipfs::get_stream(id, config).await
+    }
+
+    async fn stat(&self, id: &str, config: &Config) -> Result<usize, Box<dyn Error>> {
+        ipfs::stat(id, config).await
+    }
+
+    async fn pin(&self, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+        ipfs::pin(id, config).await
+    }
+}
+
+/// Files under `config.blob_dir` named by the sha256 of their content, fanned out
+/// into directories by the first two hex characters.
+pub struct Fs;
+
+impl Fs {
+    fn path(id: &str, config: &Config) -> Result<PathBuf, Box<dyn Error>> {
+        // ids come back from redis, but never let one walk out of blob_dir
+        if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("invalid blob id {}", id).into());
+        }
+        Ok(PathBuf::from(&config.blob_dir).join(&id[..2]).join(id))
+    }
+}
+
+#[async_trait]
+impl BlobStore for Fs {
+    async fn add(&self, payload: Vec<u8>, config: &Config) -> Result<String, Box<dyn Error>> {
+        let id = content_id(&payload);
+        let path = Fs::path(&id, config)?;
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(id);
+        }
+        let dir = path.parent().ok_or("blob path has no parent")?;
+        tokio::fs::create_dir_all(dir).await?;
+        // write then rename so readers never see a partial file
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        tokio::fs::write(&tmp, &payload).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        debug!(id = %id, bytes = payload.len(), "added blob file");
+        Ok(id)
+    }
+
+    async fn get(&self, id: &str, config: &Config) -> Result<Vec<u8>, Box<dyn Error>> {
+        let path = Fs::path(id, config)?;
+        Ok(tokio::fs::read(path).await?)
+    }
+
+    async fn delete(&self, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+        let path = Fs::path(id, config)?;
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn stat(&self, id: &str, config: &Config) -> Result<usize, Box<dyn Error>> {
+        let path = Fs::path(id, config)?;
+        Ok(tokio::fs::metadata(path).await?.len() as usize)
+    }
+}
+
+/// Objects in `config.s3_bucket` keyed by the sha256 of their content.
+pub struct S3;
+
+#[cfg(feature = "s3")]
+impl S3 {
+    fn bucket(config: &Config) -> Result<s3::Bucket, Box<dyn Error>> {
+        let region = s3::Region::Custom {
+            region: config.s3_region.clone(),
+            endpoint: config.s3_endpoint.clone(),
+        };
+        let credentials = s3::creds::Credentials::new(
+            Some(&config.s3_access_key),
+            Some(&config.s3_secret_key),
+            None,
+            None,
+            None,
+        )?;
+        // path style works with MinIO and most other S3 compatible stores
+        Ok(s3::Bucket::new(&config.s3_bucket, region, credentials)?.with_path_style())
+    }
+}
+
+#[cfg(feature = "s3")]
+#[async_trait]
+impl BlobStore for S3 {
+    async fn add(&self, payload: Vec<u8>, config: &Config) -> Result<String, Box<dyn Error>> {
+        let id = content_id(&payload);
+        let bucket = S3::bucket(config)?;
+        bucket.put_object(&id, &payload).await?;
+        debug!(id = %id, bytes = payload.len(), "added blob object");
+        Ok(id)
+    }
+
+    async fn get(&self, id: &str, config: &Config) -> Result<Vec<u8>, Box<dyn Error>> {
+        let bucket = S3::bucket(config)?;
+        let resp = bucket.get_object(id).await?;
+        Ok(resp.bytes().to_vec())
+    }
+
+    async fn delete(&self, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+        let bucket = S3::bucket(config)?;
+        bucket.delete_object(id).await?;
+        Ok(())
+    }
+
+    async fn stat(&self, id: &str, config: &Config) -> Result<usize, Box<dyn Error>> {
+        let bucket = S3::bucket(config)?;
+        let (head, status) = bucket.head_object(id).await?;
+        if status != 200 {
+            return Err(format!("head of blob object {} returned {}", id, status).into());
+        }
+        let length = head
+            .content_length
+            .ok_or("blob object has no content length")?;
+        Ok(length as usize)
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+#[async_trait]
+impl BlobStore for S3 {
+    async fn add(&self, _payload: Vec<u8>, _config: &Config) -> Result<String, Box<dyn Error>> {
+        Err(S3_DISABLED.into())
+    }
+
+    async fn get(&self, _id: &str, _config: &Config) -> Result<Vec<u8>, Box<dyn Error>> {
+        Err(S3_DISABLED.into())
+    }
+
+    async fn delete(&self, _id: &str, _config: &Config) -> Result<(), Box<dyn Error>> {
+        Err(S3_DISABLED.into())
+    }
+
+    async fn stat(&self, _id: &str, _config: &Config) -> Result<usize, Box<dyn Error>> {
+        Err(S3_DISABLED.into())
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+const S3_DISABLED: &str = "built without the s3 feature";
+
+pub fn store(backend: BlobBackend) -> &'static dyn BlobStore {
+    match backend {
+        BlobBackend::Ipfs => &Ipfs,
+        BlobBackend::Fs => &Fs,
+        BlobBackend::S3 => &S3,
+    }
+}
+
+fn content_id(payload: &[u8]) -> String {
+    hex::encode(Sha256::digest(payload))
+}
+
+/// An offloaded value as recorded next to its key.
+pub struct Blob {
+    pub id: String,
+    pub compressed: bool,
+    /// sha256 of the bytes handed to the backend
+    pub digest: String,
+}
+
+/// Offloads `value` to `config.blob_store`, gzipping it first when
+/// `config.ipfs_compress` is set.
+pub async fn add(value: &str, config: &Config) -> Result<Blob, Box<dyn Error>> {
+    let payload = if config.ipfs_compress {
+        compress(value.as_bytes())?
+    } else {
+        value.as_bytes().to_vec()
+    };
+    let digest = hex::encode(Sha256::digest(&payload));
+    let id = store(config.blob_store).add(payload, config).await?;
+    Ok(Blob {
+        id,
+        compressed: config.ipfs_compress,
+        digest,
+    })
+}
+
+enum PumpError {
+    TooLarge,
+    Failed(String),
+}
+
+/// Like `add`, for a value arriving as a request body. It is compressed and hashed
+/// as it passes through to the backend, and the upload fails with `BodyTooLarge`
+/// once more than `max_bytes` have come in. Returns the value's length alongside.
+pub async fn add_stream(
+    mut body: Body,
+    max_bytes: usize,
+    config: &Config,
+) -> Result<(Blob, usize), Box<dyn Error>> {
+    let compressed = config.ipfs_compress;
+    let (mut sender, payload) = Body::channel();
+    let pump = tokio::spawn(async move {
+        let mut hasher = Sha256::new();
+        let mut encoder = compressed.then(|| GzEncoder::new(Vec::new(), Compression::default()));
+        let mut size = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    sender.abort();
+                    return Err(PumpError::Failed(e.to_string()));
+                }
+            };
+            size += chunk.len();
+            if size > max_bytes {
+                sender.abort();
+                return Err(PumpError::TooLarge);
+            }
+            let out = match encoder.as_mut() {
+                Some(encoder) => match encoder.write_all(&chunk) {
+                    Ok(()) => std::mem::take(encoder.get_mut()),
+                    Err(e) => {
+                        sender.abort();
+                        return Err(PumpError::Failed(e.to_string()));
+                    }
+                },
+                None => chunk.to_vec(),
+            };
+            hasher.update(&out);
+            if !out.is_empty() && sender.send_data(out.into()).await.is_err() {
+                return Err(PumpError::Failed(String::from("upload closed early")));
+            }
+        }
+        let rest = match encoder.map(GzEncoder::finish) {
+            Some(Ok(rest)) => rest,
+            Some(Err(e)) => {
+                sender.abort();
+                return Err(PumpError::Failed(e.to_string()));
+            }
+            None => Vec::new(),
+        };
+        hasher.update(&rest);
+        if !rest.is_empty() && sender.send_data(rest.into()).await.is_err() {
+            return Err(PumpError::Failed(String::from("upload closed early")));
+        }
+        Ok((hex::encode(hasher.finalize()), size))
+    });
+    let added = store(config.blob_store).add_stream(payload, config).await;
+    let pumped = pump.await?;
+    if let Err(PumpError::TooLarge) = pumped {
+        return Err(BodyTooLarge.into());
+    }
+    let id = added?;
+    let (digest, size) = match pumped {
+        Ok(pumped) => pumped,
+        Err(PumpError::Failed(e)) => return Err(e.into()),
+        Err(PumpError::TooLarge) => return Err(BodyTooLarge.into()),
+    };
+    debug!(id = %id, bytes = size, "added streamed blob");
+    Ok((
+        Blob {
+            id,
+            compressed,
+            digest,
+        },
+        size,
+    ))
+}
+
+/// Fetches a value offloaded by `add`. An ipfs CID hashes the chunked UnixFS DAG
+/// rather than the bytes `cat` returns, so content is checked against `digest`
+/// instead, which is skipped for values added before digests were recorded.
+pub async fn get(
+    backend: BlobBackend,
+    id: &str,
+    compressed: bool,
+    digest: Option<&str>,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    let bytes = store(backend).get(id, config).await?;
+    if let Some(digest) = digest {
+        if let Err(e) = verify(&bytes, digest) {
+            error!(id, "blob content does not match its digest");
+            return Err(e.into());
+        }
+    }
+    if compressed {
+        return Ok(decompress(&bytes)?);
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Like `get`, but decompresses and checks the content as it streams through instead
+/// of buffering it. A digest mismatch can only be seen at the end, by which point the
+/// rest has been sent, so the body is aborted to make the client discard it.
+pub async fn get_stream(
+    backend: BlobBackend,
+    id: &str,
+    compressed: bool,
+    digest: Option<&str>,
+    config: &Config,
+) -> Result<Body, Box<dyn Error>> {
+    let mut source = store(backend).get_stream(id, config).await?;
+    let (mut sender, body) = Body::channel();
+    let id = id.to_string();
+    let digest = digest.map(str::to_string);
+    tokio::spawn(async move {
+        let mut hasher = Sha256::new();
+        let mut decoder = compressed.then(|| GzWriteDecoder::new(Vec::new()));
+        while let Some(chunk) = source.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!(id, "blob stream failed: {}", e);
+                    return sender.abort();
+                }
+            };
+            hasher.update(&chunk);
+            let out = match decoder.as_mut() {
+                Some(decoder) => match decoder.write_all(&chunk) {
+                    Ok(()) => std::mem::take(decoder.get_mut()),
+                    Err(e) => {
+                        error!(id, "blob stream failed to decompress: {}", e);
+                        return sender.abort();
+                    }
+                },
+                None => chunk.to_vec(),
+            };
+            if !out.is_empty() && sender.send_data(out.into()).await.is_err() {
+                // the client went away
+                return;
+            }
+        }
+        let rest = match decoder.map(GzWriteDecoder::finish) {
+            Some(Ok(rest)) => rest,
+            Some(Err(e)) => {
+                error!(id, "blob stream failed to decompress: {}", e);
+                return sender.abort();
+            }
+            None => Vec::new(),
+        };
+        if let Some(digest) = digest {
+            if !hex::encode(hasher.finalize()).eq_ignore_ascii_case(&digest) {
+                error!(id, "blob content does not match its digest");
+                return sender.abort();
+            }
+        }
+        if !rest.is_empty() {
+            let _ = sender.send_data(rest.into()).await;
+        }
+    });
+    Ok(body)
+}
+
+pub async fn delete(backend: BlobBackend, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    store(backend).delete(id, config).await
+}
+
+pub async fn pin(backend: BlobBackend, id: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    store(backend).pin(id, config).await
+}
+
+pub async fn stat(
+    backend: BlobBackend,
+    id: &str,
+    config: &Config,
+) -> Result<usize, Box<dyn Error>> {
+    store(backend).stat(id, config).await
+}
+
+fn verify(data: &[u8], digest: &str) -> Result<(), IntegrityError> {
+    if !hex::encode(Sha256::digest(data)).eq_ignore_ascii_case(digest) {
+        return Err(IntegrityError);
+    }
+    Ok(())
+}
+
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn decompress(data: &[u8]) -> io::Result<String> {
+    let mut value = String::new();
+    GzDecoder::new(data).read_to_string(&mut value)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() -> Result<(), Box<dyn Error>> {
+        let value = "{\"key\": \"value\"}".repeat(1000);
+        let compressed = compress(value.as_bytes())?;
+        assert!(compressed.len() < value.len() / 10);
+        assert_eq!(value, decompress(&compressed)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fs_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.blob_dir = std::env::temp_dir()
+            .join("test_fs_round_trip")
+            .to_string_lossy()
+            .into_owned();
+        let id = Fs.add(b"This is a test value".to_vec(), &config).await?;
+        assert_eq!(hex::encode(Sha256::digest(b"This is a test value")), id);
+        assert_eq!(
+            b"This is a test value".to_vec(),
+            Fs.get(&id, &config).await?
+        );
+        assert_eq!(20, Fs.stat(&id, &config).await?);
+        Fs.pin(&id, &config).await?;
+        Fs.delete(&id, &config).await?;
+        assert!(Fs.get(&id, &config).await.is_err());
+        assert!(Fs.pin(&id, &config).await.is_err());
+        // already gone is not an error
+        Fs.delete(&id, &config).await?;
+        assert!(Fs.get("../../etc/passwd", &config).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fs_stream() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.blob_dir = std::env::temp_dir()
+            .join("test_fs_stream")
+            .to_string_lossy()
+            .into_owned();
+        config.blob_store = BlobBackend::Fs;
+        let value = "{\"key\": \"value\"}".repeat(1000);
+        let blob = add(&value, &config).await?;
+        let body = get_stream(
+            BlobBackend::Fs,
+            &blob.id,
+            blob.compressed,
+            Some(&blob.digest),
+            &config,
+        )
+        .await?;
+        assert_eq!(value.as_bytes(), &hyper::body::to_bytes(body).await?[..]);
+
+        // the body fails rather than ending cleanly when the digest doesn't match
+        let body = get_stream(
+            BlobBackend::Fs,
+            &blob.id,
+            blob.compressed,
+            Some(&content_id(b"other")),
+            &config,
+        )
+        .await?;
+        assert!(hyper::body::to_bytes(body).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fs_add_stream() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.blob_dir = std::env::temp_dir()
+            .join("test_fs_add_stream")
+            .to_string_lossy()
+            .into_owned();
+        config.blob_store = BlobBackend::Fs;
+        let value = "{\"key\": \"value\"}".repeat(1000);
+        let (blob, size) = add_stream(value.clone().into(), value.len(), &config).await?;
+        assert_eq!(value.len(), size);
+        assert_eq!(
+            value,
+            get(
+                BlobBackend::Fs,
+                &blob.id,
+                blob.compressed,
+                Some(&blob.digest),
+                &config
+            )
+            .await?
+        );
+        let err = add_stream(value.clone().into(), value.len() - 1, &config)
+            .await
+            .unwrap_err();
+        assert!(err.is::<BodyTooLarge>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let data = b"{\"key\": \"value\"}";
+        let digest = hex::encode(Sha256::digest(data));
+        assert!(verify(data, &digest).is_ok());
+        assert!(verify(b"{\"key\": \"other\"}", &digest).is_err());
+    }
+}
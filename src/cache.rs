@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bounded LRU of decoded offloaded values keyed by blob id and weighed by value
+/// length. Blob ids are content hashes, so an entry can never go stale; entries
+/// are only dropped to make room or once the blob itself is deleted.
+#[derive(Default)]
+pub struct BlobCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// id to value and the tick it was last used at
+    entries: HashMap<String, (String, u64)>,
+    /// last used tick to id, oldest first
+    order: BTreeMap<u64, String>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl BlobCache {
+    /// A cache holding up to `max_bytes` of values, 0 disables it.
+    pub fn new(max_bytes: usize) -> BlobCache {
+        BlobCache {
+            max_bytes,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<String> {
+        let mut inner = self.inner.lock().ok()?;
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (value, last_used) = inner.entries.get_mut(id)?;
+        let previous = std::mem::replace(last_used, tick);
+        let value = value.clone();
+        inner.order.remove(&previous);
+        inner.order.insert(tick, id.to_string());
+        Some(value)
+    }
+
+    pub fn insert(&self, id: &str, value: &str) {
+        if self.max_bytes == 0 || value.len() > self.max_bytes {
+            return;
+        }
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+        inner.remove(id);
+        while inner.bytes + value.len() > self.max_bytes {
+            let oldest = match inner.order.keys().next() {
+                Some(tick) => inner.order[tick].clone(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.bytes += value.len();
+        inner.order.insert(tick, id.to_string());
+        inner
+            .entries
+            .insert(id.to_string(), (value.to_string(), tick));
+    }
+
+    pub fn remove(&self, id: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.remove(id);
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, id: &str) {
+        if let Some((value, tick)) = self.entries.remove(id) {
+            self.bytes -= value.len();
+            self.order.remove(&tick);
+        }
+    }
+}
+
+/// Per pcr settings read from Redis, each used for `ttl` before it is read again.
+/// Holds at most `max_entries`, dropping the stale ones and then the oldest to make
+/// room, so a flood of pcrs can't grow it without bound.
+pub struct ReadCache<V> {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (V, Instant)>>,
+}
+
+impl<V: Clone> ReadCache<V> {
+    pub fn new(ttl: Duration, max_entries: usize) -> ReadCache<V> {
+        ReadCache {
+            ttl,
+            max_entries,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// The value last read for `pcr`, or `None` once it needs reading again.
+    pub fn get(&self, pcr: &str, now: Instant) -> Option<V> {
+        let entries = self.entries.lock().ok()?;
+        match entries.get(pcr) {
+            Some((value, read_at)) if now.saturating_duration_since(*read_at) < self.ttl => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, pcr: &str, value: V, now: Instant) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        if entries.len() >= self.max_entries && !entries.contains_key(pcr) {
+            let ttl = self.ttl;
+            entries.retain(|_, (_, read_at)| now.saturating_duration_since(*read_at) < ttl);
+        }
+        while entries.len() >= self.max_entries && !entries.contains_key(pcr) {
+            let oldest = match entries.iter().min_by_key(|(_, (_, read_at))| *read_at) {
+                Some((oldest, _)) => oldest.clone(),
+                None => break,
+            };
+            entries.remove(&oldest);
+        }
+        entries.insert(pcr.to_string(), (value, now));
+    }
+
+    /// Forgets `pcr`, for settings changed through this instance to apply at once.
+    pub fn remove(&self, pcr: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(pcr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = BlobCache::new(10);
+        cache.insert("a", "aaaa");
+        cache.insert("b", "bbbb");
+        // touching a leaves b as the oldest
+        assert_eq!(Some(String::from("aaaa")), cache.get("a"));
+        cache.insert("c", "cccc");
+        assert_eq!(None, cache.get("b"));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+
+        cache.remove("a");
+        assert_eq!(None, cache.get("a"));
+        // bigger than the whole cache, so never kept
+        cache.insert("d", "ddddddddddd");
+        assert_eq!(None, cache.get("d"));
+        assert_eq!(None, BlobCache::default().get("c"));
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let cache = ReadCache::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        assert_eq!(None, cache.get("a", now));
+        cache.insert("a", Some(5), now);
+        assert_eq!(Some(Some(5)), cache.get("a", now));
+        assert_eq!(None, cache.get("a", now + Duration::from_secs(10)));
+        cache.remove("a");
+        assert_eq!(None, cache.get("a", now));
+
+        // full, so the oldest goes to make room
+        cache.insert("a", None, now);
+        cache.insert("b", None, now + Duration::from_secs(1));
+        cache.insert("c", None, now + Duration::from_secs(2));
+        assert_eq!(None, cache.get("a", now + Duration::from_secs(2)));
+        assert_eq!(Some(None), cache.get("b", now + Duration::from_secs(2)));
+        assert_eq!(Some(None), cache.get("c", now + Duration::from_secs(2)));
+    }
+}
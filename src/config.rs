@@ -0,0 +1,321 @@
+use crate::blob::BlobBackend;
+use crate::{handler, Config};
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+/// prefix of the environment variables overriding config fields, e.g. `OYSTER_IPFS_URL`
+const ENV_PREFIX: &str = "OYSTER_";
+/// the sample config, whose field order and comments generated configs follow
+const SAMPLE_CONFIG: &str = include_str!("../config.toml");
+/// comments for defaults in other units than the sample's values
+const DEFAULT_COMMENTS: &[(&str, &str)] = &[
+    ("operation_a_cost", "(in 10^-15 $) list"),
+    ("operation_b_cost", "(in 10^-15 $) load, stat, lock, unlock"),
+    ("operation_c_cost", "(in 10^-15 $) store, delete, exists"),
+    ("memory_cost", "cost per Byte per millisecond (in 10^-23 $)"),
+];
+
+/// Overrides the fields of `config` named by the `OYSTER_<FIELD>` variables in `vars`.
+/// String fields take the value as is and the others parse it as JSON, so
+/// `OYSTER_MAX_TREE_KEYS=500` and `OYSTER_ALLOWED_PCRS='["ab..."]'` both work.
+/// Variables with the prefix that name no field are rejected, so a typo doesn't
+/// silently leave the loaded value in place.
+pub fn with_env_overrides<I>(config: Config, vars: I) -> Result<Config, Box<dyn Error>>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut value = serde_json::to_value(&config)?;
+    let fields = value.as_object_mut().ok_or("config is not a table")?;
+    for (name, raw) in vars {
+        let field = match name.strip_prefix(ENV_PREFIX) {
+            Some(field) => field.to_ascii_lowercase(),
+            None => continue,
+        };
+        let current = fields
+            .get_mut(&field)
+            .ok_or_else(|| format!("{} does not name a config field", name))?;
+        *current = if current.is_string() {
+            raw.into()
+        } else {
+            serde_json::from_str(&raw).map_err(|e| format!("could not parse {}: {}", name, e))?
+        };
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Writes the default config to `path` with every field commented, refusing to
+/// replace a file that is already there.
+pub fn generate(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("could not create {}: {}", path, e))?;
+    file.write_all(default_toml()?.as_bytes())?;
+    Ok(())
+}
+
+/// `Config::default()` as TOML, laid out and commented like the sample config.
+fn default_toml() -> Result<String, Box<dyn Error>> {
+    let value = serde_json::to_value(Config::default())?;
+    let mut fields = value.as_object().ok_or("config is not a table")?.clone();
+    let mut out = String::new();
+    for line in SAMPLE_CONFIG.lines() {
+        let (name, comment) = match line.split_once('=') {
+            Some((name, rest)) => (name.trim(), rest.split_once(" # ").map(|(_, c)| c)),
+            None => continue,
+        };
+        let value = match fields.remove(name) {
+            Some(value) => value,
+            None => continue,
+        };
+        let comment = DEFAULT_COMMENTS
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, comment)| *comment)
+            .or(comment);
+        out += &format!("{} = {}", name, toml_value(&value));
+        if let Some(comment) = comment {
+            out += &format!(" # {}", comment);
+        }
+        out.push('\n');
+    }
+    // fields the sample doesn't have yet still get their default
+    for (name, value) in fields {
+        out += &format!("{} = {}\n", name, toml_value(&value));
+    }
+    Ok(out)
+}
+
+/// `value` as an inline TOML value. JSON strings are valid TOML basic strings.
+fn toml_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(toml_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        serde_json::Value::Object(entries) if entries.is_empty() => "{}".to_string(),
+        serde_json::Value::Object(entries) => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| {
+                    format!(
+                        "{} = {}",
+                        serde_json::Value::from(key.as_str()),
+                        toml_value(value)
+                    )
+                })
+                .collect();
+            format!("{{ {} }}", entries.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+impl Config {
+    /// Checks for settings that would only fail later, or quietly misbehave, returning
+    /// every problem found rather than just the first.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        for (name, cost) in [
+            ("operation_a_cost", self.operation_a_cost),
+            ("operation_b_cost", self.operation_b_cost),
+            ("operation_c_cost", self.operation_c_cost),
+            ("memory_cost", self.memory_cost),
+        ] {
+            if cost < 0 {
+                problems.push(format!("{} must not be negative, got {}", name, cost));
+            }
+        }
+        if self.retry_count == 0 {
+            problems.push("retry_count must be at least 1".to_string());
+        }
+        if self.retry_delay == 0 {
+            problems.push("retry_delay must be at least 1 millisecond".to_string());
+        }
+        if self.lock_expiry == 0 {
+            problems.push("lock_expiry must be at least 1 millisecond".to_string());
+        }
+        if self.lock_expiry > self.max_lock_expiry {
+            problems.push(format!(
+                "lock_expiry ({}) must not be over max_lock_expiry ({})",
+                self.lock_expiry, self.max_lock_expiry
+            ));
+        }
+        if self.min_expiry_ms < 0 {
+            problems.push("min_expiry_ms must not be negative".to_string());
+        }
+        if self.max_expiry_ms < 0 {
+            problems.push("max_expiry_ms must not be negative, 0 is unlimited".to_string());
+        } else if self.max_expiry_ms > 0 && self.min_expiry_ms > self.max_expiry_ms {
+            problems.push(format!(
+                "min_expiry_ms ({}) must not be over max_expiry_ms ({})",
+                self.min_expiry_ms, self.max_expiry_ms
+            ));
+        }
+        if self.key_separator.is_empty() {
+            problems.push("key_separator must not be empty".to_string());
+        }
+        // '.' joins the pcr to its own keys, and the rest would be read as a pattern
+        // by the SCAN MATCH over the namespace
+        if self.key_separator.contains(['.', '*', '?', '[', ']', '\\']) {
+            problems.push(format!(
+                "key_separator must not contain '.' or any of *?[]\\, got {:?}",
+                self.key_separator
+            ));
+        }
+        if self.scan_count == 0 {
+            problems.push("scan_count must be at least 1".to_string());
+        }
+        // namespace thresholds and the ipfs storage mode send values there whatever
+        // mem_threshold is, so the blob store always has to be usable
+        match self.blob_store {
+            BlobBackend::Ipfs => check_url(&mut problems, "ipfs_url", &self.ipfs_url),
+            BlobBackend::Fs if self.blob_dir.is_empty() => {
+                problems.push("blob_dir must be set for the fs blob_store".to_string())
+            }
+            BlobBackend::Fs => {}
+            BlobBackend::S3 => {
+                check_url(&mut problems, "s3_endpoint", &self.s3_endpoint);
+                if self.s3_bucket.is_empty() {
+                    problems.push("s3_bucket must be set for the s3 blob_store".to_string());
+                }
+            }
+        }
+        if !self.otlp_endpoint.is_empty() {
+            check_url(&mut problems, "otlp_endpoint", &self.otlp_endpoint);
+        }
+        for pcr in self.allowed_pcrs.iter().chain(self.api_tokens.keys()) {
+            if !handler::is_valid_pcr(pcr) {
+                problems.push(format!("{} is not a valid pcr", pcr));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+fn check_url(problems: &mut Vec<String>, name: &str, value: &str) {
+    match url::Url::parse(value) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        Ok(url) => problems.push(format!(
+            "{} must be an http or https url, got {} ({})",
+            name,
+            url.scheme(),
+            value
+        )),
+        Err(e) => problems.push(format!("{} is not a valid url ({}): {:?}", name, e, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_with_env_overrides() -> Result<(), Box<dyn Error>> {
+        let config = with_env_overrides(
+            Config::default(),
+            vars(&[
+                ("OYSTER_IPFS_URL", "http://localhost:5001"),
+                ("OYSTER_MAX_TREE_KEYS", "500"),
+                ("OYSTER_HEALTH_CHECK_IPFS", "true"),
+                ("OYSTER_ALLOWED_PCRS", "[\"ab\"]"),
+                ("OYSTER_STORAGE_FORMAT", "msgpack"),
+                // without the prefix it isn't meant for us
+                ("IPFS_URL", "http://elsewhere"),
+            ]),
+        )?;
+        assert_eq!("http://localhost:5001", config.ipfs_url);
+        assert_eq!(500, config.max_tree_keys);
+        assert!(config.health_check_ipfs);
+        assert_eq!(vec!["ab".to_string()], config.allowed_pcrs);
+        assert_eq!(
+            crate::database::StorageFormat::Msgpack,
+            config.storage_format
+        );
+        // untouched fields keep what was loaded
+        assert_eq!(Config::default().scan_count, config.scan_count);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_toml() -> Result<(), Box<dyn Error>> {
+        let generated = default_toml()?;
+        // new fields need a line in the sample to be documented
+        let fields = serde_json::to_value(Config::default())?;
+        let fields = fields.as_object().ok_or("config is not a table")?;
+        for name in fields.keys() {
+            assert!(
+                SAMPLE_CONFIG
+                    .lines()
+                    .any(|line| line.starts_with(&format!("{} =", name))),
+                "{} is missing from config.toml",
+                name
+            );
+        }
+        assert!(generated.contains("operation_a_cost = 17637500 # (in 10^-15 $) list\n"));
+        assert!(generated.contains("key_separator = \"/\" # "));
+        assert!(generated.contains("api_tokens = {} # "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_toml_value() {
+        let value = serde_json::json!({"a\"b": ["x", 1, true], "c": {}});
+        assert_eq!(
+            "{ \"a\\\"b\" = [\"x\", 1, true], \"c\" = {} }",
+            toml_value(&value)
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        config.ipfs_url = "https://ipfs.infura.io:5001/api/v0/".to_string();
+        assert_eq!(Ok(()), config.validate());
+        config.ipfs_url = "".to_string();
+        config.memory_cost = -1;
+        config.lock_expiry = config.max_lock_expiry + 1;
+        config.key_separator = "".to_string();
+        let problems = config.validate().unwrap_err();
+        assert_eq!(4, problems.len());
+        assert!(problems[0].starts_with("memory_cost"));
+        // the blob store is checked even when values would all stay in redis
+        config.mem_threshold = config.max_value_bytes;
+        assert_eq!(4, config.validate().unwrap_err().len());
+        config.scan_count = 0;
+        assert_eq!(5, config.validate().unwrap_err().len());
+        for separator in [".", "*", "a?", "[", "\\"] {
+            config.key_separator = separator.to_string();
+            assert_eq!(5, config.validate().unwrap_err().len());
+        }
+    }
+
+    #[test]
+    fn test_with_env_overrides_rejects_bad_values() {
+        let err = with_env_overrides(Config::default(), vars(&[("OYSTER_MAX_TREE_KEYS", "many")]))
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+        assert!(err.contains("OYSTER_MAX_TREE_KEYS"));
+        assert!(
+            with_env_overrides(Config::default(), vars(&[("OYSTER_MAX_TREE_KEYS", "-1")])).is_err()
+        );
+        let err = with_env_overrides(Config::default(), vars(&[("OYSTER_REDIS_URL", "redis://")]))
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+        assert!(err.contains("OYSTER_REDIS_URL"));
+    }
+}
@@ -1,22 +1,537 @@
 use chrono::Utc;
+use redis::aio::ConnectionManager;
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp;
-use std::collections::HashSet;
-use std::error::Error;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::io::{self, Read};
-use std::thread::sleep;
 use std::time::Duration;
+use tracing::{error, info_span, Instrument};
 
-use crate::{ipfs, Config};
+use crate::blob::{self, BlobBackend};
+use crate::cache::BlobCache;
+use crate::error::{BodyTooLarge, IntegrityError, StorageError};
+use crate::Config;
 //use rslock::LockManager;
+
+// keys SCANned and deleted per round trip when evicting a namespace
+const EVICT_BATCH: usize = 500;
+
+// leading byte of msgpack encoded StorageData. json encoded data always starts
+// with '{', so values written before the format was configurable still decode
+const MSGPACK_FORMAT: u8 = 0x01;
+// leading byte of inline data written as a fixed header, 8 big endian bytes of
+// modified time, followed by the value itself
+const COMPACT_FORMAT: u8 = 0x02;
+const COMPACT_HEADER_LEN: usize = 9;
+// as COMPACT_FORMAT, with 8 big endian bytes of version after the modified time
+const COMPACT_VERSIONED_FORMAT: u8 = 0x03;
+const COMPACT_VERSIONED_HEADER_LEN: usize = 17;
+
+// source of value versions, shared by every key so a version is never reused
+const VERSION_KEY: &str = "storage.version";
+
+// accumulated cost per pcr, added to by every flush of the in-memory cost map
+const COST_KEY: &str = "billing.cost";
+
+// reference counts for offloaded blobs, shared by every namespace so identical
+// values stored under different keys share one blob
+const IPFS_REFS_KEY: &str = "ipfs.refs";
+
+// soft deleted keys, scored by the unix millisecond time they are purged
+const TRASH_INDEX_KEY: &str = "trash.index";
+// the ttl each soft deleted key had left when deleted, -1 if it had none
+const TRASH_TTL_KEY: &str = "trash.ttl";
+
+// drops one reference to ARGV[1], forgetting it once none are left. returns the
+// remaining count, which is negative for pins made before refcounting
+const RELEASE_CID_SCRIPT: &str = r#"
+local count = redis.call("HINCRBY", KEYS[1], ARGV[1], -1)
+if count <= 0 then
+    redis.call("HDEL", KEYS[1], ARGV[1])
+end
+return count
+"#;
+
+// tracks per namespace byte usage: a hash of key sizes, a sorted set of key expiries
+// and the running total. expired keys are dropped from the total before anything else.
+// ARGV is now, max bytes (0 for unlimited) and optionally key, size (0 once removed)
+// and expire at (-1 to keep the previous expiry). returns the total, or -1 when the
+// update would go over the limit
+const USAGE_SCRIPT: &str = r#"
+local expired = redis.call("ZRANGEBYSCORE", KEYS[2], "-inf", ARGV[1])
+for _, key in ipairs(expired) do
+    local size = redis.call("HGET", KEYS[1], key)
+    if size then
+        redis.call("DECRBY", KEYS[3], size)
+        redis.call("HDEL", KEYS[1], key)
+    end
+end
+redis.call("ZREMRANGEBYSCORE", KEYS[2], "-inf", ARGV[1])
+local total = tonumber(redis.call("GET", KEYS[3]) or "0")
+if #ARGV < 5 then
+    return total
+end
+local old = tonumber(redis.call("HGET", KEYS[1], ARGV[3]) or "0")
+local size = tonumber(ARGV[4])
+total = total - old + size
+local max = tonumber(ARGV[2])
+if max > 0 and size > old and total > max then
+    return -1
+end
+if size > 0 then
+    redis.call("HSET", KEYS[1], ARGV[3], size)
+    if ARGV[5] ~= "-1" then
+        redis.call("ZADD", KEYS[2], ARGV[5], ARGV[3])
+    end
+else
+    redis.call("HDEL", KEYS[1], ARGV[3])
+    redis.call("ZREM", KEYS[2], ARGV[3])
+end
+redis.call("SET", KEYS[3], total)
+return total
+"#;
+
+// moves KEYS[1] to the trash key KEYS[2] without a ttl, indexing it in KEYS[3] to be
+// purged ARGV[2] milliseconds after ARGV[1], or when the key would have expired if
+// that is sooner, and recording the ttl it had left in KEYS[4]. returns whether the
+// key existed, the raw data of any earlier trash entry it replaced, or "", when it is
+// purged and the bytes the usage hash KEYS[5] counted for ARGV[3]
+const SOFT_DELETE_SCRIPT: &str = r#"
+local ttl = redis.call("PTTL", KEYS[1])
+if ttl == -2 then
+    return {0, "", 0, 0}
+end
+local purge_at = tonumber(ARGV[1]) + tonumber(ARGV[2])
+if ttl > 0 and ttl < tonumber(ARGV[2]) then
+    purge_at = tonumber(ARGV[1]) + ttl
+end
+local old = redis.call("GET", KEYS[2]) or ""
+local size = tonumber(redis.call("HGET", KEYS[5], ARGV[3]) or "0")
+redis.call("RENAME", KEYS[1], KEYS[2])
+redis.call("PERSIST", KEYS[2])
+redis.call("ZADD", KEYS[3], purge_at, KEYS[2])
+redis.call("HSET", KEYS[4], KEYS[2], ttl)
+return {1, old, purge_at, size}
+"#;
+
+// moves the trash key KEYS[1] back to KEYS[2] with the ttl it had left. returns 1
+// if restored, 0 if it is no longer in the trash and -1 if KEYS[2] exists again
+const RESTORE_SCRIPT: &str = r#"
+if redis.call("EXISTS", KEYS[1]) == 0 then
+    return 0
+end
+if redis.call("EXISTS", KEYS[2]) == 1 then
+    return -1
+end
+local ttl = tonumber(redis.call("HGET", KEYS[4], KEYS[1]) or "-1")
+redis.call("RENAME", KEYS[1], KEYS[2])
+redis.call("ZREM", KEYS[3], KEYS[1])
+redis.call("HDEL", KEYS[4], KEYS[1])
+if ttl > 0 then
+    redis.call("PEXPIRE", KEYS[2], ttl)
+end
+return 1
+"#;
+
+// writes ARGV[1] to KEYS[1] with a ttl of ARGV[3] milliseconds when ARGV[2] is "px",
+// expiring at the unix millisecond time ARGV[3] when it is "at", or keeping the ttl the
+// key has when it is "keep", in which case the key has to exist already, or "keepttl",
+// in which case it doesn't. a reference to the blob ARGV[4] is taken in KEYS[2] along
+// with the write unless it is "". returns whether the value was written and the raw
+// data it replaced, or ""
+const STORE_SCRIPT: &str = r#"
+local old
+if ARGV[2] == "keep" then
+    old = redis.call("GET", KEYS[1])
+    if not old then
+        return {0, ""}
+    end
+    redis.call("SET", KEYS[1], ARGV[1], "KEEPTTL")
+elseif ARGV[2] == "keepttl" then
+    old = redis.call("SET", KEYS[1], ARGV[1], "KEEPTTL", "GET")
+elseif ARGV[2] == "px" then
+    old = redis.call("SET", KEYS[1], ARGV[1], "PX", ARGV[3], "GET")
+else
+    old = redis.call("SET", KEYS[1], ARGV[1], "GET")
+    redis.call("PEXPIREAT", KEYS[1], ARGV[3])
+end
+if ARGV[4] ~= "" then
+    redis.call("HINCRBY", KEYS[2], ARGV[4], 1)
+end
+return {1, old or ""}
+"#;
+
+// adds ARGV[1] operations to the window counter KEYS[1], starting a window of ARGV[2]
+// milliseconds when there is none. returns the count so far
+const OPS_SCRIPT: &str = r#"
+local count = redis.call("INCRBY", KEYS[1], ARGV[1])
+if redis.call("PTTL", KEYS[1]) < 0 then
+    redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return count
+"#;
+
+// pushes ARGV[1] onto the history list KEYS[1], keeping the newest ARGV[2] entries
+// and the same ttl as the key KEYS[2], and returns the ones dropped
+const HISTORY_SCRIPT: &str = r#"
+redis.call("LPUSH", KEYS[1], ARGV[1])
+local dropped = redis.call("LRANGE", KEYS[1], ARGV[2], -1)
+redis.call("LTRIM", KEYS[1], 0, tonumber(ARGV[2]) - 1)
+local ttl = redis.call("PTTL", KEYS[2])
+if ttl > 0 then
+    redis.call("PEXPIRE", KEYS[1], ttl)
+elseif ttl == -1 then
+    redis.call("PERSIST", KEYS[1])
+end
+return dropped
+"#;
+
+// takes the lock if it is free and bumps the per-key fencing counter in the same step.
+// returns the new fencing token, or 0 if the lock is already held
+const LOCK_SCRIPT: &str = r#"
+if redis.call("SET", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+    return redis.call("INCR", KEYS[2])
+end
+return 0
+"#;
+
+// deletes the lock only if it is still held with the supplied lock_id.
+// returns 1 if released, 0 if the lock no longer exists, -1 if held by someone else
+const UNLOCK_SCRIPT: &str = r#"
+local current = redis.call("GET", KEYS[1])
+if not current then
+    return 0
+end
+if current == ARGV[1] then
+    redis.call("DEL", KEYS[1])
+    return 1
+end
+return -1
+"#;
+
+// LOCK_SCRIPT for several keys at once: KEYS are the lock keys followed by their
+// fence keys in the same order. takes every lock or none of them, returning the new
+// fencing tokens, or an empty list if any lock is already held
+const LOCK_MANY_SCRIPT: &str = r#"
+local n = #KEYS / 2
+for i = 1, n do
+    if redis.call("EXISTS", KEYS[i]) == 1 then
+        return {}
+    end
+end
+local fences = {}
+for i = 1, n do
+    redis.call("SET", KEYS[i], ARGV[1], "PX", ARGV[2])
+    fences[i] = redis.call("INCR", KEYS[n + i])
+end
+return fences
+"#;
+
+// UNLOCK_SCRIPT for each of KEYS, returning its result per key
+const UNLOCK_MANY_SCRIPT: &str = r#"
+local results = {}
+for i, key in ipairs(KEYS) do
+    local current = redis.call("GET", key)
+    if not current then
+        results[i] = 0
+    elseif current == ARGV[1] then
+        redis.call("DEL", key)
+        results[i] = 1
+    else
+        results[i] = -1
+    end
+end
+return results
+"#;
+
+// pushes the lock expiry forward only if it is still held with the supplied lock_id.
+// returns 1 if extended, 0 otherwise
+const EXTEND_LOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+"#;
+
+// replaces the value and its ttl only if the stored data is unchanged, taking a
+// reference to the blob ARGV[4] in KEYS[2] along with the write unless it is "".
+// returns 1 if updated, 0 otherwise
+const CAS_TOUCH_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call("SET", KEYS[1], ARGV[2], "PX", ARGV[3])
+if ARGV[4] ~= "" then
+    redis.call("HINCRBY", KEYS[2], ARGV[4], 1)
+end
+return 1
+"#;
+
+// deletes the key only if the stored data is unchanged.
+// returns 1 if deleted, 0 otherwise
+const CAS_DELETE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call("DEL", KEYS[1])
+return 1
+"#;
+
+// writes the value only if the stored data is unchanged, with a ttl of ARGV[3]
+// milliseconds or keeping the current one for -1. a reference to the blob ARGV[4] is
+// taken in KEYS[2] along with the write unless it is "". returns 1 if written, 0
+// otherwise
+const STORE_IF_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+if ARGV[3] == "-1" then
+    redis.call("SET", KEYS[1], ARGV[2], "KEEPTTL")
+else
+    redis.call("SET", KEYS[1], ARGV[2], "PX", ARGV[3])
+end
+if ARGV[4] ~= "" then
+    redis.call("HINCRBY", KEYS[2], ARGV[4], 1)
+end
+return 1
+"#;
+
+// commits a transaction. KEYS are the keys it touched and ARGV[i] the raw data read
+// from KEYS[i] beforehand, empty if it was missing. the rest of ARGV is a key index,
+// new raw data (empty to delete) and ttl (-1 to keep the current one) per write.
+// nothing is written unless every key still holds what was read. returns 1 if
+// committed, 0 otherwise
+const TX_SCRIPT: &str = r#"
+for i, key in ipairs(KEYS) do
+    if (redis.call("GET", key) or "") ~= ARGV[i] then
+        return 0
+    end
+end
+for i = #KEYS + 1, #ARGV, 3 do
+    local key = KEYS[tonumber(ARGV[i])]
+    if ARGV[i + 1] == "" then
+        redis.call("DEL", key)
+    elseif ARGV[i + 2] == "-1" then
+        redis.call("SET", key, ARGV[i + 1], "KEEPTTL")
+    else
+        redis.call("SET", key, ARGV[i + 1], "PX", ARGV[i + 2])
+    end
+end
+return 1
+"#;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyInfo {
     key: String,
-    modified: i64,
-    size: usize,
+    pub modified: i64,
+    pub size: usize,
+    is_terminal: bool,
+    /// remaining time to live in milliseconds, -1 when the key doesn't expire
+    #[serde(default)]
+    ttl: i64,
+    #[serde(default)]
+    pub ipfs: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Where a stored value lives: `Auto` offloads to the blob store above
+/// `config.mem_threshold`, the others force the choice for that write. `Ipfs` keeps
+/// its name for compatibility and offloads to whichever `config.blob_store` is set.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageMode {
+    #[default]
+    Auto,
+    Inline,
+    Ipfs,
+}
+
+/// How `StorageData` is encoded in Redis. Reads accept either, so the setting can
+/// be changed without rewriting existing keys.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageFormat {
+    #[default]
+    Json,
+    /// smaller and quicker to parse, prefixed with `MSGPACK_FORMAT`
+    Msgpack,
+    /// inline values as `COMPACT_FORMAT`, the modified time and then the raw value,
+    /// with offloaded ones falling back to msgpack
+    Compact,
+}
+
+/// Optional knobs for `store_with_options`.
+#[derive(Debug, Default)]
+pub struct StoreOptions {
+    pub storage: StorageMode,
+    /// absolute unix millisecond expiry, used in place of `exp` when set
+    pub expire_at_ms: Option<i64>,
+    /// only write if the stored value is at this version
+    pub if_version: Option<u64>,
+    /// kept with the value and returned by load and stat, counted towards its size
+    pub metadata: HashMap<String, String>,
+}
+
+/// What `store_with_options` wrote, so callers needn't stat the key afterwards.
+#[derive(Serialize, Debug)]
+pub struct StoreResult {
+    pub modified: i64,
+    pub size: usize,
+    pub ipfs: bool,
+    pub cost: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+/// One key as written by `export_batch`, a line of a namespace export.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportEntry {
+    pub key: String,
+    /// absent for offloaded values unless the export materializes them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// remaining time to live in milliseconds, -1 when the key doesn't expire
+    pub ttl: i64,
+    pub modified: i64,
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// where an offloaded value lives, for it to be pinned again without copying it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    #[serde(default)]
+    pub compressed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<BlobBackend>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// What `import_entry` does with a key that already exists.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    #[default]
+    Skip,
+    Overwrite,
+}
+
+/// A previous value of a key, as listed by `history`.
+#[derive(Serialize, Debug)]
+pub struct HistoryEntry {
+    pub version: Option<u64>,
+    pub modified: i64,
+    pub size: usize,
+    pub ipfs: bool,
+}
+
+/// A lock currently held in a namespace.
+#[derive(Serialize, Debug)]
+pub struct LockInfo {
+    pub key: String,
+    /// remaining time to live in milliseconds
+    pub ttl: i64,
+}
+
+/// Redis memory held by a namespace, including its locks, trash, history and
+/// bookkeeping keys.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    /// summed `MEMORY USAGE`, extrapolated from the sampled keys when not all were
+    pub bytes: u64,
+    pub keys: u64,
+    /// keys whose `MEMORY USAGE` was read
+    pub sampled: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct TreeNode {
     is_terminal: bool,
+    children: BTreeMap<String, TreeNode>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlockResult {
+    /// the caller held the lock and it has been released
+    Released,
+    /// the lock had already been released or expired
+    Expired,
+    /// the lock is now held by someone else
+    HeldByOther,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxOpKind {
+    Get,
+    Set,
+    Del,
+    Incr,
+}
+
+/// One step of a `transaction`, run in order against the keys as the earlier
+/// steps left them.
+#[derive(Deserialize, Debug)]
+pub struct TxOp {
+    pub op: TxOpKind,
+    pub key: String,
+    /// the value to `set`
+    #[serde(default)]
+    pub value: Option<String>,
+    /// added to the value by `incr`, 1 if unset
+    #[serde(default)]
+    pub by: Option<i64>,
+    /// relative expiry in milliseconds for `set` and `incr`, -1 to keep the existing one.
+    /// Required for every op, so a forgotten one can't quietly mean "expire now"
+    pub expiry: i64,
+    /// abort the transaction unless the key is at this version
+    #[serde(default)]
+    pub if_version: Option<u64>,
+    /// abort the transaction unless the key does or doesn't exist
+    #[serde(default)]
+    pub if_exists: Option<bool>,
+}
+
+/// What a `TxOp` returned: the value read by `get` (absent if the key is missing),
+/// the new value from `incr` and for writes the version committed.
+#[derive(Serialize, Debug, Default)]
+pub struct TxResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+/// A key's state while a transaction runs.
+struct TxSlot {
+    /// raw data read before running, empty when the key was missing
+    raw: Vec<u8>,
+    value: TxValue,
+    /// changed by an op, so part of the commit
+    written: bool,
+    /// ttl of the written value, -1 to keep the current one
+    expiry: i64,
+    /// the last op that wrote the key, which reports the committed version
+    last_write: usize,
+}
+
+enum TxValue {
+    Missing,
+    /// read but not loaded yet, as that may mean fetching a blob
+    Stored(StorageData),
+    /// the current value and its version, `None` once changed in the transaction
+    Loaded(String, Option<u64>),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,45 +539,170 @@ struct StorageData {
     value: String,
     modified: i64,
     ipfs: bool,
+    /// ipfs content is gzipped, absent on values written before compression
+    #[serde(default)]
+    compressed: bool,
+    /// sha256 of the bytes added to ipfs, checked against what the gateway returns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    /// length of the value as written, absent on values written before it was recorded
+    #[serde(default)]
+    size: Option<usize>,
+    /// blob store holding an offloaded value, absent on values offloaded to ipfs
+    /// before the backend was configurable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backend: Option<BlobBackend>,
+    /// changes with every write, absent on values written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    /// headers given with the value, such as `content_type`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+}
+
+/// A change to a key in a namespace, read from a Redis keyspace notification.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: String,
+    /// the command or event behind the change, e.g. `set`, `del` or `expired`
+    pub event: String,
 }
 
-pub async fn connect() -> Result<redis::aio::Connection, Box<dyn Error>> {
+fn client(config: &Config) -> Result<redis::Client, StorageError> {
     let redis_host_name = "127.0.0.1/";
     //let redis_password = "";
 
-    let redis_conn_url = format!("redis://{}", redis_host_name);
-    let conn = redis::Client::open(redis_conn_url)?
-        .get_async_connection()
+    let redis_conn_url = format!("redis://{}{}", redis_host_name, config.redis_db);
+    Ok(redis::Client::open(redis_conn_url)?)
+}
+
+/// Connects to Redis through a `ConnectionManager`, which reconnects in the
+/// background once the connection drops. The command that saw the drop still fails,
+/// but the ones after it go through without restarting the server.
+pub async fn connect(config: &Config) -> Result<ConnectionManager, StorageError> {
+    Ok(ConnectionManager::new(client(config)?).await?)
+}
+
+/// Turns on the keyspace notifications `subscribe` relies on. Managed Redis
+/// deployments may refuse CONFIG, in which case they have to be enabled there.
+pub async fn enable_keyspace_events(
+    events: &str,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("notify-keyspace-events")
+        .arg(events)
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+/// Opens a dedicated connection subscribed to keyspace notifications for every
+/// key in the namespace; pass its messages to `key_event`.
+pub async fn subscribe(pcr: &String, config: &Config) -> Result<redis::aio::PubSub, StorageError> {
+    let mut pubsub = client(config)?.get_async_connection().await?.into_pubsub();
+    pubsub
+        .psubscribe(get_keyspace_channel(pcr, &String::from("*"), config))
+        .await?;
+    Ok(pubsub)
+}
+
+/// Like `subscribe`, but for notifications about `key` alone.
+pub async fn watch(
+    pcr: &String,
+    key: &String,
+    config: &Config,
+) -> Result<redis::aio::PubSub, StorageError> {
+    let mut pubsub = client(config)?.get_async_connection().await?.into_pubsub();
+    pubsub
+        .subscribe(get_keyspace_channel(pcr, key, config))
         .await?;
+    Ok(pubsub)
+}
+
+/// The key and event a notification from `subscribe` or `watch` is about, or `None` if it
+/// isn't for a key in the namespace.
+pub fn key_event(pcr: &String, msg: &redis::Msg, config: &Config) -> Option<KeyEvent> {
+    let key =
+        msg.get_channel_name()
+            .strip_prefix(&get_keyspace_channel(pcr, &String::new(), config))?;
+    let event: String = msg.get_payload().ok()?;
+    Some(KeyEvent {
+        key: String::from(key),
+        event,
+    })
+}
 
-    Ok(conn)
+pub async fn ping(conn: &mut ConnectionManager) -> Result<(), StorageError> {
+    redis::cmd("PING").query_async::<_, ()>(conn).await?;
+    Ok(())
 }
 
 pub async fn load(
     pcr: String,
     key: &String,
-    conn: &mut redis::aio::Connection,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
     config: &Config,
-) -> Result<(String, i64), Box<dyn Error>> {
-    let key = get_namespaced_key(&pcr, key);
-    let value: String = redis::cmd("GET").arg(key).query_async(conn).await?;
+) -> Result<(String, i64, Option<u64>, HashMap<String, String>), StorageError> {
+    let span = info_span!("redis.get", pcr = %pcr, key = span_key(key, config));
+    let key = get_namespaced_key(&pcr, key, config);
+    let value: Option<Vec<u8>> = redis::cmd("GET")
+        .arg(key)
+        .query_async(conn)
+        .instrument(span)
+        .await?;
+    let value = value.ok_or(StorageError::NotFound)?;
 
-    let mut value: StorageData = serde_json::from_str(&String::from(value))?;
-    if value.ipfs {
-        value.value = ipfs::get(value.value, config).await?;
-    }
-    Ok((value.value, config.operation_c_cost))
+    let mut value: StorageData = decode(&value)?;
+    let version = value.version;
+    let metadata = std::mem::take(&mut value.metadata);
+    Ok((
+        load_value(value, cache, config).await?,
+        config.operation_b_cost,
+        version,
+        metadata,
+    ))
 }
 
-async fn load_locked(
+/// Like `load`, but offloaded values of at least `config.stream_min_bytes` come back
+/// as a body streamed from the blob store rather than being buffered. Smaller ones
+/// go through the cache as usual.
+pub async fn load_stream(
     pcr: String,
     key: &String,
-    conn: &mut redis::aio::Connection,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let key = get_locked_key(&pcr, key);
-    let value = redis::cmd("GET").arg(key).query_async(conn).await?;
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(hyper::Body, i64), StorageError> {
+    let span = info_span!("redis.get", pcr = %pcr, key = span_key(key, config));
+    let key = get_namespaced_key(&pcr, key, config);
+    let value: Option<Vec<u8>> = redis::cmd("GET")
+        .arg(key)
+        .query_async(conn)
+        .instrument(span)
+        .await?;
+    let value = value.ok_or(StorageError::NotFound)?;
 
-    Ok(value)
+    let data = decode(&value)?;
+    let size = data.size.unwrap_or_default();
+    if !data.ipfs || size < config.stream_min_bytes || cache.get(&data.value).is_some() {
+        let value = load_value(data, cache, config).await?;
+        return Ok((value.into(), config.operation_b_cost));
+    }
+    let backend = data.backend.unwrap_or_default();
+    let body = blob::get_stream(
+        backend,
+        &data.value,
+        data.compressed,
+        data.digest.as_deref(),
+        config,
+    )
+    .instrument(info_span!("blob.get_stream", backend = ?backend, id = %data.value))
+    .await
+    .map_err(blob_error)?;
+    Ok((body, config.operation_b_cost))
 }
 
 pub async fn store(
@@ -70,587 +710,4742 @@ pub async fn store(
     key: &String,
     exp: i64,
     value: &String,
-    conn: &mut redis::aio::Connection,
+    conn: &mut ConnectionManager,
     config: &Config,
-) -> Result<i64, Box<dyn Error>> {
-    let key = get_namespaced_key(&pcr, key);
-    let mut data = StorageData {
-        ipfs: false,
-        value: String::from(value),
-        modified: Utc::now().timestamp_millis(),
+) -> Result<i64, StorageError> {
+    // offloaded values are content addressed, so skipping the cache can't leave it stale
+    let cache = BlobCache::default();
+    store_with_options(
+        pcr,
+        key,
+        exp,
+        value,
+        &StoreOptions::default(),
+        &cache,
+        conn,
+        config,
+    )
+    .await
+    .map(|result| result.cost)
+}
+
+pub async fn store_with_options(
+    pcr: String,
+    key: &String,
+    exp: i64,
+    value: &String,
+    options: &StoreOptions,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<StoreResult, StorageError> {
+    // an absolute expiry replaces the relative one, but has to still be ahead
+    let exp = match options.expire_at_ms {
+        Some(at) => at - Utc::now().timestamp_millis(),
+        None => exp,
     };
-    if value.len() > config.mem_threshold {
-        data.value = ipfs::add(value.to_string(), config).await?;
-        data.ipfs = true;
+    if exp <= 0 && (exp != -1 || options.expire_at_ms.is_some()) {
+        return Err(StorageError::BadExpiry);
     }
-    let value = serde_json::to_string(&data)?;
-    let mut cost = value.len() as i64;
-    if exp > 0 {
-        cost = key.len() as i64 + cost;
-        redis::cmd("SET")
-            .arg(key)
-            .arg(value)
-            .arg("PX")
-            .arg(exp)
-            .query_async(conn)
-            .await?;
-    } else if exp == -1 {
-        // only set the key if it already exist.
-        let old_value: String = redis::cmd("SET")
-            .arg(key)
-            .arg(value)
-            .arg("XX")
-            .arg("GET")
-            .arg("KEEPTTL")
-            .query_async(conn)
-            .await?;
-        cost = cmp::max(cost - old_value.len() as i64, 0);
-    } else {
-        return Err("expiry cannot be zero".into());
+    check_expiry(exp, config)?;
+    validate_key(key, config)?;
+    check_value_size(value, config)?;
+    let size = value.len() + metadata_size(&options.metadata);
+    if size > config.max_value_bytes {
+        return Err(StorageError::ValueTooLarge);
+    }
+    let expire_at = match options.expire_at_ms {
+        Some(at) => at,
+        None if exp > 0 => Utc::now().timestamp_millis() + exp,
+        None => -1,
+    };
+    if let Some(version) = options.if_version {
+        return store_if_version(
+            pcr, key, exp, expire_at, value, version, options, cache, conn, config,
+        )
+        .await;
+    }
+    let usage_key = key;
+    let previous = reserve_usage(&pcr, usage_key, size, expire_at, conn, config).await?;
+
+    let key = get_namespaced_key(&pcr, key, config);
+    // the blob reference is only taken by the write, so a failed store leaves none
+    let mut data = match prepare_storage_data(&pcr, value, options.storage, conn, config).await {
+        Ok(data) => data,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, None, cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    data.metadata = options.metadata.clone();
+    let mut result = StoreResult {
+        modified: data.modified,
+        size: value.len(),
+        ipfs: data.ipfs,
+        cost: 0,
+        version: data.version,
+    };
+    let value = match encode(&data, config) {
+        Ok(value) => value,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    let cost = value.len() as i64;
+    let (mode, ttl) = match options.expire_at_ms {
+        Some(at) => ("at", at),
+        None if exp > 0 => ("px", exp),
+        None => ("keep", 0),
+    };
+    let written: redis::RedisResult<(i64, Vec<u8>)> = redis::Script::new(STORE_SCRIPT)
+        .key(&key)
+        .key(IPFS_REFS_KEY)
+        .arg(value)
+        .arg(mode)
+        .arg(ttl)
+        .arg(if data.ipfs { data.value.as_str() } else { "" })
+        .invoke_async(conn)
+        .instrument(info_span!("redis.store", pcr = %pcr, key = span_key(usage_key, config)))
+        .await;
+    let old_value = match written {
+        Ok((1, old_value)) => (!old_value.is_empty()).then_some(old_value),
+        Ok(_) => {
+            // nothing was written, so give back the usage and the blob uploaded above
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(StorageError::NotFound);
+        }
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e.into());
+        }
+    };
+    if exp == -1 {
+        let old_value = old_value.unwrap_or_default();
+        retire_value(&pcr, usage_key, &old_value, cache, conn, config).await?;
+        let cost = cmp::max(cost - old_value.len() as i64, 0);
+        // the key keeps its previous ttl, so bill for the time it has left
+        let ttl: i64 = conn.pttl(&key).await?;
+        result.cost = store_cost(cost, cmp::max(ttl, 0), config);
+        return Ok(result);
+    }
+    if let Some(old_value) = old_value {
+        retire_value(&pcr, usage_key, &old_value, cache, conn, config).await?;
     }
-    Ok(cost * (exp / 1000) * config.memory_cost + config.operation_c_cost)
+    result.cost = store_cost(key.len() as i64 + cost, exp, config);
+    Ok(result)
 }
 
-async fn store_locked(
-    pcr: String,
+/// Reserves room for a write of `size` bytes to `key`, counted with the key, and
+/// returns the usage recorded before for `abandon_store` to put back if the write
+/// doesn't happen.
+async fn reserve_usage(
+    pcr: &String,
     key: &String,
-    value: &[u8],
-    conn: &mut redis::aio::Connection,
+    size: usize,
+    expire_at: i64,
+    conn: &mut ConnectionManager,
     config: &Config,
-) -> Result<bool, Box<dyn Error>> {
-    let key = get_locked_key(&pcr, key);
+) -> Result<(i64, Option<i64>), StorageError> {
+    let previous = recorded_usage(pcr, key, conn).await?;
+    update_usage(pcr, key, (key.len() + size) as i64, expire_at, conn, config).await?;
+    Ok(previous)
+}
 
-    let res: bool = redis::cmd("SET")
-        .arg(key)
-        .arg(value)
-        .arg("NX")
-        .arg("PX")
-        .arg(config.lock_expiry)
+/// The bytes the usage hash counts for `key` and the expiry it has for them, if any.
+async fn recorded_usage(
+    pcr: &String,
+    key: &String,
+    conn: &mut ConnectionManager,
+) -> Result<(i64, Option<i64>), StorageError> {
+    let (size, expire_at): (Option<i64>, Option<f64>) = redis::pipe()
+        .hget(get_usage_key(pcr), key)
+        .zscore(get_usage_expiry_key(pcr), key)
         .query_async(conn)
         .await?;
-    Ok(res)
+    Ok((size.unwrap_or(0), expire_at.map(|at| at as i64)))
 }
 
-pub async fn delete(
-    pcr: String,
+/// Puts the usage of `key` back to what `recorded_usage` returned before a store
+/// reserved room for it.
+async fn restore_usage(
+    pcr: &String,
+    key: &String,
+    (size, expire_at): (i64, Option<i64>),
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    count_usage(pcr, key, size, expire_at.unwrap_or(-1), conn).await?;
+    if size > 0 && expire_at.is_none() {
+        let _: () = conn.zrem(get_usage_expiry_key(pcr), key).await?;
+    }
+    Ok(())
+}
+
+/// Undoes a store that failed before its value was written: the usage it reserved
+/// and the blob uploaded for `data`, unless other keys hold it. errors are logged, so
+/// the caller can still report the one that made it give up.
+async fn abandon_store(
+    pcr: &String,
     key: &String,
-    conn: &mut redis::aio::Connection,
+    previous: (i64, Option<i64>),
+    data: Option<StorageData>,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
     config: &Config,
-) -> Result<i64, Box<dyn Error>> {
-    let key = get_namespaced_key(&pcr, key);
-    let value: String = redis::cmd("GET")
-        .arg(key.to_string())
-        .query_async(conn)
-        .await?;
-    if value.len() > 0 {
-        let value: StorageData = serde_json::from_str(&String::from(value))?;
-        if value.ipfs {
-            ipfs::delete(value.value, config).await?;
+) {
+    if let Err(e) = restore_usage(pcr, key, previous, conn).await {
+        error!(pcr = %pcr, key = %key, "could not restore usage: {}", e);
+    }
+    if let Some(data) = data {
+        if let Err(e) = discard_unreferenced(data, cache, conn, config).await {
+            error!(pcr = %pcr, key = %key, "could not discard unwritten value: {}", e);
         }
     }
-    redis::cmd("DEL").arg(key).query_async(conn).await?;
-    Ok(config.operation_c_cost)
 }
 
-pub async fn delete_locked(
+/// The rest of `store_with_options` when the write is conditional on the version
+/// of the stored value, failing with `PreconditionFailed` if it has another one.
+async fn store_if_version(
     pcr: String,
     key: &String,
-    conn: &mut redis::aio::Connection,
-) -> Result<(), Box<dyn Error>> {
-    let key = get_locked_key(&pcr, key);
-    redis::cmd("DEL").arg(key).query_async(conn).await?;
-    Ok(())
+    exp: i64,
+    expire_at: i64,
+    value: &String,
+    version: u64,
+    options: &StoreOptions,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<StoreResult, StorageError> {
+    let usage_key = key;
+    let key = get_namespaced_key(&pcr, key, config);
+    let current: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(conn).await?;
+    let current = current.ok_or(StorageError::PreconditionFailed)?;
+    let current_data = decode(&current)?;
+    if current_data.version != Some(version) {
+        return Err(StorageError::PreconditionFailed);
+    }
+
+    let size = value.len() + metadata_size(&options.metadata);
+    let previous = reserve_usage(&pcr, usage_key, size, expire_at, conn, config).await?;
+    // the blob reference is only taken by the write, so a failed store leaves none
+    let mut data = match prepare_storage_data(&pcr, value, options.storage, conn, config).await {
+        Ok(data) => data,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, None, cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    data.metadata = options.metadata.clone();
+    let mut result = StoreResult {
+        modified: data.modified,
+        size: value.len(),
+        ipfs: data.ipfs,
+        cost: 0,
+        version: data.version,
+    };
+    let raw = match encode(&data, config) {
+        Ok(raw) => raw,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    let cost = (key.len() + raw.len()) as i64;
+    // the script checks the raw data read above is still there, so a write racing
+    // this one can't slip in between the version check and the SET
+    let written: redis::RedisResult<bool> = redis::Script::new(STORE_IF_SCRIPT)
+        .key(&key)
+        .key(IPFS_REFS_KEY)
+        .arg(&current)
+        .arg(raw)
+        .arg(exp)
+        .arg(if data.ipfs { data.value.as_str() } else { "" })
+        .invoke_async(conn)
+        .instrument(info_span!("redis.store", pcr = %pcr, key = span_key(usage_key, config)))
+        .await;
+    match written {
+        Ok(true) => {}
+        Ok(false) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(StorageError::PreconditionFailed);
+        }
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e.into());
+        }
+    }
+    retire_value(&pcr, usage_key, &current, cache, conn, config).await?;
+    let ttl = if exp > 0 {
+        exp
+    } else {
+        let ttl: i64 = conn.pttl(&key).await?;
+        cmp::max(ttl, 0)
+    };
+    result.cost = store_cost(cost, ttl, config);
+    Ok(result)
 }
 
-pub async fn exists(
+/// Replaces the value and returns the one it held, if any, in a single `SET ... GET`.
+/// An `exp` of -1 keeps the previous ttl, or sets none when the key is new.
+pub async fn swap(
     pcr: String,
     key: &String,
-    conn: &mut redis::aio::Connection,
+    exp: i64,
+    value: &String,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
     config: &Config,
-) -> Result<(bool, i64), Box<dyn Error>> {
-    let key = get_namespaced_key(&pcr, key);
-    let ans: bool = conn.exists(key).await?;
-    Ok((ans, config.operation_c_cost))
+) -> Result<(Option<String>, i64), StorageError> {
+    if exp <= 0 && exp != -1 {
+        return Err(StorageError::BadExpiry);
+    }
+    check_expiry(exp, config)?;
+    validate_key(key, config)?;
+    check_value_size(value, config)?;
+    let expire_at = if exp > 0 {
+        Utc::now().timestamp_millis() + exp
+    } else {
+        -1
+    };
+    let usage_key = key;
+    // the swapped in value has no metadata, so any the old one had stops counting
+    let metadata = HashMap::new();
+    let size = value.len() + metadata_size(&metadata);
+    let previous = reserve_usage(&pcr, usage_key, size, expire_at, conn, config).await?;
+
+    let key = get_namespaced_key(&pcr, key, config);
+    // the blob reference is only taken by the write, so a failed swap leaves none
+    let mut data = match prepare_storage_data(&pcr, value, StorageMode::Auto, conn, config).await {
+        Ok(data) => data,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, None, cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    data.metadata = metadata;
+    let value = match encode(&data, config) {
+        Ok(value) => value,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    let cost = (key.len() + value.len()) as i64;
+    let written: redis::RedisResult<(i64, Vec<u8>)> = redis::Script::new(STORE_SCRIPT)
+        .key(&key)
+        .key(IPFS_REFS_KEY)
+        .arg(value)
+        .arg(if exp > 0 { "px" } else { "keepttl" })
+        .arg(exp)
+        .arg(if data.ipfs { data.value.as_str() } else { "" })
+        .invoke_async(conn)
+        .instrument(info_span!("redis.swap", pcr = %pcr, key = span_key(usage_key, config)))
+        .await;
+    let old_value = match written {
+        Ok((_, old_value)) => (!old_value.is_empty()).then_some(old_value),
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e.into());
+        }
+    };
+    let ttl = if exp > 0 {
+        exp
+    } else {
+        let ttl: i64 = conn.pttl(&key).await?;
+        cmp::max(ttl, 0)
+    };
+    let cost = store_cost(cost, ttl, config).saturating_add(config.operation_b_cost);
+    let old_value = match old_value {
+        Some(old_value) => old_value,
+        None => return Ok((None, cost)),
+    };
+    // fetch the previous value before releasing it, which may delete its blob, and
+    // release it even when the fetch fails since the key no longer refers to it
+    let old_data: StorageData = decode(&old_value)?;
+    let previous = load_value(old_data, cache, config).await;
+    retire_value(&pcr, usage_key, &old_value, cache, conn, config).await?;
+    Ok((Some(previous?), cost))
 }
 
-async fn exists_locked(
+/// Rejects relative expiries outside `config.min_expiry_ms` and `config.max_expiry_ms`,
+/// -1 keeps an existing expiry and isn't checked.
+fn check_expiry(exp: i64, config: &Config) -> Result<(), StorageError> {
+    if exp == -1 {
+        return Ok(());
+    }
+    if exp < config.min_expiry_ms || (config.max_expiry_ms > 0 && exp > config.max_expiry_ms) {
+        return Err(StorageError::ExpiryOutOfRange);
+    }
+    Ok(())
+}
+
+/// A value uploaded to the blob store by `offload_stream`, waiting for
+/// `store_offloaded` to put it under a key.
+pub struct Offloaded {
+    blob: blob::Blob,
+    size: usize,
+}
+
+/// Uploads a value arriving as a request body straight to `config.blob_store`.
+/// Needs no connection, so it can run without holding one for the whole upload.
+pub async fn offload_stream(body: hyper::Body, config: &Config) -> Result<Offloaded, StorageError> {
+    let (blob, size) = blob::add_stream(body, config.max_value_bytes, config)
+        .instrument(info_span!("blob.add_stream", backend = ?config.blob_store))
+        .await
+        .map_err(blob_error)?;
+    Ok(Offloaded { blob, size })
+}
+
+/// Stores a value uploaded by `offload_stream` under `key` for `exp` milliseconds.
+pub async fn store_offloaded(
     pcr: String,
     key: &String,
-    conn: &mut redis::aio::Connection,
-) -> Result<bool, Box<dyn Error>> {
-    let key = get_locked_key(&pcr, key);
-    let ans: bool = conn.exists(key).await?;
-    Ok(ans)
+    exp: i64,
+    value: Offloaded,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<StoreResult, StorageError> {
+    let mut data = StorageData {
+        value: value.blob.id,
+        modified: Utc::now().timestamp_millis(),
+        ipfs: true,
+        compressed: value.blob.compressed,
+        digest: Some(value.blob.digest),
+        size: Some(value.size),
+        backend: Some(config.blob_store),
+        version: None,
+        metadata: HashMap::new(),
+    };
+    // the blob is uploaded but unreferenced until the write takes a reference with it,
+    // so anything going wrong before that has to discard it
+    let usage_key = key;
+    let expire_at = data.modified + exp;
+    let reserved = async {
+        validate_expiry(exp, config)?;
+        validate_key(key, config)?;
+        let version = next_version(conn).await?;
+        let previous = reserve_usage(&pcr, usage_key, value.size, expire_at, conn, config).await?;
+        Ok::<_, StorageError>((version, previous))
+    }
+    .await;
+    let previous = match reserved {
+        Ok((version, previous)) => {
+            data.version = Some(version);
+            previous
+        }
+        Err(e) => {
+            if let Err(e) = discard_unreferenced(data, cache, conn, config).await {
+                error!(pcr = %pcr, key = %key, "could not discard blob: {}", e);
+            }
+            return Err(e);
+        }
+    };
+
+    let key = get_namespaced_key(&pcr, key, config);
+    let result = StoreResult {
+        modified: data.modified,
+        size: value.size,
+        ipfs: true,
+        cost: 0,
+        version: data.version,
+    };
+    let raw = match encode(&data, config) {
+        Ok(raw) => raw,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    let cost = (key.len() + raw.len()) as i64;
+    let written: redis::RedisResult<(i64, Vec<u8>)> = redis::Script::new(STORE_SCRIPT)
+        .key(&key)
+        .key(IPFS_REFS_KEY)
+        .arg(raw)
+        .arg("px")
+        .arg(exp)
+        .arg(data.value.as_str())
+        .invoke_async(conn)
+        .instrument(info_span!("redis.store", pcr = %pcr, key = span_key(usage_key, config)))
+        .await;
+    let old_value = match written {
+        Ok((_, old_value)) => (!old_value.is_empty()).then_some(old_value),
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e.into());
+        }
+    };
+    if let Some(old_value) = old_value {
+        retire_value(&pcr, usage_key, &old_value, cache, conn, config).await?;
+    }
+    Ok(StoreResult {
+        cost: store_cost(cost, exp, config),
+        ..result
+    })
 }
 
-pub async fn list(
+/// Checks a relative expiry for a new write, which has to be positive and in range.
+pub fn validate_expiry(exp: i64, config: &Config) -> Result<(), StorageError> {
+    if exp <= 0 {
+        return Err(StorageError::BadExpiry);
+    }
+    check_expiry(exp, config)
+}
+
+/// Checks a key for a new write: at most `config.max_key_bytes` long, free of control
+/// characters and more than just separators, any of which would break listing and
+/// the namespace prefixes.
+pub fn validate_key(key: &str, config: &Config) -> Result<(), StorageError> {
+    if key.trim_matches(config.key_separator.as_str()).is_empty()
+        || key.len() > config.max_key_bytes
+        || key.chars().any(char::is_control)
+    {
+        return Err(StorageError::BadKey);
+    }
+    Ok(())
+}
+
+fn check_value_size(value: &String, config: &Config) -> Result<(), StorageError> {
+    if value.len() > config.max_value_bytes {
+        return Err(StorageError::ValueTooLarge);
+    }
+    Ok(())
+}
+
+/// Cost of keeping `bytes` for `exp` milliseconds plus the operation cost,
+/// saturating at `i64::MAX` rather than wrapping on huge values or expiries.
+fn store_cost(bytes: i64, exp: i64, config: &Config) -> i64 {
+    bytes
+        .saturating_mul(exp / 1000)
+        .saturating_mul(config.memory_cost)
+        .saturating_add(config.operation_c_cost)
+}
+
+pub async fn cas_touch(
     pcr: String,
-    prefix: &String,
-    recursive: bool,
-    conn: &mut redis::aio::Connection,
+    key: &String,
+    expected: &String,
+    new: &String,
+    exp: i64,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
     config: &Config,
-) -> Result<(Vec<String>, i64), Box<dyn Error>> {
-    let mut keysfound: Vec<String> = Vec::new();
-    let firstpointer = 0;
-    let mut pointer = 0;
-    let search: String;
-
-    if prefix == "*" || prefix.trim().len() == 0 {
-        search = get_namespaced_key(&pcr, &String::from("*"));
-    } else {
-        search = get_namespaced_key(&pcr, &String::from(prefix)) + "*";
+) -> Result<(bool, i64), StorageError> {
+    if exp <= 0 {
+        return Err(StorageError::BadExpiry);
     }
+    check_expiry(exp, config)?;
+    validate_key(key, config)?;
+    check_value_size(new, config)?;
+    let usage_key = key;
+    let key = get_namespaced_key(&pcr, key, config);
+    let current: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(conn).await?;
+    let current = current.ok_or(StorageError::NotFound)?;
 
-    loop {
-        let mut res: (i32, Vec<String>) = redis::cmd("SCAN")
-            .arg(pointer)
-            .arg("MATCH")
-            .arg(&search)
-            .arg("COUNT")
-            .arg(1)
-            .query_async(conn)
-            .await?;
+    // ipfs backed values can only be compared after fetching the payload, so the
+    // script compares the raw stored data that was read here instead
+    let data: StorageData = decode(&current)?;
+    let value = load_value(data, cache, config).await?;
+    if value.ne(expected) {
+        return Ok((false, config.operation_c_cost));
+    }
 
-        for prefixed_key in &mut res.1 {
-            match prefixed_key.strip_prefix(&get_namespace_prefix(&pcr)) {
-                Some(val) => keysfound.push(String::from(val)),
-                _ => (),
-            }
+    let expire_at = Utc::now().timestamp_millis() + exp;
+    let previous = reserve_usage(&pcr, usage_key, new.len(), expire_at, conn, config).await?;
+    // the blob reference is only taken by the write, so a failed touch leaves none
+    let data = match prepare_storage_data(&pcr, new, StorageMode::Auto, conn, config).await {
+        Ok(data) => data,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, None, cache, conn, config).await;
+            return Err(e);
         }
-        //keysfound.append(&mut res.1);
-        pointer = res.0;
-        if firstpointer == pointer {
-            break;
+    };
+    let new_value = match encode(&data, config) {
+        Ok(value) => value,
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    let cost = (key.len() + new_value.len()) as i64;
+    let updated: redis::RedisResult<bool> = redis::Script::new(CAS_TOUCH_SCRIPT)
+        .key(&key)
+        .key(IPFS_REFS_KEY)
+        .arg(&current)
+        .arg(new_value)
+        .arg(exp)
+        .arg(if data.ipfs { data.value.as_str() } else { "" })
+        .invoke_async(conn)
+        .instrument(info_span!("redis.cas_touch", pcr = %pcr, key = span_key(usage_key, config)))
+        .await;
+    match updated {
+        Ok(true) => {}
+        Ok(false) => {
+            // the value changed under us, so go back to accounting for the one read above
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Ok((false, config.operation_c_cost));
+        }
+        Err(e) => {
+            abandon_store(&pcr, usage_key, previous, Some(data), cache, conn, config).await;
+            return Err(e.into());
         }
     }
+    retire_value(&pcr, usage_key, &current, cache, conn, config).await?;
+    Ok((true, store_cost(cost, exp, config)))
+}
 
-    if recursive || prefix == "*" || prefix.trim().len() == 0 {
-        return Ok((keysfound, config.operation_a_cost));
+async fn to_storage_data(
+    pcr: &String,
+    value: &String,
+    mode: StorageMode,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<StorageData, StorageError> {
+    let data = prepare_storage_data(pcr, value, mode, conn, config).await?;
+    if data.ipfs {
+        redis::cmd("HINCRBY")
+            .arg(IPFS_REFS_KEY)
+            .arg(&data.value)
+            .arg(1)
+            .query_async::<_, i64>(conn)
+            .await?;
     }
+    Ok(data)
+}
+
+/// Like `to_storage_data`, without taking a reference to the blob it uploads.
+async fn prepare_storage_data(
+    pcr: &String,
+    value: &String,
+    mode: StorageMode,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<StorageData, StorageError> {
+    let mut data = StorageData {
+        ipfs: false,
+        value: String::from(value),
+        modified: Utc::now().timestamp_millis(),
+        compressed: false,
+        digest: None,
+        size: Some(value.len()),
+        backend: None,
+        version: Some(next_version(conn).await?),
+        metadata: HashMap::new(),
+    };
+    let offload = match mode {
+        StorageMode::Auto => value.len() > mem_threshold(pcr, conn, config).await?,
+        StorageMode::Inline => false,
+        StorageMode::Ipfs => true,
+    };
+    if offload {
+        let blob = blob::add(value, config)
+            .instrument(info_span!("blob.add", backend = ?config.blob_store))
+            .await
+            .map_err(blob_error)?;
+        data.value = blob.id;
+        data.ipfs = true;
+        data.compressed = blob.compressed;
+        data.digest = Some(blob.digest);
+        data.backend = Some(config.blob_store);
+    }
+    Ok(data)
+}
+
+fn encode(data: &StorageData, config: &Config) -> Result<Vec<u8>, StorageError> {
+    match config.storage_format {
+        StorageFormat::Json => Ok(serde_json::to_vec(data)?),
+        // the fixed header has no room for metadata
+        StorageFormat::Compact if !data.ipfs && data.metadata.is_empty() => {
+            let mut raw = Vec::with_capacity(COMPACT_VERSIONED_HEADER_LEN + data.value.len());
+            match data.version {
+                Some(version) => {
+                    raw.push(COMPACT_VERSIONED_FORMAT);
+                    raw.extend(data.modified.to_be_bytes());
+                    raw.extend(version.to_be_bytes());
+                }
+                None => {
+                    raw.push(COMPACT_FORMAT);
+                    raw.extend(data.modified.to_be_bytes());
+                }
+            }
+            raw.extend(data.value.as_bytes());
+            Ok(raw)
+        }
+        StorageFormat::Msgpack | StorageFormat::Compact => {
+            let mut raw = vec![MSGPACK_FORMAT];
+            raw.extend(rmp_serde::to_vec_named(data)?);
+            Ok(raw)
+        }
+    }
+}
+
+fn decode(raw: &[u8]) -> Result<StorageData, StorageError> {
+    match raw.split_first() {
+        Some((&MSGPACK_FORMAT, rest)) => Ok(rmp_serde::from_slice(rest)?),
+        Some((&format, rest)) if format == COMPACT_FORMAT || format == COMPACT_VERSIONED_FORMAT => {
+            let header_len = if format == COMPACT_FORMAT {
+                COMPACT_HEADER_LEN
+            } else {
+                COMPACT_VERSIONED_HEADER_LEN
+            };
+            if rest.len() < header_len - 1 {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "truncated compact header").into(),
+                );
+            }
+            let (header, value) = rest.split_at(header_len - 1);
+            let (modified, version) = header.split_at(8);
+            let modified = i64::from_be_bytes(modified.try_into().unwrap_or_default());
+            let version = match version.try_into() {
+                Ok(version) => Some(u64::from_be_bytes(version)),
+                Err(_) => None,
+            };
+            let value = String::from_utf8(value.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(StorageData {
+                size: Some(value.len()),
+                value,
+                modified,
+                ipfs: false,
+                compressed: false,
+                digest: None,
+                backend: None,
+                version,
+                metadata: HashMap::new(),
+            })
+        }
+        _ => Ok(serde_json::from_slice(raw)?),
+    }
+}
+
+async fn next_version(conn: &mut ConnectionManager) -> Result<u64, StorageError> {
+    Ok(conn.incr(VERSION_KEY, 1).await?)
+}
+
+/// Deals with the raw `value` a write to `key` replaced: kept in the key's history
+/// when the namespace records one, released otherwise.
+async fn retire_value(
+    pcr: &String,
+    key: &String,
+    value: &[u8],
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(), StorageError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    let depth = history_depth(pcr, conn, config).await?;
+    if depth == 0 {
+        return release_value(value, cache, conn, config).await;
+    }
+    // the history entry takes over the value's pin, so only dropped ones are released
+    let history_key = get_history_key(pcr, key, config);
+    let dropped: Vec<Vec<u8>> = redis::Script::new(HISTORY_SCRIPT)
+        .key(&history_key)
+        .key(get_namespaced_key(pcr, key, config))
+        .arg(value)
+        .arg(depth)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.history", pcr = %pcr, key = span_key(key, config)))
+        .await?;
+    let mut grown = usage_size(key, &decode(value)?);
+    for value in dropped {
+        grown -= usage_size(key, &decode(&value)?);
+        release_value(&value, cache, conn, config).await?;
+    }
+    // the history counts towards usage like the key, and expires along with it
+    let history_usage_key = get_history_usage_key(key, config);
+    let counted: Option<i64> = conn.hget(get_usage_key(pcr), &history_usage_key).await?;
+    let ttl: i64 = conn.pttl(&history_key).await?;
+    let expire_at = match ttl {
+        ttl if ttl > 0 => Utc::now().timestamp_millis() + ttl,
+        _ => -1,
+    };
+    let size = cmp::max(counted.unwrap_or(0) + grown, 0);
+    count_usage(pcr, &history_usage_key, size, expire_at, conn).await?;
+    Ok(())
+}
+
+/// Releases whatever raw stored `value` had pinned, if anything.
+async fn release_value(
+    value: &[u8],
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(), StorageError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    let data: StorageData = decode(value)?;
+    release_cid(data, cache, conn, config).await
+}
+
+/// Drops this value's reference to its CID and unpins it once no key refers to it.
+async fn release_cid(
+    data: StorageData,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(), StorageError> {
+    if !data.ipfs {
+        return Ok(());
+    }
+    let count: i64 = redis::Script::new(RELEASE_CID_SCRIPT)
+        .key(IPFS_REFS_KEY)
+        .arg(&data.value)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.release", id = %data.value))
+        .await?;
+    if count <= 0 {
+        cache.remove(&data.value);
+        let backend = data.backend.unwrap_or_default();
+        blob::delete(backend, &data.value, config)
+            .instrument(info_span!("blob.delete", backend = ?backend, id = %data.value))
+            .await
+            .map_err(blob_error)?;
+    }
+    Ok(())
+}
+
+/// Deletes the blob uploaded for `data` if no key holds a reference to it.
+async fn discard_unreferenced(
+    data: StorageData,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(), StorageError> {
+    if !data.ipfs {
+        return Ok(());
+    }
+    let referenced: bool = conn.hexists(IPFS_REFS_KEY, &data.value).await?;
+    if !referenced {
+        cache.remove(&data.value);
+        let backend = data.backend.unwrap_or_default();
+        blob::delete(backend, &data.value, config)
+            .instrument(info_span!("blob.delete", backend = ?backend, id = %data.value))
+            .await
+            .map_err(blob_error)?;
+    }
+    Ok(())
+}
+
+/// The value `data` holds, fetched from its blob store when offloaded.
+async fn load_value(
+    data: StorageData,
+    cache: &BlobCache,
+    config: &Config,
+) -> Result<String, StorageError> {
+    if !data.ipfs {
+        return Ok(data.value);
+    }
+    if let Some(value) = cache.get(&data.value) {
+        return Ok(value);
+    }
+    let backend = data.backend.unwrap_or_default();
+    let value = blob::get(
+        backend,
+        &data.value,
+        data.compressed,
+        data.digest.as_deref(),
+        config,
+    )
+    .instrument(info_span!("blob.get", backend = ?backend, id = %data.value))
+    .await
+    .map_err(blob_error)?;
+    cache.insert(&data.value, &value);
+    Ok(value)
+}
+
+/// The key as recorded on spans, which leave the service when they're exported.
+fn span_key<'a>(key: &'a str, config: &Config) -> &'a str {
+    if config.trace_redact_keys {
+        return "<redacted>";
+    }
+    key
+}
+
+/// Keeps integrity failures apart from the backend simply being unavailable.
+fn blob_error(e: Box<dyn std::error::Error>) -> StorageError {
+    if e.is::<IntegrityError>() {
+        return StorageError::Integrity;
+    }
+    if e.is::<BodyTooLarge>() {
+        return StorageError::ValueTooLarge;
+    }
+    StorageError::Blob(e.to_string())
+}
+
+/// The offload threshold set for the namespace, or `config.mem_threshold`.
+async fn mem_threshold(
+    pcr: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<usize, StorageError> {
+    let threshold: Option<usize> = conn.get(get_mem_threshold_key(pcr)).await?;
+    Ok(threshold.unwrap_or(config.mem_threshold))
+}
+
+/// Previous values kept per key in the namespace, capped at `config.max_history_depth`.
+async fn history_depth(
+    pcr: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<usize, StorageError> {
+    let depth: Option<usize> = conn.get(get_history_depth_key(pcr)).await?;
+    Ok(cmp::min(depth.unwrap_or(0), config.max_history_depth))
+}
+
+/// Keeps the last `depth` values of each key in the namespace once overwritten, or
+/// stops keeping them with `None`. Histories already recorded are left as they are.
+pub async fn set_history_depth(
+    pcr: String,
+    depth: Option<usize>,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    let key = get_history_depth_key(&pcr);
+    match depth {
+        Some(depth) => conn.set(key, depth).await?,
+        None => conn.del(key).await?,
+    }
+    Ok(())
+}
+
+/// The previous values recorded for `key`, newest first.
+pub async fn history(
+    pcr: String,
+    key: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<HistoryEntry>, i64), StorageError> {
+    let raws: Vec<Vec<u8>> = conn
+        .lrange(get_history_key(&pcr, key, config), 0, -1)
+        .await?;
+    let mut entries = Vec::with_capacity(raws.len());
+    for raw in raws {
+        let data = decode(&raw)?;
+        entries.push(HistoryEntry {
+            version: data.version,
+            modified: data.modified,
+            size: data.size.unwrap_or(data.value.len()),
+            ipfs: data.ipfs,
+        });
+    }
+    Ok((entries, config.operation_b_cost))
+}
+
+/// Writes the value `key` had at `version` back as its current value, which is
+/// stored as a new version like any other write.
+pub async fn restore_version(
+    pcr: String,
+    key: &String,
+    version: u64,
+    exp: i64,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<StoreResult, StorageError> {
+    let raws: Vec<Vec<u8>> = conn
+        .lrange(get_history_key(&pcr, key, config), 0, -1)
+        .await?;
+    let mut found = None;
+    for raw in raws {
+        let data = decode(&raw)?;
+        if data.version == Some(version) {
+            found = Some(data);
+            break;
+        }
+    }
+    let value = load_value(found.ok_or(StorageError::NotFound)?, cache, config).await?;
+    store_with_options(
+        pcr,
+        key,
+        exp,
+        &value,
+        &StoreOptions::default(),
+        cache,
+        conn,
+        config,
+    )
+    .await
+}
+
+/// The request rate set for the namespace, if any.
+pub async fn rate_limit(
+    pcr: String,
+    conn: &mut ConnectionManager,
+) -> Result<Option<u64>, StorageError> {
+    Ok(conn.get(get_rate_limit_key(&pcr)).await?)
+}
+
+/// Overrides `config.rate_limit_per_sec` for the namespace, or goes back to it with `None`.
+pub async fn set_rate_limit(
+    pcr: String,
+    limit: Option<u64>,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    let key = get_rate_limit_key(&pcr);
+    match limit {
+        Some(limit) => conn.set(key, limit).await?,
+        None => conn.del(key).await?,
+    }
+    Ok(())
+}
+
+/// The hash of the API token set for the namespace, if there is one.
+pub async fn api_token_hash(
+    pcr: String,
+    conn: &mut ConnectionManager,
+) -> Result<Option<String>, StorageError> {
+    Ok(conn.get(get_api_token_key(&pcr)).await?)
+}
+
+/// Sets the API token clients of the namespace must present, or removes it with
+/// `None`. Only its hash is stored.
+pub async fn set_api_token(
+    pcr: String,
+    token: Option<String>,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    let key = get_api_token_key(&pcr);
+    match token {
+        Some(token) => conn.set(key, token_hash(&token)).await?,
+        None => conn.del(key).await?,
+    }
+    Ok(())
+}
+
+/// What API tokens are compared and stored as, so neither comparing them leaks
+/// the token through timing nor reading Redis reveals it.
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Overrides `config.mem_threshold` for the namespace, or goes back to it with `None`.
+pub async fn set_mem_threshold(
+    pcr: String,
+    threshold: Option<usize>,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    let key = get_mem_threshold_key(&pcr);
+    match threshold {
+        Some(threshold) => conn.set(key, threshold).await?,
+        None => conn.del(key).await?,
+    }
+    Ok(())
+}
+
+async fn store_locked(
+    pcr: String,
+    key: &String,
+    value: &[u8],
+    expiry: u64,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<Option<u64>, StorageError> {
+    let fence: u64 = redis::Script::new(LOCK_SCRIPT)
+        .key(get_locked_key(&pcr, key, config))
+        .key(get_fence_key(&pcr, key, config))
+        .arg(value)
+        .arg(expiry)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.lock", pcr = %pcr, key = span_key(key, config)))
+        .await?;
+    if fence == 0 {
+        return Ok(None);
+    }
+    Ok(Some(fence))
+}
+
+pub async fn delete(
+    pcr: String,
+    key: &String,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<i64, StorageError> {
+    let usage_key = key;
+    let key = get_namespaced_key(&pcr, key, config);
+    // read and removed in one step, so the value released is the one deleted
+    let value: Option<Vec<u8>> = redis::cmd("GETDEL")
+        .arg(key)
+        .query_async(conn)
+        .instrument(info_span!("redis.getdel", pcr = %pcr, key = span_key(usage_key, config)))
+        .await?;
+    let value = value.ok_or(StorageError::NotFound)?;
+    // the key is gone whatever happens to its blob, so it stops counting first
+    update_usage(&pcr, usage_key, 0, -1, conn, config).await?;
+    release_value(&value, cache, conn, config).await?;
+    Ok(config.operation_c_cost)
+}
+
+/// Moves the key to the namespace's trash instead of deleting it, where `restore`
+/// can bring it back until `purge_trash_batch` removes it `config.trash_retention`
+/// milliseconds later, or when the key would have expired if that is sooner. The
+/// key's bytes keep counting towards the namespace's usage until then.
+pub async fn soft_delete(
+    pcr: String,
+    key: &String,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<i64, StorageError> {
+    let (found, old, purge_at, size): (bool, Vec<u8>, i64, i64) =
+        redis::Script::new(SOFT_DELETE_SCRIPT)
+            .key(get_namespaced_key(&pcr, key, config))
+            .key(get_trash_key(&pcr, key, config))
+            .key(TRASH_INDEX_KEY)
+            .key(TRASH_TTL_KEY)
+            .key(get_usage_key(&pcr))
+            .arg(Utc::now().timestamp_millis())
+            .arg(config.trash_retention)
+            .arg(key)
+            .invoke_async(conn)
+            .instrument(info_span!("redis.soft_delete", pcr = %pcr, key = span_key(key, config)))
+            .await?;
+    if !found {
+        return Err(StorageError::NotFound);
+    }
+    // deleting the same key twice keeps only the latest copy
+    if !old.is_empty() {
+        release_value(&old, cache, conn, config).await?;
+    }
+    // the bytes move over to the trash entry, which stops counting once purged
+    update_usage(&pcr, key, 0, -1, conn, config).await?;
+    count_usage(
+        &pcr,
+        &get_trash_usage_key(key, config),
+        size,
+        purge_at,
+        conn,
+    )
+    .await?;
+    Ok(config.operation_c_cost)
+}
+
+/// Brings a soft deleted key back with the ttl it had left, failing with
+/// `AlreadyExists` if the key has been written since.
+pub async fn restore(
+    pcr: String,
+    key: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<i64, StorageError> {
+    let trash_key = get_trash_key(&pcr, key, config);
+    let namespaced_key = get_namespaced_key(&pcr, key, config);
+    let (raw, ttl): (Option<Vec<u8>>, Option<i64>) = redis::pipe()
+        .cmd("GET")
+        .arg(&trash_key)
+        .cmd("HGET")
+        .arg(TRASH_TTL_KEY)
+        .arg(&trash_key)
+        .query_async(conn)
+        .await?;
+    let data = decode(&raw.ok_or(StorageError::NotFound)?)?;
+    let exists: bool = conn.exists(&namespaced_key).await?;
+    if exists {
+        return Err(StorageError::AlreadyExists);
+    }
+    let expire_at = match ttl {
+        Some(ttl) if ttl > 0 => Utc::now().timestamp_millis() + ttl,
+        _ => -1,
+    };
+    let size = (key.len() + data.size.unwrap_or(data.value.len())) as i64;
+    // the trash entry's bytes move back to the key
+    let trash_usage_key = get_trash_usage_key(key, config);
+    let trash_size: Option<i64> = conn.hget(get_usage_key(&pcr), &trash_usage_key).await?;
+    let trash_size = trash_size.unwrap_or(0);
+    let purge_at: Option<i64> = conn.zscore(TRASH_INDEX_KEY, &trash_key).await?;
+    let purge_at = purge_at.unwrap_or(-1);
+    update_usage(&pcr, &trash_usage_key, 0, -1, conn, config).await?;
+    if let Err(e) = update_usage(&pcr, key, size, expire_at, conn, config).await {
+        count_usage(&pcr, &trash_usage_key, trash_size, purge_at, conn).await?;
+        return Err(e);
+    }
+    let restored: i64 = redis::Script::new(RESTORE_SCRIPT)
+        .key(&trash_key)
+        .key(&namespaced_key)
+        .key(TRASH_INDEX_KEY)
+        .key(TRASH_TTL_KEY)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.restore", pcr = %pcr, key = span_key(key, config)))
+        .await?;
+    match restored {
+        1 => Ok(config.operation_c_cost),
+        // purged in between, so neither counts any more
+        0 => {
+            update_usage(&pcr, key, 0, -1, conn, config).await?;
+            Err(StorageError::NotFound)
+        }
+        _ => {
+            // written by someone else in between, so record what they wrote instead
+            let raw: Option<Vec<u8>> = conn.get(&namespaced_key).await?;
+            let size = match raw {
+                Some(raw) => {
+                    let data = decode(&raw)?;
+                    (key.len() + data.size.unwrap_or(data.value.len())) as i64
+                }
+                None => 0,
+            };
+            update_usage(&pcr, key, size, -1, conn, config).await?;
+            count_usage(&pcr, &trash_usage_key, trash_size, purge_at, conn).await?;
+            Err(StorageError::AlreadyExists)
+        }
+    }
+}
+
+/// Removes one batch of soft deleted keys whose retention has passed along with
+/// their blobs, returning how many were purged and whether any are left to do.
+pub async fn purge_trash_batch(
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(i64, bool), StorageError> {
+    let due: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+        .arg(TRASH_INDEX_KEY)
+        .arg("-inf")
+        .arg(Utc::now().timestamp_millis())
+        .arg("LIMIT")
+        .arg(0)
+        .arg(EVICT_BATCH)
+        .query_async(conn)
+        .await?;
+    let mut purged = 0;
+    for key in &due {
+        let (raw,): (Option<Vec<u8>>,) = redis::pipe()
+            .atomic()
+            .cmd("GET")
+            .arg(key)
+            .cmd("DEL")
+            .arg(key)
+            .ignore()
+            .cmd("ZREM")
+            .arg(TRASH_INDEX_KEY)
+            .arg(key)
+            .ignore()
+            .cmd("HDEL")
+            .arg(TRASH_TTL_KEY)
+            .arg(key)
+            .ignore()
+            .query_async(conn)
+            .await?;
+        // gone already if the namespace was evicted
+        if let Some(raw) = raw {
+            release_value(&raw, cache, conn, config).await?;
+            purged += 1;
+        }
+    }
+    Ok((purged, due.len() == EVICT_BATCH))
+}
+
+/// Deletes the key only if its value is still `expected`, returning whether it did.
+pub async fn cas_delete(
+    pcr: String,
+    key: &String,
+    expected: &String,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(bool, i64), StorageError> {
+    let usage_key = key;
+    let key = get_namespaced_key(&pcr, key, config);
+    let current: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(conn).await?;
+    let current = current.ok_or(StorageError::NotFound)?;
+
+    // as in cas_touch, the logical value is compared here and the script only
+    // checks the raw stored data hasn't changed since
+    let data: StorageData = decode(&current)?;
+    if load_value(data, cache, config).await?.ne(expected) {
+        return Ok((false, config.operation_c_cost));
+    }
+    let deleted: bool = redis::Script::new(CAS_DELETE_SCRIPT)
+        .key(&key)
+        .arg(&current)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.cas_delete", pcr = %pcr, key = span_key(usage_key, config)))
+        .await?;
+    if deleted {
+        release_value(&current, cache, conn, config).await?;
+        update_usage(&pcr, usage_key, 0, -1, conn, config).await?;
+    }
+    Ok((deleted, config.operation_c_cost))
+}
+
+/// Runs `ops` in order as one atomic step, returning a result per op. Nothing is
+/// written if any precondition fails, and the whole transaction is retried up to
+/// `config.retry_count` times if a key it read changes before it commits.
+pub async fn transaction(
+    pcr: String,
+    ops: &[TxOp],
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<TxResult>, i64), StorageError> {
+    if ops.len() > config.max_batch_keys {
+        return Err(StorageError::TooManyKeys);
+    }
+    if ops.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    for op in ops {
+        validate_key(&op.key, config)?;
+        match op.op {
+            TxOpKind::Set | TxOpKind::Incr => {
+                if op.expiry <= 0 && op.expiry != -1 {
+                    return Err(StorageError::BadExpiry);
+                }
+                check_expiry(op.expiry, config)?;
+            }
+            TxOpKind::Get | TxOpKind::Del => {}
+        }
+        if op.op == TxOpKind::Set {
+            check_value_size(op.value.as_ref().ok_or(StorageError::BadTxOp)?, config)?;
+        }
+    }
+    let keys: Vec<String> = ops
+        .iter()
+        .map(|op| op.key.clone())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+    for _ in 0..cmp::max(config.retry_count, 1) {
+        if let Some(result) = try_transaction(&pcr, ops, &keys, cache, conn, config).await? {
+            return Ok(result);
+        }
+    }
+    Err(StorageError::Conflict)
+}
+
+/// One attempt at `transaction`, returning `None` if a key changed before it
+/// could commit.
+async fn try_transaction(
+    pcr: &String,
+    ops: &[TxOp],
+    keys: &[String],
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<Option<(Vec<TxResult>, i64)>, StorageError> {
+    let prefix = get_namespace_prefix(pcr, config);
+    let namespaced: Vec<String> = keys.iter().map(|key| prefixed_key(&prefix, key)).collect();
+    let raws: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+        .arg(&namespaced)
+        .query_async(conn)
+        .await?;
+    let mut slots = Vec::with_capacity(keys.len());
+    for raw in raws {
+        let value = match &raw {
+            Some(raw) => TxValue::Stored(decode(raw)?),
+            None => TxValue::Missing,
+        };
+        slots.push(TxSlot {
+            raw: raw.unwrap_or_default(),
+            value,
+            written: false,
+            expiry: -1,
+            last_write: 0,
+        });
+    }
+
+    let mut results = Vec::with_capacity(ops.len());
+    let mut cost: i64 = 0;
+    for (i, op) in ops.iter().enumerate() {
+        // keys came out of a BTreeSet, so are sorted
+        let slot = match keys.binary_search(&op.key) {
+            Ok(index) => &mut slots[index],
+            Err(_) => return Err(StorageError::NotFound),
+        };
+        let version = match &slot.value {
+            TxValue::Missing => None,
+            TxValue::Stored(data) => data.version,
+            TxValue::Loaded(_, version) => *version,
+        };
+        let exists = !matches!(slot.value, TxValue::Missing);
+        if op.if_version.is_some() && op.if_version != version
+            || op.if_exists.map_or(false, |want| want != exists)
+        {
+            return Err(StorageError::PreconditionFailed);
+        }
+        let mut result = TxResult::default();
+        match op.op {
+            TxOpKind::Get => {
+                result.value = tx_value(slot, cache, config).await?;
+                cost = cost.saturating_add(config.operation_b_cost);
+            }
+            TxOpKind::Del => {
+                slot.value = TxValue::Missing;
+                slot.written = true;
+                cost = cost.saturating_add(config.operation_c_cost);
+            }
+            TxOpKind::Set | TxOpKind::Incr => {
+                let value = match op.op {
+                    TxOpKind::Set => op.value.clone().unwrap_or_default(),
+                    _ => {
+                        let current = match tx_value(slot, cache, config).await? {
+                            Some(value) => value.parse::<i64>().ok(),
+                            None => Some(0),
+                        };
+                        let value = current
+                            .and_then(|current| current.checked_add(op.by.unwrap_or(1)))
+                            .ok_or(StorageError::NotAnInteger)?
+                            .to_string();
+                        result.value = Some(value.clone());
+                        value
+                    }
+                };
+                // as with store, -1 keeps the ttl of a key that has to exist already
+                if op.expiry == -1 {
+                    if !exists {
+                        return Err(StorageError::NotFound);
+                    }
+                } else {
+                    slot.expiry = op.expiry;
+                }
+                slot.value = TxValue::Loaded(value, None);
+                slot.written = true;
+                slot.last_write = i;
+            }
+        }
+        results.push(result);
+    }
+
+    // usage and blob pins are taken before committing, as store does, and handed
+    // back if the commit doesn't happen
+    let mut pinned = Vec::new();
+    let mut writes: Vec<(usize, Vec<u8>)> = Vec::new();
+    let mut prepared = Ok(());
+    for (index, (key, slot)) in keys.iter().zip(&slots).enumerate() {
+        if !slot.written {
+            continue;
+        }
+        let value = match &slot.value {
+            TxValue::Loaded(value, _) => value,
+            _ => {
+                writes.push((index, Vec::new()));
+                continue;
+            }
+        };
+        let expire_at = match slot.expiry {
+            -1 => -1,
+            expiry => Utc::now().timestamp_millis() + expiry,
+        };
+        let size = (key.len() + value.len()) as i64;
+        if let Err(e) = update_usage(pcr, key, size, expire_at, conn, config).await {
+            prepared = Err(e);
+            break;
+        }
+        let data = match to_storage_data(pcr, value, StorageMode::Auto, conn, config).await {
+            Ok(data) => data,
+            Err(e) => {
+                prepared = Err(e);
+                break;
+            }
+        };
+        results[slot.last_write].version = data.version;
+        let raw = encode(&data, config);
+        pinned.push(data);
+        match raw {
+            Ok(raw) => writes.push((index, raw)),
+            Err(e) => {
+                prepared = Err(e);
+                break;
+            }
+        }
+    }
+    if let Err(e) = prepared {
+        undo_transaction(pcr, keys, &slots, pinned, cache, conn, config).await?;
+        return Err(e);
+    }
+
+    let script = redis::Script::new(TX_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    for (key, slot) in namespaced.iter().zip(&slots) {
+        invocation.key(key).arg(&slot.raw);
+    }
+    for (index, raw) in &writes {
+        invocation.arg(index + 1).arg(raw).arg(slots[*index].expiry);
+    }
+    let committed = invocation
+        .invoke_async::<_, bool>(conn)
+        .instrument(info_span!("redis.transaction", pcr = %pcr, keys = namespaced.len()))
+        .await;
+    match committed {
+        Ok(true) => {}
+        Ok(false) => {
+            undo_transaction(pcr, keys, &slots, pinned, cache, conn, config).await?;
+            return Ok(None);
+        }
+        Err(e) => {
+            undo_transaction(pcr, keys, &slots, pinned, cache, conn, config).await?;
+            return Err(e.into());
+        }
+    }
+
+    // committed, so nothing below may fail the transaction: cleanup that doesn't
+    // happen only leaves a blob pinned or usage counted for longer than needed
+    for (index, raw) in writes {
+        let (key, slot) = (&keys[index], &slots[index]);
+        if raw.is_empty() {
+            if !slot.raw.is_empty() {
+                if let Err(e) = release_value(&slot.raw, cache, conn, config).await {
+                    error!(pcr = %pcr, key = %key, "could not release deleted value: {}", e);
+                }
+                if let Err(e) = update_usage(pcr, key, 0, -1, conn, config).await {
+                    error!(pcr = %pcr, key = %key, "could not update usage: {}", e);
+                }
+            }
+            continue;
+        }
+        if let Err(e) = retire_value(pcr, key, &slot.raw, cache, conn, config).await {
+            error!(pcr = %pcr, key = %key, "could not retire overwritten value: {}", e);
+        }
+        // a kept ttl is billed for the time the key has left, as in store
+        let ttl = match slot.expiry {
+            -1 => {
+                let ttl: redis::RedisResult<i64> = conn.pttl(&namespaced[index]).await;
+                cmp::max(ttl.unwrap_or(0), 0)
+            }
+            expiry => expiry,
+        };
+        let bytes = (namespaced[index].len() + raw.len()) as i64;
+        cost = cost.saturating_add(store_cost(bytes, ttl, config));
+    }
+    Ok(Some((results, cost)))
+}
+
+/// The value `slot` holds as of the op being run, loading it on first use.
+async fn tx_value(
+    slot: &mut TxSlot,
+    cache: &BlobCache,
+    config: &Config,
+) -> Result<Option<String>, StorageError> {
+    if let TxValue::Stored(_) = slot.value {
+        if let TxValue::Stored(data) = std::mem::replace(&mut slot.value, TxValue::Missing) {
+            let version = data.version;
+            slot.value = TxValue::Loaded(load_value(data, cache, config).await?, version);
+        }
+    }
+    match &slot.value {
+        TxValue::Loaded(value, _) => Ok(Some(value.clone())),
+        _ => Ok(None),
+    }
+}
+
+/// Hands back the usage and blob pins `try_transaction` took for a commit that
+/// didn't happen.
+async fn undo_transaction(
+    pcr: &String,
+    keys: &[String],
+    slots: &[TxSlot],
+    pinned: Vec<StorageData>,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(), StorageError> {
+    for (key, slot) in keys.iter().zip(slots) {
+        if !slot.written {
+            continue;
+        }
+        let size = match slot.raw.is_empty() {
+            true => 0,
+            false => {
+                let data = decode(&slot.raw)?;
+                (key.len() + data.size.unwrap_or(data.value.len())) as i64
+            }
+        };
+        update_usage(pcr, key, size, -1, conn, config).await?;
+    }
+    for data in pinned {
+        release_cid(data, cache, conn, config).await?;
+    }
+    Ok(())
+}
+
+/// Bytes currently stored under the namespace, counting key and value lengths.
+pub async fn usage(
+    pcr: String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<i64, StorageError> {
+    let total: i64 = redis::Script::new(USAGE_SCRIPT)
+        .key(get_usage_key(&pcr))
+        .key(get_usage_expiry_key(&pcr))
+        .key(get_usage_total_key(&pcr))
+        .arg(Utc::now().timestamp_millis())
+        .arg(config.max_bytes_per_pcr)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.usage", pcr = %pcr))
+        .await?;
+    Ok(total)
+}
+
+/// Records `size` bytes against `key` (0 once it is removed) and returns the new
+/// namespace total, failing if that would go over `config.max_bytes_per_pcr`.
+/// `expire_at` is the unix millisecond expiry, or -1 to keep the previous one.
+async fn update_usage(
+    pcr: &String,
+    key: &String,
+    size: i64,
+    expire_at: i64,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<i64, StorageError> {
+    let total: i64 = redis::Script::new(USAGE_SCRIPT)
+        .key(get_usage_key(pcr))
+        .key(get_usage_expiry_key(pcr))
+        .key(get_usage_total_key(pcr))
+        .arg(Utc::now().timestamp_millis())
+        .arg(config.max_bytes_per_pcr)
+        .arg(key)
+        .arg(size)
+        .arg(expire_at)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.usage", pcr = %pcr))
+        .await?;
+    if total < 0 {
+        return Err(StorageError::QuotaExceeded);
+    }
+    Ok(total)
+}
+
+/// Like `update_usage`, for bytes that are already stored and so can't be refused.
+async fn count_usage(
+    pcr: &String,
+    key: &String,
+    size: i64,
+    expire_at: i64,
+    conn: &mut ConnectionManager,
+) -> Result<i64, StorageError> {
+    let total: i64 = redis::Script::new(USAGE_SCRIPT)
+        .key(get_usage_key(pcr))
+        .key(get_usage_expiry_key(pcr))
+        .key(get_usage_total_key(pcr))
+        .arg(Utc::now().timestamp_millis())
+        .arg(0)
+        .arg(key)
+        .arg(size)
+        .arg(expire_at)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.usage", pcr = %pcr))
+        .await?;
+    Ok(total)
+}
+
+pub async fn exists(
+    pcr: String,
+    key: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(bool, i64), StorageError> {
+    let key = get_namespaced_key(&pcr, key, config);
+    let ans: bool = conn.exists(key).await?;
+    Ok((ans, config.operation_c_cost))
+}
+
+/// Checks all of `keys` with one pipelined EXISTS each, in a single round trip.
+pub async fn exists_batch(
+    pcr: String,
+    keys: &[String],
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(BTreeMap<String, bool>, i64), StorageError> {
+    if keys.len() > config.max_batch_keys {
+        return Err(StorageError::TooManyKeys);
+    }
+    if keys.is_empty() {
+        return Ok((BTreeMap::new(), 0));
+    }
+    let prefix = get_namespace_prefix(&pcr, config);
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.exists(prefixed_key(&prefix, key));
+    }
+    let results: Vec<bool> = pipe.query_async(conn).await?;
+    let found = keys.iter().cloned().zip(results).collect();
+    let cost = config.operation_c_cost.saturating_mul(keys.len() as i64);
+    Ok((found, cost))
+}
+
+#[cfg(test)]
+async fn exists_locked(
+    pcr: String,
+    key: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<bool, StorageError> {
+    let key = get_locked_key(&pcr, key, config);
+    let ans: bool = conn.exists(key).await?;
+    Ok(ans)
+}
+
+/// Lists every key matching the Redis glob `pattern`, which is matched against
+/// the whole key after the namespace prefix so it can't reach other namespaces.
+pub async fn list_matching(
+    pcr: String,
+    pattern: &str,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<String>, i64), StorageError> {
+    check_pattern(pattern)?;
+    let search = get_namespace_prefix(&pcr, config) + pattern;
+    let mut keysfound = BTreeSet::new();
+    scan_keys(&pcr, &search, &mut keysfound, conn, config).await?;
+    Ok((keysfound.into_iter().collect(), config.operation_a_cost))
+}
+
+/// Rejects patterns Redis would read past, an unclosed `[` class or a trailing
+/// escape, which could otherwise swallow the namespace separator.
+fn check_pattern(pattern: &str) -> Result<(), StorageError> {
+    if pattern.is_empty() {
+        return Err(StorageError::BadPattern);
+    }
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next().ok_or(StorageError::BadPattern)?;
+            }
+            '[' => loop {
+                match chars.next().ok_or(StorageError::BadPattern)? {
+                    ']' => break,
+                    '\\' => {
+                        chars.next().ok_or(StorageError::BadPattern)?;
+                    }
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// SCANs for `search`, adding matches to `keys` with the namespace stripped, which
+/// sorts them and drops the ones SCAN returns more than once. Each SCAN is sent on its
+/// own task as soon as the cursor for it comes back, so its round trip overlaps adding
+/// the batch before it.
+async fn scan_keys(
+    pcr: &String,
+    search: &String,
+    keys: &mut BTreeSet<String>,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(), StorageError> {
+    let prefix = get_namespace_prefix(pcr, config);
+    let scan = |cursor: u64| {
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(cursor)
+            .arg("MATCH")
+            .arg(search)
+            .arg("COUNT")
+            .arg(config.scan_count);
+        // the connection is multiplexed, so a clone of it shares the one socket
+        let mut conn = conn.clone();
+        tokio::spawn(async move { cmd.query_async::<_, (u64, Vec<String>)>(&mut conn).await })
+    };
+    let mut pending = scan(0);
+    loop {
+        let (cursor, found) = match pending.await {
+            Ok(scanned) => scanned?,
+            // the task only ends without a result if it panicked
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        let next = (cursor != 0).then(|| scan(cursor));
+        keys.extend(
+            found
+                .iter()
+                .filter_map(|key| key.strip_prefix(prefix.as_str()))
+                .map(String::from),
+        );
+        match next {
+            Some(next) => pending = next,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+pub async fn list(
+    pcr: String,
+    prefix: &String,
+    recursive: bool,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<String>, i64), StorageError> {
+    let mut keysfound = BTreeSet::new();
+    let search: String;
+
+    if prefix == "*" || prefix.trim().len() == 0 {
+        search = get_namespaced_key(&pcr, &String::from("*"), config);
+    } else {
+        search = get_namespaced_key(&pcr, &String::from(prefix), config) + "*";
+    }
+
+    scan_keys(&pcr, &search, &mut keysfound, conn, config).await?;
+
+    let keysfound: Vec<String> = keysfound.into_iter().collect();
+    if recursive || prefix == "*" || prefix.trim().len() == 0 {
+        return Ok((keysfound, config.operation_a_cost));
+    }
+
+    Ok((
+        collapse_children(prefix, &keysfound, &config.key_separator),
+        config.operation_a_cost,
+    ))
+}
+
+/// The immediate children of `prefix` among `keys`: keys with no further separator
+/// are returned as is and deeper ones are cut to their first level "directory",
+/// keeping the trailing separator. Like an S3 delimiter listing, a prefix that
+/// doesn't end in the separator also matches siblings, so `a` gives `a/` and `ab/`.
+fn collapse_children(prefix: &str, keys: &[String], separator: &str) -> Vec<String> {
+    let mut children = BTreeSet::new();
+    for key in keys {
+        let rest = match key.strip_prefix(prefix) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        match rest.find(separator) {
+            Some(end) => children.insert(String::from(prefix) + &rest[..end + separator.len()]),
+            None => children.insert(key.clone()),
+        };
+    }
+    children.into_iter().collect()
+}
+
+/// One SCAN page over the whole keyspace, adding the namespaces its keys are in to
+/// `namespaces`. Returns the cursor to continue from, or `None` once every key was seen.
+pub async fn namespaces_step(
+    cursor: u64,
+    namespaces: &mut BTreeSet<String>,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<Option<u64>, StorageError> {
+    let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("COUNT")
+        .arg(config.scan_count)
+        .query_async(conn)
+        .await?;
+    for key in keys {
+        // namespaced keys are `pcr` + separator + key, lock and bookkeeping keys
+        // carry a `.suffix`
+        if let Some((pcr, _)) = key.split_once(config.key_separator.as_str()) {
+            if !pcr.contains('.') {
+                namespaces.insert(String::from(pcr));
+            }
+        }
+    }
+    Ok((next != 0).then_some(next))
+}
+
+/// Adds each pcr's cost to its running total in Redis, all or nothing.
+pub async fn add_costs(
+    costs: &HashMap<String, i64>,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    if costs.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (pcr, cost) in costs {
+        pipe.cmd("HINCRBY")
+            .arg(COST_KEY)
+            .arg(pcr)
+            .arg(*cost)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(conn).await?;
+    Ok(())
+}
+
+/// Adds each pcr's handled operations to its window counter, starting a window of
+/// `window` milliseconds for pcrs without one. Pcrs are removed from `ops` once
+/// added, so on error it holds the counts still to add.
+pub async fn add_ops(
+    ops: &mut HashMap<String, u64>,
+    window: u64,
+    conn: &mut ConnectionManager,
+) -> Result<(), StorageError> {
+    let pcrs: Vec<String> = ops.keys().cloned().collect();
+    for pcr in pcrs {
+        redis::Script::new(OPS_SCRIPT)
+            .key(get_ops_key(&pcr))
+            .arg(ops[&pcr])
+            .arg(cmp::max(window, 1))
+            .invoke_async::<_, i64>(conn)
+            .instrument(info_span!("redis.ops", pcr = %pcr))
+            .await?;
+        ops.remove(&pcr);
+    }
+    Ok(())
+}
+
+/// Operations counted for the namespace in its current window, and the milliseconds
+/// until that window ends, negative when none has started.
+pub async fn ops(pcr: String, conn: &mut ConnectionManager) -> Result<(u64, i64), StorageError> {
+    let key = get_ops_key(&pcr);
+    let (count, left): (Option<u64>, i64) =
+        redis::pipe().get(&key).pttl(&key).query_async(conn).await?;
+    Ok((count.unwrap_or(0), left))
+}
+
+/// Returns every pcr's cost total and resets them in the same transaction, so no
+/// cost is counted twice or lost between reads.
+pub async fn take_costs(
+    conn: &mut ConnectionManager,
+) -> Result<HashMap<String, i64>, StorageError> {
+    let (costs,): (HashMap<String, i64>,) = redis::pipe()
+        .atomic()
+        .cmd("HGETALL")
+        .arg(COST_KEY)
+        .cmd("DEL")
+        .arg(COST_KEY)
+        .ignore()
+        .query_async(conn)
+        .await?;
+    Ok(costs)
+}
+
+/// How far an eviction has got, carried between `evict_step` calls.
+#[derive(Default)]
+pub struct Eviction {
+    // the prefix being scanned, an index into the ones `evict_step` works through
+    stage: usize,
+    cursor: u64,
+    /// keys and locks removed so far
+    pub removed: i64,
+}
+
+/// One SCAN batch towards deleting everything stored for a namespace: its keys,
+/// locks, fencing counters and usage accounting, releasing offloaded values on the
+/// way. Batches aren't one transaction, so writes racing the eviction may survive.
+/// Returns whether the eviction is done, with the count removed in `eviction`.
+pub async fn evict_step(
+    pcr: &String,
+    eviction: &mut Eviction,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<bool, StorageError> {
+    // what the keys under a prefix hold, so values they pin can be released
+    enum Holds {
+        Nothing,
+        Value,
+        History,
+    }
+    // (prefix, what its keys hold, counted in the result)
+    let stages = [
+        (get_namespace_prefix(pcr, config), Holds::Value, true),
+        (get_locked_prefix(pcr, config), Holds::Nothing, true),
+        (get_fence_prefix(pcr, config), Holds::Nothing, false),
+        (get_trash_prefix(pcr, config), Holds::Value, false),
+        (get_history_prefix(pcr, config), Holds::History, false),
+    ];
+    if let Some((prefix, holds, counted)) = stages.into_iter().nth(eviction.stage) {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(eviction.cursor)
+            .arg("MATCH")
+            .arg(prefix + "*")
+            .arg("COUNT")
+            .arg(EVICT_BATCH)
+            .query_async(conn)
+            .await?;
+        if !keys.is_empty() {
+            let raw: Vec<Vec<u8>> = match holds {
+                Holds::Nothing => Vec::new(),
+                Holds::Value => {
+                    let raw: Vec<Option<Vec<u8>>> =
+                        redis::cmd("MGET").arg(&keys).query_async(conn).await?;
+                    raw.into_iter().flatten().collect()
+                }
+                Holds::History => {
+                    let mut pipe = redis::pipe();
+                    for key in &keys {
+                        pipe.cmd("LRANGE").arg(key).arg(0).arg(-1);
+                    }
+                    let raw: Vec<Vec<Vec<u8>>> = pipe.query_async(conn).await?;
+                    raw.into_iter().flatten().collect()
+                }
+            };
+            for value in raw {
+                release_value(&value, cache, conn, config).await?;
+            }
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.cmd("DEL").arg(key).ignore();
+            }
+            pipe.query_async::<_, ()>(conn).await?;
+            if counted {
+                eviction.removed += keys.len() as i64;
+            }
+        }
+        eviction.cursor = next;
+        if next == 0 {
+            eviction.stage += 1;
+        }
+        return Ok(false);
+    }
+    redis::cmd("DEL")
+        .arg(get_usage_key(pcr))
+        .arg(get_usage_expiry_key(pcr))
+        .arg(get_usage_total_key(pcr))
+        .arg(get_mem_threshold_key(pcr))
+        .arg(get_rate_limit_key(pcr))
+        .arg(get_ops_key(pcr))
+        .arg(get_history_depth_key(pcr))
+        .arg(get_api_token_key(pcr))
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(true)
+}
+
+/// One SCAN page towards the Redis memory used by everything stored for `pcr`,
+/// added to `usage`. `MEMORY USAGE` is only read for the first
+/// `config.memory_usage_sample_keys` keys (all when 0); once that many are read the
+/// scan stops, the key count falls back to the keys the namespace tracks usage for,
+/// and the rest are assumed to average the same. Returns the cursor to continue
+/// from, or `None` once `usage` is final.
+pub async fn memory_usage_step(
+    pcr: &String,
+    cursor: u64,
+    usage: &mut MemoryUsage,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<Option<u64>, StorageError> {
+    // pcrs have a fixed length, so this only matches keys of this namespace
+    let search = pcr.to_owned() + "*";
+    let sample = match config.memory_usage_sample_keys {
+        0 => u64::MAX,
+        sample => sample,
+    };
+    let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(&search)
+        .arg("COUNT")
+        .arg(EVICT_BATCH)
+        .query_async(conn)
+        .await?;
+    usage.keys += keys.len() as u64;
+    let wanted = cmp::min(sample - usage.sampled, keys.len() as u64) as usize;
+    if wanted > 0 {
+        let mut pipe = redis::pipe();
+        for key in &keys[..wanted] {
+            pipe.cmd("MEMORY").arg("USAGE").arg(key);
+        }
+        let sizes: Vec<Option<u64>> = pipe.query_async(conn).await?;
+        for size in sizes {
+            match size {
+                Some(size) => {
+                    usage.bytes += size;
+                    usage.sampled += 1;
+                }
+                // expired after the scan saw it
+                None => usage.keys -= 1,
+            }
+        }
+    }
+    if next != 0 && usage.sampled < sample {
+        return Ok(Some(next));
+    }
+    if next != 0 {
+        let tracked: u64 = conn.hlen(get_usage_key(pcr)).await?;
+        usage.keys = cmp::max(usage.keys, tracked);
+    }
+    if usage.sampled > 0 && usage.sampled < usage.keys {
+        usage.bytes = usage.bytes / usage.sampled * usage.keys;
+    }
+    Ok(None)
+}
+
+/// Every lock held in the namespace with its remaining ttl, for operators to see
+/// what a stuck job is holding.
+pub async fn list_locks(
+    pcr: String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<Vec<LockInfo>, StorageError> {
+    let prefix = get_locked_prefix(&pcr, config);
+    let search = prefix.clone() + "*";
+    let mut locks = Vec::new();
+    let mut pointer: u64 = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(pointer)
+            .arg("MATCH")
+            .arg(&search)
+            .arg("COUNT")
+            .arg(EVICT_BATCH)
+            .query_async(conn)
+            .await?;
+        if !keys.is_empty() {
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.cmd("PTTL").arg(key);
+            }
+            let ttls: Vec<i64> = pipe.query_async(conn).await?;
+            for (key, ttl) in keys.iter().zip(ttls) {
+                // -2 once the lock expired after the scan saw it
+                if ttl == -2 {
+                    continue;
+                }
+                if let Some(key) = key.strip_prefix(&prefix) {
+                    locks.push(LockInfo {
+                        key: String::from(key),
+                        ttl,
+                    });
+                }
+            }
+        }
+        pointer = next;
+        if pointer == 0 {
+            break;
+        }
+    }
+    locks.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(locks)
+}
+
+/// Releases the lock on `key` whoever holds it, returning whether it was held.
+pub async fn force_unlock(
+    pcr: String,
+    key: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<bool, StorageError> {
+    let removed: i64 = conn.del(get_locked_key(&pcr, key, config)).await?;
+    Ok(removed > 0)
+}
+
+/// One step of exporting the namespace: the keys a SCAN from `cursor` returned with
+/// their values, ttls and metadata, the cursor to continue from (0 once done) and
+/// the cost. Offloaded values are exported by CID, for `materialize` to fetch when
+/// the caller wants them inline.
+pub async fn export_batch(
+    pcr: String,
+    cursor: u64,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<ExportEntry>, u64, i64), StorageError> {
+    let prefix = get_namespace_prefix(&pcr, config);
+    let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(prefix.clone() + "*")
+        .arg("COUNT")
+        .arg(EVICT_BATCH)
+        .query_async(conn)
+        .await?;
+    if keys.is_empty() {
+        return Ok((Vec::new(), next, 0));
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in &keys {
+        pipe.cmd("GET").arg(key).cmd("PTTL").arg(key);
+    }
+    let results: Vec<(Option<Vec<u8>>, i64)> = pipe.query_async(conn).await?;
+
+    let mut entries = Vec::with_capacity(keys.len());
+    for (key, (raw, ttl)) in keys.iter().zip(results) {
+        let (key, raw) = match (key.strip_prefix(&prefix), raw) {
+            (Some(key), Some(raw)) => (key, raw),
+            // expired between the scan and the read
+            _ => continue,
+        };
+        let data = decode(&raw)?;
+        let mut entry = ExportEntry {
+            key: String::from(key),
+            value: None,
+            ttl,
+            modified: data.modified,
+            size: data.size.unwrap_or(data.value.len()),
+            version: data.version,
+            cid: None,
+            compressed: data.compressed,
+            digest: data.digest.clone(),
+            backend: data.backend,
+            metadata: data.metadata.clone(),
+        };
+        if data.ipfs {
+            entry.cid = Some(data.value);
+        } else {
+            entry.value = Some(data.value);
+        }
+        entries.push(entry);
+    }
+    let cost = config.operation_b_cost.saturating_mul(keys.len() as i64);
+    Ok((entries, next, cost))
+}
+
+/// Replaces the CID of an exported offloaded value with the value itself.
+pub async fn materialize(
+    entry: &mut ExportEntry,
+    cache: &BlobCache,
+    config: &Config,
+) -> Result<(), StorageError> {
+    let cid = match entry.cid.take() {
+        Some(cid) => cid,
+        None => return Ok(()),
+    };
+    let data = StorageData {
+        value: cid,
+        modified: entry.modified,
+        ipfs: true,
+        compressed: entry.compressed,
+        digest: entry.digest.clone(),
+        size: Some(entry.size),
+        backend: entry.backend,
+        version: entry.version,
+        metadata: HashMap::new(),
+    };
+    entry.value = Some(load_value(data, cache, config).await?);
+    Ok(())
+}
+
+/// Recreates a key from an export in the namespace, returning whether it was
+/// written and the cost. Values exported by CID are pinned again rather than copied,
+/// and keep their modified time while getting a new version. Their size and digest
+/// come from the blob store rather than the entry, and whether the caller may
+/// reference the blob at all is up to the caller to check.
+pub async fn import_entry(
+    pcr: String,
+    entry: ExportEntry,
+    mode: ImportMode,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(bool, i64), StorageError> {
+    if entry.ttl <= 0 && entry.ttl != -1 {
+        return Err(StorageError::BadExpiry);
+    }
+    validate_key(&entry.key, config)?;
+    let usage_key = &entry.key;
+    let key = get_namespaced_key(&pcr, usage_key, config);
+    if mode == ImportMode::Skip {
+        let exists: bool = conn.exists(&key).await?;
+        if exists {
+            return Ok((false, 0));
+        }
+    }
+    let data = match (entry.value, entry.cid) {
+        (Some(value), _) => {
+            check_value_size(&value, config)?;
+            let mut data = to_storage_data(&pcr, &value, StorageMode::Auto, conn, config).await?;
+            data.modified = entry.modified;
+            data.metadata = entry.metadata;
+            data
+        }
+        (None, Some(cid)) => {
+            let backend = entry.backend.unwrap_or_default();
+            let mut data = StorageData {
+                value: cid,
+                modified: entry.modified,
+                ipfs: true,
+                compressed: entry.compressed,
+                digest: None,
+                size: Some(entry.size),
+                backend: entry.backend,
+                version: Some(next_version(conn).await?),
+                metadata: entry.metadata,
+            };
+            let refs: i64 = conn.hincr(IPFS_REFS_KEY, &data.value, 1).await?;
+            // only the first reference here needs the blob store to hold it
+            if refs == 1 {
+                let pinned = blob::pin(backend, &data.value, config)
+                    .instrument(info_span!("blob.pin", backend = ?backend, id = %data.value))
+                    .await;
+                if let Err(e) = pinned {
+                    release_cid(data, cache, conn, config).await?;
+                    return Err(blob_error(e));
+                }
+            }
+            let stored = blob::stat(backend, &data.value, config)
+                .instrument(info_span!("blob.stat", backend = ?backend, id = %data.value))
+                .await;
+            let stored = match stored {
+                Ok(stored) => stored,
+                Err(e) => {
+                    release_cid(data, cache, conn, config).await?;
+                    return Err(blob_error(e));
+                }
+            };
+            // a compressed value is larger than what is stored, but never smaller
+            data.size = Some(cmp::max(entry.size, stored));
+            // fs and s3 ids are the sha256 of the stored bytes, a CID is checked by ipfs
+            if backend != BlobBackend::Ipfs {
+                data.digest = Some(data.value.clone());
+            }
+            data
+        }
+        (None, None) => return Err(StorageError::BadEntry),
+    };
+    let expire_at = match entry.ttl {
+        -1 => -1,
+        ttl => Utc::now().timestamp_millis() + ttl,
+    };
+    let previous = match recorded_usage(&pcr, usage_key, conn).await {
+        Ok(previous) => previous,
+        Err(e) => {
+            release_cid(data, cache, conn, config).await?;
+            return Err(e);
+        }
+    };
+    if let Err(e) = update_usage(
+        &pcr,
+        usage_key,
+        usage_size(usage_key, &data),
+        expire_at,
+        conn,
+        config,
+    )
+    .await
+    {
+        release_cid(data, cache, conn, config).await?;
+        return Err(e);
+    }
+
+    let raw = match encode(&data, config) {
+        Ok(raw) => raw,
+        Err(e) => {
+            abandon_import(&pcr, usage_key, previous, data, cache, conn, config).await;
+            return Err(e);
+        }
+    };
+    let cost = store_cost(
+        (key.len() + raw.len()) as i64,
+        cmp::max(entry.ttl, 0),
+        config,
+    );
+    let mut cmd = redis::cmd("SET");
+    cmd.arg(&key).arg(raw);
+    if entry.ttl > 0 {
+        cmd.arg("PX").arg(entry.ttl);
+    }
+    let written = match mode {
+        ImportMode::Overwrite => cmd
+            .arg("GET")
+            .query_async::<_, Option<Vec<u8>>>(conn)
+            .await
+            .map(|old_value| (true, old_value)),
+        ImportMode::Skip => cmd
+            .arg("NX")
+            .query_async::<_, Option<String>>(conn)
+            .await
+            .map(|written| (written.is_some(), None)),
+    };
+    let (written, old_value) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            abandon_import(&pcr, usage_key, previous, data, cache, conn, config).await;
+            return Err(e.into());
+        }
+    };
+    if let Some(old_value) = old_value {
+        retire_value(&pcr, usage_key, &old_value, cache, conn, config).await?;
+    }
+    if !written {
+        // written by someone else since the check above, so keep theirs and count
+        // it the way its own write did, expiry included
+        release_cid(data, cache, conn, config).await?;
+        let current: Option<Vec<u8>> = conn.get(&key).await?;
+        let theirs = match current {
+            Some(current) => {
+                let ttl: i64 = conn.pttl(&key).await?;
+                let expire_at = (ttl > 0).then(|| Utc::now().timestamp_millis() + ttl);
+                (usage_size(usage_key, &decode(&current)?), expire_at)
+            }
+            None => (0, None),
+        };
+        restore_usage(&pcr, usage_key, theirs, conn).await?;
+        return Ok((false, 0));
+    }
+    Ok((true, cost))
+}
+
+/// Undoes an import that failed after reserving its usage: puts back `previous` and
+/// drops the reference it took to the blob of `data`. errors are logged, like
+/// `abandon_store`'s.
+async fn abandon_import(
+    pcr: &String,
+    key: &String,
+    previous: (i64, Option<i64>),
+    data: StorageData,
+    cache: &BlobCache,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) {
+    if let Err(e) = restore_usage(pcr, key, previous, conn).await {
+        error!(pcr = %pcr, key = %key, "could not restore usage: {}", e);
+    }
+    if let Err(e) = release_cid(data, cache, conn, config).await {
+        error!(pcr = %pcr, key = %key, "could not release unwritten value: {}", e);
+    }
+}
+
+/// The bytes `data` stored under `key` counts towards the namespace's usage.
+fn usage_size(key: &String, data: &StorageData) -> i64 {
+    (key.len() + data.size.unwrap_or(data.value.len()) + metadata_size(&data.metadata)) as i64
+}
+
+fn metadata_size(metadata: &HashMap<String, String>) -> usize {
+    metadata
+        .iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum()
+}
+
+/// Hex encoded sha256 of a sorted key list, used to skip unchanged listings.
+pub fn list_checksum(keys: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for key in keys {
+        hasher.update(key.as_bytes());
+        // keys can't contain NUL without being ambiguous, so use it as a delimiter
+        hasher.update([0u8]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Returns the keys under `prefix` as a nested tree split on the separator, along
+/// with whether the tree was cut short at `config.max_tree_keys` keys.
+pub async fn tree(
+    pcr: String,
+    prefix: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(BTreeMap<String, TreeNode>, bool, i64), StorageError> {
+    let (mut keys, cost) = list(pcr, prefix, true, conn, config).await?;
+    keys.sort();
+    let truncated = keys.len() > config.max_tree_keys;
+    keys.truncate(config.max_tree_keys);
+
+    let mut root = TreeNode::default();
+    for key in &keys {
+        let mut node = &mut root;
+        for part in key
+            .split(config.key_separator.as_str())
+            .filter(|part| !part.is_empty())
+        {
+            node = node.children.entry(String::from(part)).or_default();
+        }
+        if !key.ends_with(config.key_separator.as_str()) {
+            node.is_terminal = true;
+        }
+    }
+    Ok((root.children, truncated, cost))
+}
+
+pub async fn stat(
+    pcr: String,
+    key: &String,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(KeyInfo, i64), StorageError> {
+    let prefixed_key = get_namespaced_key(&pcr, key, config);
+    let (value, ttl): (Option<Vec<u8>>, i64) = redis::pipe()
+        .atomic()
+        .cmd("GET")
+        .arg(&prefixed_key)
+        .cmd("PTTL")
+        .arg(&prefixed_key)
+        .query_async(conn)
+        .await?;
+    let value = value.ok_or(StorageError::NotFound)?;
+
+    let value: StorageData = decode(&value)?;
+    Ok((key_info(key, value, ttl, config), config.operation_b_cost))
+}
+
+/// Stats all of `keys` in one round trip, leaving out the ones that don't exist.
+pub async fn stat_batch(
+    pcr: String,
+    keys: &[String],
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<KeyInfo>, i64), StorageError> {
+    if keys.len() > config.max_batch_keys {
+        return Err(StorageError::TooManyKeys);
+    }
+    if keys.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    let prefix = get_namespace_prefix(&pcr, config);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in keys {
+        let namespaced = prefixed_key(&prefix, key);
+        pipe.cmd("GET")
+            .arg(&namespaced)
+            .cmd("PTTL")
+            .arg(&namespaced);
+    }
+    let results: Vec<(Option<Vec<u8>>, i64)> = pipe.query_async(conn).await?;
+
+    let mut infos = Vec::with_capacity(keys.len());
+    for (key, (value, ttl)) in keys.iter().zip(results) {
+        if let Some(value) = value {
+            let value: StorageData = decode(&value)?;
+            infos.push(key_info(key, value, ttl, config));
+        }
+    }
+    let cost = config.operation_b_cost.saturating_mul(keys.len() as i64);
+    Ok((infos, cost))
+}
+
+fn key_info(key: &String, value: StorageData, ttl: i64, config: &Config) -> KeyInfo {
+    KeyInfo {
+        key: String::from(key),
+        modified: value.modified,
+        // value.value is the CID for ipfs backed values, so prefer the recorded size
+        size: value.size.unwrap_or(value.value.len()),
+        is_terminal: !key.ends_with(config.key_separator.as_str()),
+        ttl,
+        ipfs: value.ipfs,
+        cid: value.ipfs.then_some(value.value),
+        version: value.version,
+        metadata: value.metadata,
+    }
+}
+
+// the key helpers concat their parts to allocate once, where chained `+` can
+// reallocate for every part
+fn get_namespaced_key(pcr: &String, key: &String, config: &Config) -> String {
+    [pcr.as_str(), &config.key_separator, key].concat()
+}
+
+fn get_namespace_prefix(pcr: &String, config: &Config) -> String {
+    [pcr.as_str(), &config.key_separator].concat()
+}
+
+fn get_locked_key(pcr: &String, key: &String, config: &Config) -> String {
+    [pcr.as_str(), ".lock", &config.key_separator, key].concat()
+}
+
+fn get_locked_prefix(pcr: &String, config: &Config) -> String {
+    [pcr.as_str(), ".lock", &config.key_separator].concat()
+}
+
+/// `key` under a prefix from one of the prefix helpers, for loops over many keys to
+/// build the prefix once rather than per key.
+fn prefixed_key(prefix: &str, key: &str) -> String {
+    [prefix, key].concat()
+}
+
+fn get_usage_key(pcr: &String) -> String {
+    String::from(pcr) + ".usage"
+}
+
+fn get_usage_expiry_key(pcr: &String) -> String {
+    String::from(pcr) + ".usage_expiry"
+}
+
+fn get_usage_total_key(pcr: &String) -> String {
+    String::from(pcr) + ".usage_total"
+}
+
+fn get_keyspace_channel(pcr: &String, key: &String, config: &Config) -> String {
+    format!("__keyspace@{}__:", config.redis_db) + &get_namespaced_key(pcr, key, config)
+}
+
+fn get_rate_limit_key(pcr: &String) -> String {
+    String::from(pcr) + ".rate_limit"
+}
+
+fn get_ops_key(pcr: &String) -> String {
+    String::from(pcr) + ".ops"
+}
+
+fn get_api_token_key(pcr: &String) -> String {
+    String::from(pcr) + ".api_token"
+}
+
+fn get_mem_threshold_key(pcr: &String) -> String {
+    String::from(pcr) + ".mem_threshold"
+}
+
+fn get_history_key(pcr: &String, key: &String, config: &Config) -> String {
+    [pcr.as_str(), ".hist", &config.key_separator, key].concat()
+}
+
+fn get_history_prefix(pcr: &String, config: &Config) -> String {
+    [pcr.as_str(), ".hist", &config.key_separator].concat()
+}
+
+fn get_history_depth_key(pcr: &String) -> String {
+    String::from(pcr) + ".history_depth"
+}
+
+fn get_trash_key(pcr: &String, key: &String, config: &Config) -> String {
+    [pcr.as_str(), ".trash", &config.key_separator, key].concat()
+}
+
+/// The usage hash field counting a soft deleted key's bytes. Keys can't hold control
+/// characters, so it never clashes with a live key's field.
+fn get_trash_usage_key(key: &String, config: &Config) -> String {
+    ["\u{0}trash", &config.key_separator, key].concat()
+}
+
+/// The usage hash field counting the bytes of a key's history.
+fn get_history_usage_key(key: &String, config: &Config) -> String {
+    ["\u{0}history", &config.key_separator, key].concat()
+}
+
+fn get_trash_prefix(pcr: &String, config: &Config) -> String {
+    [pcr.as_str(), ".trash", &config.key_separator].concat()
+}
+
+fn get_fence_key(pcr: &String, key: &String, config: &Config) -> String {
+    [pcr.as_str(), ".fence", &config.key_separator, key].concat()
+}
+
+fn get_fence_prefix(pcr: &String, config: &Config) -> String {
+    [pcr.as_str(), ".fence", &config.key_separator].concat()
+}
+
+pub fn get_unique_lock_id() -> io::Result<Vec<u8>> {
+    let file = File::open("/dev/urandom")?;
+    let mut buf = Vec::with_capacity(20);
+    match file.take(20).read_to_end(&mut buf) {
+        Ok(20) => Ok(buf),
+        Ok(_containers) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Can't read enough random bytes",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// Acquires the lock on `key`, returning the lock_id, a fencing token and the cost.
+/// The lock lives for `expiry` milliseconds (default `config.lock_expiry`), capped
+/// at `config.max_lock_expiry`.
+///
+/// The fencing token strictly increases with every acquisition of the same key, so
+/// writers downstream must remember the highest token seen and reject requests
+/// carrying a lower one. This fences off a holder whose lock expired while it was
+/// still working.
+///
+/// Makes one attempt, failing with `LockHeld` while someone else holds the lock;
+/// waiting is left to callers so they can give up the connection meanwhile.
+pub async fn lock(
+    pcr: String,
+    key: &String,
+    expiry: Option<u64>,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<u8>, u64, i64), StorageError> {
+    let expiry = cmp::min(expiry.unwrap_or(config.lock_expiry), config.max_lock_expiry);
+    if expiry == 0 {
+        return Err(StorageError::BadExpiry);
+    }
+    let val = get_unique_lock_id()?;
+    match store_locked(pcr, key, &val, expiry, conn, config).await? {
+        Some(fence) => Ok((val, fence, config.operation_b_cost)),
+        None => Err(StorageError::LockHeld),
+    }
+}
+
+/// Acquires the locks on all of `keys` under a single lock_id, or none of them,
+/// returning the lock_id, the fencing token per key and the cost. Keys are taken in
+/// sorted order in one script, so two callers locking overlapping sets can't each
+/// end up holding part of what the other needs. Like `lock`, makes one attempt.
+pub async fn lock_many(
+    pcr: String,
+    keys: &[String],
+    expiry: Option<u64>,
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(Vec<u8>, BTreeMap<String, u64>, i64), StorageError> {
+    if keys.len() > config.max_batch_keys {
+        return Err(StorageError::TooManyKeys);
+    }
+    let expiry = cmp::min(expiry.unwrap_or(config.lock_expiry), config.max_lock_expiry);
+    if expiry == 0 {
+        return Err(StorageError::BadExpiry);
+    }
+    let keys: BTreeSet<&String> = keys.iter().collect();
+    let cost = config.operation_b_cost.saturating_mul(keys.len() as i64);
+    if keys.is_empty() {
+        return Err(StorageError::NoKeys);
+    }
+    let locked_prefix = get_locked_prefix(&pcr, config);
+    let fence_prefix = get_fence_prefix(&pcr, config);
+    let locked_keys: Vec<String> = keys
+        .iter()
+        .map(|key| prefixed_key(&locked_prefix, key))
+        .collect();
+    let fence_keys: Vec<String> = keys
+        .iter()
+        .map(|key| prefixed_key(&fence_prefix, key))
+        .collect();
+    let val = get_unique_lock_id()?;
+    let mut invocation = redis::Script::new(LOCK_MANY_SCRIPT).prepare_invoke();
+    for key in locked_keys.iter().chain(&fence_keys) {
+        invocation.key(key);
+    }
+    let fences: Vec<u64> = invocation
+        .arg(&val)
+        .arg(expiry)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.lock_many", pcr = %pcr, keys = keys.len()))
+        .await?;
+    if fences.is_empty() {
+        return Err(StorageError::LockHeld);
+    }
+    let fences = keys.into_iter().cloned().zip(fences).collect();
+    Ok((val, fences, cost))
+}
+
+/// Releases each of `keys` still held with `lock_id`, as `unlock` would.
+pub async fn unlock_many(
+    pcr: String,
+    keys: &[String],
+    lock_id: &[u8],
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(BTreeMap<String, UnlockResult>, i64), StorageError> {
+    if keys.len() > config.max_batch_keys {
+        return Err(StorageError::TooManyKeys);
+    }
+    let keys: BTreeSet<&String> = keys.iter().collect();
+    if keys.is_empty() {
+        return Ok((BTreeMap::new(), 0));
+    }
+    let locked_prefix = get_locked_prefix(&pcr, config);
+    let script = redis::Script::new(UNLOCK_MANY_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    for key in &keys {
+        invocation.key(prefixed_key(&locked_prefix, key));
+    }
+    let results: Vec<i64> = invocation
+        .arg(lock_id)
+        .invoke_async(conn)
+        .instrument(info_span!("redis.unlock_many", pcr = %pcr, keys = keys.len()))
+        .await?;
+    let cost = config.operation_b_cost.saturating_mul(keys.len() as i64);
+    let results = keys
+        .into_iter()
+        .cloned()
+        .zip(results)
+        .map(|(key, res)| {
+            let result = match res {
+                1 => UnlockResult::Released,
+                0 => UnlockResult::Expired,
+                _ => UnlockResult::HeldByOther,
+            };
+            (key, result)
+        })
+        .collect();
+    Ok((results, cost))
+}
+
+pub async fn unlock(
+    pcr: String,
+    key: &String,
+    lock_id: &[u8],
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<(UnlockResult, i64), StorageError> {
+    let span = info_span!("redis.unlock", pcr = %pcr, key = span_key(key, config));
+    let key = get_locked_key(&pcr, key, config);
+    let res: i64 = redis::Script::new(UNLOCK_SCRIPT)
+        .key(key)
+        .arg(lock_id)
+        .invoke_async(conn)
+        .instrument(span)
+        .await?;
+    let result = match res {
+        1 => UnlockResult::Released,
+        0 => UnlockResult::Expired,
+        _ => UnlockResult::HeldByOther,
+    };
+    Ok((result, config.operation_b_cost))
+}
+
+pub async fn extend_lock(
+    pcr: String,
+    key: &String,
+    lock_id: &[u8],
+    conn: &mut ConnectionManager,
+    config: &Config,
+) -> Result<i64, StorageError> {
+    let span = info_span!("redis.extend_lock", pcr = %pcr, key = span_key(key, config));
+    let key = get_locked_key(&pcr, key, config);
+    let extended: bool = redis::Script::new(EXTEND_LOCK_SCRIPT)
+        .key(key)
+        .arg(lock_id)
+        .arg(config.lock_expiry)
+        .invoke_async(conn)
+        .instrument(span)
+        .await?;
+    if !extended {
+        return Err(StorageError::LockMismatch);
+    }
+    Ok(config.operation_b_cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::thread::sleep;
+
+    #[tokio::test]
+    async fn test_connection() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        connect(&config).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_store"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_load"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let val = load(
+            String::from("pcr"),
+            &String::from("test_load"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(val.0, String::from("This is a test value"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_expiry() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_store_expiry"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        sleep(Duration::from_millis(1000));
+        load(
+            String::from("pcr"),
+            &String::from("test_store_expiry"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not load");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_keepttl() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_store_keepttl"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        sleep(Duration::from_millis(400));
+        store(
+            String::from("pcr"),
+            &String::from("test_store_keepttl"),
+            -1,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        sleep(Duration::from_millis(400));
+        load(
+            String::from("pcr"),
+            &String::from("test_store_keepttl"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        sleep(Duration::from_millis(400));
+        load(
+            String::from("pcr"),
+            &String::from("test_store_keepttl"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not load");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_keepttl_cost() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let old_value = String::from("short");
+        let new_value = String::from("This is a longer test value");
+        store(
+            String::from("pcr"),
+            &String::from("test_store_keepttl_cost"),
+            10000,
+            &old_value,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let key = get_namespaced_key(
+            &String::from("pcr"),
+            &String::from("test_store_keepttl_cost"),
+            &config,
+        );
+        let old_raw: Vec<u8> = conn.get(&key).await?;
+        let cost = store(
+            String::from("pcr"),
+            &String::from("test_store_keepttl_cost"),
+            -1,
+            &new_value,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let new_raw: Vec<u8> = conn.get(&key).await?;
+        assert!(cost >= config.operation_c_cost);
+        // billed for how much the encoded value grew, for just under 10s of the
+        // original ttl in whole seconds
+        let grown = new_raw.len() as i64 - old_raw.len() as i64;
+        assert_eq!(
+            grown * 9 * config.memory_cost + config.operation_c_cost,
+            cost
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_zeroexpiry() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_store_zeroexpiry"),
+            0,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not store zero expiry");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_not_found() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let err = load(
+            String::from("pcr"),
+            &String::from("test_load_not_found"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not load missing key");
+        assert!(matches!(err, StorageError::NotFound));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_cas_touch_match() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_cas_touch_match"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let res = cas_touch(
+            String::from("pcr"),
+            &String::from("test_cas_touch_match"),
+            &String::from("This is a test value"),
+            &String::from("This is a new value"),
+            5000,
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(res.0);
+        let val = load(
+            String::from("pcr"),
+            &String::from("test_cas_touch_match"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(String::from("This is a new value"), val.0);
+        let ttl: i64 = conn.pttl("pcr/test_cas_touch_match").await?;
+        assert!(ttl > 1000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cas_touch_mismatch() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_cas_touch_mismatch"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let res = cas_touch(
+            String::from("pcr"),
+            &String::from("test_cas_touch_mismatch"),
+            &String::from("This is not the value"),
+            &String::from("This is a new value"),
+            5000,
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(!res.0);
+        let val = load(
+            String::from("pcr"),
+            &String::from("test_cas_touch_mismatch"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(String::from("This is a test value"), val.0);
+        let ttl: i64 = conn.pttl("pcr/test_cas_touch_mismatch").await?;
+        assert!(ttl <= 1000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cas_delete() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let key = String::from("test_cas_delete");
+        store(
+            String::from("pcr"),
+            &key,
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let res = cas_delete(
+            String::from("pcr"),
+            &key,
+            &String::from("This is not the value"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(!res.0);
+        assert!(
+            exists(String::from("pcr"), &key, &mut conn, &config)
+                .await?
+                .0
+        );
+        let res = cas_delete(
+            String::from("pcr"),
+            &key,
+            &String::from("This is a test value"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(res.0);
+        assert!(
+            !exists(String::from("pcr"), &key, &mut conn, &config)
+                .await?
+                .0
+        );
+        Ok(())
+    }
+
+    fn tx_op(op: TxOpKind, key: &str) -> TxOp {
+        TxOp {
+            op,
+            key: String::from(key),
+            value: None,
+            by: None,
+            expiry: 10000,
+            if_version: None,
+            if_exists: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let cache = BlobCache::default();
+        let counter = "test_transaction_counter";
+        let other = "test_transaction_other";
+        let _: () = conn
+            .del(get_namespaced_key(&pcr, &String::from(counter), &config))
+            .await?;
+
+        let ops = vec![
+            tx_op(TxOpKind::Incr, counter),
+            TxOp {
+                by: Some(4),
+                ..tx_op(TxOpKind::Incr, counter)
+            },
+            TxOp {
+                value: Some(String::from("value")),
+                ..tx_op(TxOpKind::Set, other)
+            },
+            tx_op(TxOpKind::Get, other),
+        ];
+        let (results, _) = transaction(pcr.clone(), &ops, &cache, &mut conn, &config).await?;
+        assert_eq!(Some(String::from("1")), results[0].value);
+        assert_eq!(Some(String::from("5")), results[1].value);
+        assert!(results[2].version.is_some());
+        // reads see the writes made earlier in the same transaction
+        assert_eq!(Some(String::from("value")), results[3].value);
+
+        // a failed precondition aborts everything, including the ops before it
+        let ops = vec![
+            tx_op(TxOpKind::Del, other),
+            TxOp {
+                if_exists: Some(false),
+                ..tx_op(TxOpKind::Incr, counter)
+            },
+        ];
+        let res = transaction(pcr.clone(), &ops, &cache, &mut conn, &config).await;
+        assert!(matches!(res, Err(StorageError::PreconditionFailed)));
+        let (value, _, _, _) = load(
+            pcr.clone(),
+            &String::from(other),
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!("value", value);
+
+        let ops = vec![tx_op(TxOpKind::Incr, other)];
+        let res = transaction(pcr.clone(), &ops, &cache, &mut conn, &config).await;
+        assert!(matches!(res, Err(StorageError::NotAnInteger)));
+
+        let ops = vec![tx_op(TxOpKind::Del, counter), tx_op(TxOpKind::Del, other)];
+        transaction(pcr.clone(), &ops, &cache, &mut conn, &config).await?;
+        let (results, _) = transaction(
+            pcr.clone(),
+            &[tx_op(TxOpKind::Get, counter)],
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(None, results[0].value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_if_version() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let key = String::from("test_store_if_version");
+        let cache = BlobCache::default();
+        let first = store_with_options(
+            pcr.clone(),
+            &key,
+            10000,
+            &String::from("first"),
+            &StoreOptions::default(),
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let version = first.version.unwrap();
+        let (value, _, loaded, _) = load(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert_eq!("first", value);
+        assert_eq!(Some(version), loaded);
+
+        let options = StoreOptions {
+            if_version: Some(version),
+            ..StoreOptions::default()
+        };
+        let second = store_with_options(
+            pcr.clone(),
+            &key,
+            10000,
+            &String::from("second"),
+            &options,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(second.version.unwrap() > version);
+        // the version has moved on, so a second write with the old one fails
+        let res = store_with_options(
+            pcr.clone(),
+            &key,
+            10000,
+            &String::from("third"),
+            &options,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await;
+        assert!(matches!(res, Err(StorageError::PreconditionFailed)));
+        let (value, _, _, _) = load(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert_eq!("second", value);
+
+        delete(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        let res = store_with_options(
+            pcr.clone(),
+            &key,
+            10000,
+            &String::from("fourth"),
+            &options,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await;
+        assert!(matches!(res, Err(StorageError::PreconditionFailed)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_expire_at() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let key = String::from("test_store_expire_at");
+        let options = StoreOptions {
+            expire_at_ms: Some(Utc::now().timestamp_millis() + 10000),
+            ..StoreOptions::default()
+        };
+        store_with_options(
+            String::from("pcr"),
+            &key,
+            0,
+            &String::from("This is a test value"),
+            &options,
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let ttl: i64 = conn.pttl("pcr/test_store_expire_at").await?;
+        assert!(ttl > 9000 && ttl <= 10000);
+
+        let past = StoreOptions {
+            expire_at_ms: Some(Utc::now().timestamp_millis() - 1000),
+            ..StoreOptions::default()
+        };
+        let res = store_with_options(
+            String::from("pcr"),
+            &key,
+            0,
+            &String::from("This is a test value"),
+            &past,
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await;
+        assert!(matches!(res, Err(StorageError::BadExpiry)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expiry_range() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.min_expiry_ms = 1000;
+        config.max_expiry_ms = 60000;
+        let mut conn = connect(&config).await?;
+        let key = String::from("test_expiry_range");
+        let value = String::from("This is a test value");
+        for exp in [1000, 60000] {
+            store(String::from("pcr"), &key, exp, &value, &mut conn, &config).await?;
+        }
+        for exp in [999, 60001] {
+            let res = store(String::from("pcr"), &key, exp, &value, &mut conn, &config).await;
+            assert!(matches!(res, Err(StorageError::ExpiryOutOfRange)));
+        }
+        // keeping the existing ttl is always allowed
+        store(String::from("pcr"), &key, -1, &value, &mut conn, &config).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let id: i64 = redis::cmd("CLIENT")
+            .arg("ID")
+            .query_async(&mut conn)
+            .await?;
+        let mut killer = connect(&config).await?;
+        redis::cmd("CLIENT")
+            .arg("KILL")
+            .arg("ID")
+            .arg(id)
+            .query_async::<_, ()>(&mut killer)
+            .await?;
+        // the first command may see the dropped connection, later ones get a new one
+        let _ = ping(&mut conn).await;
+        let mut recovered = false;
+        for _ in 0..10 {
+            if ping(&mut conn).await.is_ok() {
+                recovered = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(recovered);
+        let new_id: i64 = redis::cmd("CLIENT")
+            .arg("ID")
+            .query_async(&mut conn)
+            .await?;
+        assert_ne!(id, new_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_swap() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let key = String::from("test_swap");
+        let _ = delete(
+            String::from("pcr"),
+            &key,
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await;
+        let (previous, _) = swap(
+            String::from("pcr"),
+            &key,
+            10000,
+            &String::from("first"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(None, previous);
+        let (previous, _) = swap(
+            String::from("pcr"),
+            &key,
+            -1,
+            &String::from("second"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(Some(String::from("first")), previous);
+        let val = load(
+            String::from("pcr"),
+            &key,
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(String::from("second"), val.0);
+        // -1 kept the ttl from the first swap
+        let ttl: i64 = conn.pttl("pcr/test_swap").await?;
+        assert!(ttl > 0 && ttl <= 10000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quota() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.max_bytes_per_pcr = 100;
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_quota_pcr");
+        let value = "x".repeat(50);
+        for key in ["test_quota_0", "test_quota_1"] {
+            let _ = delete(
+                pcr.clone(),
+                &String::from(key),
+                &BlobCache::default(),
+                &mut conn,
+                &config,
+            )
+            .await;
+        }
+
+        store(
+            pcr.clone(),
+            &String::from("test_quota_0"),
+            10000,
+            &value,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(62, usage(pcr.clone(), &mut conn, &config).await?);
+        let err = store(
+            pcr.clone(),
+            &String::from("test_quota_1"),
+            10000,
+            &value,
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not go over the quota");
+        assert!(matches!(err, StorageError::QuotaExceeded));
+        assert_eq!(62, usage(pcr.clone(), &mut conn, &config).await?);
+        // a write that doesn't happen gives back the room it reserved
+        let err = store(
+            pcr.clone(),
+            &String::from("test_quota_1"),
+            -1,
+            &String::from("x"),
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not find the key");
+        assert!(matches!(err, StorageError::NotFound));
+        assert_eq!(62, usage(pcr.clone(), &mut conn, &config).await?);
+
+        delete(
+            pcr.clone(),
+            &String::from("test_quota_0"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(0, usage(pcr.clone(), &mut conn, &config).await?);
+        store(
+            pcr.clone(),
+            &String::from("test_quota_1"),
+            10000,
+            &value,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_quota_expiry() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.min_expiry_ms = 0;
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_quota_expiry_pcr");
+        store(
+            pcr.clone(),
+            &String::from("test_quota_expiry"),
+            100,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(usage(pcr.clone(), &mut conn, &config).await? > 0);
+        sleep(Duration::from_millis(200));
+        assert_eq!(0, usage(pcr.clone(), &mut conn, &config).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_force_inline() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let value = "x".repeat(config.mem_threshold + 1);
+        store_with_options(
+            String::from("pcr"),
+            &String::from("test_store_force_inline"),
+            1000,
+            &value,
+            &StoreOptions {
+                storage: StorageMode::Inline,
+                ..StoreOptions::default()
+            },
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let raw: Vec<u8> = conn.get("pcr/test_store_force_inline").await?;
+        let data: StorageData = decode(&raw)?;
+        assert!(!data.ipfs);
+        assert_eq!(value, data.value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_format() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        let data = StorageData {
+            value: String::from("This is a test value"),
+            modified: 1700000000000,
+            ipfs: false,
+            compressed: false,
+            digest: None,
+            size: Some(20),
+            backend: None,
+            version: Some(7),
+            metadata: HashMap::new(),
+        };
+        let json = encode(&data, &config)?;
+        config.storage_format = StorageFormat::Msgpack;
+        let msgpack = encode(&data, &config)?;
+        assert_eq!(MSGPACK_FORMAT, msgpack[0]);
+        assert!(msgpack.len() < json.len());
+        config.storage_format = StorageFormat::Compact;
+        let compact = encode(&data, &config)?;
+        assert_eq!(COMPACT_VERSIONED_FORMAT, compact[0]);
+        assert_eq!(
+            COMPACT_VERSIONED_HEADER_LEN + data.value.len(),
+            compact.len()
+        );
+        assert!(decode(&compact[..12]).is_err());
+        // all decode whatever the configured format
+        for raw in [json, msgpack, compact] {
+            let decoded = decode(&raw)?;
+            assert_eq!(data.value, decoded.value);
+            assert_eq!(data.modified, decoded.modified);
+            assert_eq!(data.size, decoded.size);
+            assert_eq!(data.version, decoded.version);
+        }
+        // values written before versions were recorded still decode
+        let unversioned = StorageData {
+            version: None,
+            ..data
+        };
+        let compact = encode(&unversioned, &config)?;
+        assert_eq!(COMPACT_FORMAT, compact[0]);
+        assert_eq!(COMPACT_HEADER_LEN + unversioned.value.len(), compact.len());
+        assert_eq!(None, decode(&compact)?.version);
+        // metadata doesn't fit the compact header, so those values fall back to msgpack
+        let mut with_metadata = StorageData {
+            version: Some(7),
+            ..unversioned
+        };
+        with_metadata
+            .metadata
+            .insert(String::from("content_type"), String::from("text/plain"));
+        let raw = encode(&with_metadata, &config)?;
+        assert_eq!(MSGPACK_FORMAT, raw[0]);
+        assert_eq!(with_metadata.metadata, decode(&raw)?.metadata);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_release_shared_cid() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let cid = String::from("test_release_shared_cid");
+        let _: () = conn.hset(IPFS_REFS_KEY, &cid, 2).await?;
+        let data = StorageData {
+            value: cid.clone(),
+            modified: 0,
+            ipfs: true,
+            compressed: false,
+            digest: None,
+            size: None,
+            backend: None,
+            version: None,
+            metadata: HashMap::new(),
+        };
+        // another key still refers to the cid, so nothing is unpinned
+        release_cid(data, &BlobCache::default(), &mut conn, &config).await?;
+        let count: i64 = conn.hget(IPFS_REFS_KEY, &cid).await?;
+        assert_eq!(1, count);
+        let _: () = conn.hdel(IPFS_REFS_KEY, &cid).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespace_mem_threshold() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_namespace_mem_threshold");
+        let key = String::from("key");
+        set_mem_threshold(pcr.clone(), Some(config.mem_threshold * 2), &mut conn).await?;
+        // over the global threshold but under the namespace one, so kept inline
+        let value = "x".repeat(config.mem_threshold + 1);
+        store(pcr.clone(), &key, 1000, &value, &mut conn, &config).await?;
+        let (info, _) = stat(pcr.clone(), &key, &mut conn, &config).await?;
+        assert!(!info.ipfs);
+        assert_eq!(
+            config.mem_threshold * 2,
+            mem_threshold(&pcr, &mut conn, &config).await?
+        );
+        set_mem_threshold(pcr.clone(), None, &mut conn).await?;
+        assert_eq!(
+            config.mem_threshold,
+            mem_threshold(&pcr, &mut conn, &config).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_namespaces");
+        store(
+            pcr.clone(),
+            &String::from("key"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        lock(pcr.clone(), &String::from("key"), None, &mut conn, &config).await?;
+        let found = namespaces(&mut conn, &config).await?;
+        assert!(found.contains(&pcr));
+        assert!(!found.iter().any(|namespace| namespace.contains('.')));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evict() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_evict");
+        for key in ["a", "b/c"] {
+            store(
+                pcr.clone(),
+                &String::from(key),
+                10000,
+                &String::from("This is a test value"),
+                &mut conn,
+                &config,
+            )
+            .await?;
+        }
+        lock(pcr.clone(), &String::from("a"), None, &mut conn, &config).await?;
+        let removed = evict(pcr.clone(), &BlobCache::default(), &mut conn, &config).await?;
+        assert_eq!(3, removed);
+        let (keys, _) = list(pcr.clone(), &String::new(), true, &mut conn, &config).await?;
+        assert!(keys.is_empty());
+        assert_eq!(0, usage(pcr.clone(), &mut conn, &config).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_costs() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_add_costs");
+        let _: () = conn.hdel(COST_KEY, &pcr).await?;
+        let costs = HashMap::from([(pcr.clone(), 5)]);
+        add_costs(&costs, &mut conn).await?;
+        add_costs(&costs, &mut conn).await?;
+        let total: i64 = conn.hget(COST_KEY, &pcr).await?;
+        assert_eq!(10, total);
+        let costs = take_costs(&mut conn).await?;
+        assert_eq!(Some(&10), costs.get(&pcr));
+        assert!(take_costs(&mut conn).await?.get(&pcr).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_ops() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_add_ops");
+        let _: () = conn.del(get_ops_key(&pcr)).await?;
+        assert_eq!(0, ops(pcr.clone(), &mut conn).await?.0);
+        let mut pending = HashMap::from([(pcr.clone(), 2)]);
+        add_ops(&mut pending, 60000, &mut conn).await?;
+        assert!(pending.is_empty());
+        let mut pending = HashMap::from([(pcr.clone(), 3)]);
+        add_ops(&mut pending, 60000, &mut conn).await?;
+        let (count, left) = ops(pcr.clone(), &mut conn).await?;
+        assert_eq!(5, count);
+        assert!(left > 0 && left <= 60000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_event() {
+        let message = |channel: &str| {
+            redis::Msg::from_value(&redis::Value::Bulk(vec![
+                redis::Value::Data(b"pmessage".to_vec()),
+                redis::Value::Data(b"__keyspace@0__:pcr/*".to_vec()),
+                redis::Value::Data(channel.as_bytes().to_vec()),
+                redis::Value::Data(b"set".to_vec()),
+            ]))
+            .unwrap()
+        };
+        let mut config: Config = Config::default();
+        let pcr = String::from("pcr");
+        assert_eq!(
+            Some(KeyEvent {
+                key: String::from("a/b"),
+                event: String::from("set"),
+            }),
+            key_event(&pcr, &message("__keyspace@0__:pcr/a/b"), &config)
+        );
+        assert_eq!(
+            None,
+            key_event(&pcr, &message("__keyspace@0__:pcr.lock/a/b"), &config)
+        );
+        // events from another logical database aren't ours
+        config.redis_db = 3;
+        assert_eq!(
+            None,
+            key_event(&pcr, &message("__keyspace@0__:pcr/a/b"), &config)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_too_large() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.max_value_bytes = 10;
+        let mut conn = connect(&config).await?;
+        let err = store(
+            String::from("pcr"),
+            &String::from("test_store_too_large"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await
+        .expect_err("should not store a value over max_value_bytes");
+        assert!(matches!(err, StorageError::ValueTooLarge));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exists() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_exists"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let check = exists(
+            String::from("pcr"),
+            &String::from("test_exists"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(true, check.0);
+        let check = exists(
+            String::from("pcr"),
+            &String::from("not_in_db"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(false, check.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_export");
+        let cache = BlobCache::default();
+        evict(pcr.clone(), &cache, &mut conn, &config).await?;
+        for i in 0..3 {
+            let key = format!("key{}", i);
+            store(
+                pcr.clone(),
+                &key,
+                10000,
+                &format!("value{}", i),
+                &mut conn,
+                &config,
+            )
+            .await?;
+        }
+
+        let mut entries = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (batch, next, _) = export_batch(pcr.clone(), cursor, &mut conn, &config).await?;
+            entries.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(3, entries.len());
+        assert_eq!("key1", entries[1].key);
+        assert_eq!(Some(String::from("value1")), entries[1].value);
+        assert!(entries[1].ttl > 0 && entries[1].ttl <= 10000);
+        assert!(entries[1].version.is_some());
+        evict(pcr.clone(), &cache, &mut conn, &config).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_import");
+        let key = String::from("key");
+        let cache = BlobCache::default();
+        evict(pcr.clone(), &cache, &mut conn, &config).await?;
+        let entry = |value: &str| ExportEntry {
+            key: key.clone(),
+            value: Some(String::from(value)),
+            ttl: 10000,
+            modified: 1700000000000,
+            size: value.len(),
+            version: Some(1),
+            cid: None,
+            compressed: false,
+            digest: None,
+            backend: None,
+            metadata: HashMap::new(),
+        };
 
-    let mut keysmap = HashSet::new();
+        let (written, _) = import_entry(
+            pcr.clone(),
+            entry("first"),
+            ImportMode::Skip,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(written);
+        let (written, _) = import_entry(
+            pcr.clone(),
+            entry("second"),
+            ImportMode::Skip,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(!written);
+        let (value, _, _, _) = load(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert_eq!("first", value);
 
-    for key in &keysfound {
-        let dir = key
-            .strip_prefix(&(prefix.to_owned()))
-            .unwrap_or("")
-            .split('/')
-            .next();
-        match dir {
-            Some(val) => keysmap.insert(String::from(val)),
-            None => false,
-        };
-    }
-    keysfound.clear();
-    for key in keysmap {
-        let found = prefix.to_owned() + &key; //Path::new(prefix).join(key).into_os_string().into_string();
-        keysfound.push(found);
-        // match found {
-        //   Ok(val) => keysfound.push(val),
-        //   _ => (),
-        // };
+        import_entry(
+            pcr.clone(),
+            entry("second"),
+            ImportMode::Overwrite,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let (info, _) = stat(pcr.clone(), &key, &mut conn, &config).await?;
+        assert_eq!(1700000000000, info.modified);
+        let (value, _, _, _) = load(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert_eq!("second", value);
+        assert_eq!(6, usage(pcr.clone(), &mut conn, &config).await?);
+        evict(pcr.clone(), &cache, &mut conn, &config).await?;
+        Ok(())
     }
-    Ok((keysfound, config.operation_a_cost))
-}
 
-pub async fn stat(
-    pcr: String,
-    key: &String,
-    conn: &mut redis::aio::Connection,
-    config: &Config,
-) -> Result<(KeyInfo, i64), Box<dyn Error>> {
-    let prefixed_key = get_namespaced_key(&pcr, key);
-    let value: String = redis::cmd("GET")
-        .arg(prefixed_key)
-        .query_async(conn)
-        .await?;
+    #[tokio::test]
+    async fn test_history() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_history");
+        let key = String::from("key");
+        let cache = BlobCache::default();
+        let _: () = conn.del(get_history_key(&pcr, &key, &config)).await?;
+        set_history_depth(pcr.clone(), Some(2), &mut conn).await?;
 
-    let value: StorageData = serde_json::from_str(&String::from(value))?;
-    Ok((
-        KeyInfo {
-            key: String::from(key),
-            modified: value.modified,
-            size: value.value.len(),
-            is_terminal: !key.ends_with('/'),
-        },
-        config.operation_c_cost,
-    ))
-}
+        let mut versions = Vec::new();
+        for value in ["first", "second", "third", "fourth"] {
+            let result = store_with_options(
+                pcr.clone(),
+                &key,
+                10000,
+                &String::from(value),
+                &StoreOptions::default(),
+                &cache,
+                &mut conn,
+                &config,
+            )
+            .await?;
+            versions.push(result.version);
+        }
+        // only the last two overwritten values are kept, newest first
+        let (entries, _) = history(pcr.clone(), &key, &mut conn, &config).await?;
+        assert_eq!(
+            vec![versions[2], versions[1]],
+            entries.iter().map(|e| e.version).collect::<Vec<_>>()
+        );
+        // the history expires with the key and its two entries count towards usage
+        let ttl: i64 = conn.pttl(get_history_key(&pcr, &key, &config)).await?;
+        assert!(ttl > 0 && ttl <= 10000);
+        let counted: i64 = conn
+            .hget(get_usage_key(&pcr), get_history_usage_key(&key, &config))
+            .await?;
+        assert_eq!(
+            2 * key.len() as i64 + "third".len() as i64 + "second".len() as i64,
+            counted
+        );
 
-fn get_namespaced_key(pcr: &String, key: &String) -> String {
-    get_namespace_prefix(&pcr) + key
-}
+        restore_version(
+            pcr.clone(),
+            &key,
+            versions[1].unwrap(),
+            10000,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let (value, _, _, _) = load(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert_eq!("second", value);
+        let res = restore_version(
+            pcr.clone(),
+            &key,
+            versions[0].unwrap(),
+            10000,
+            &cache,
+            &mut conn,
+            &config,
+        )
+        .await;
+        assert!(matches!(res, Err(StorageError::NotFound)));
 
-fn get_namespace_prefix(pcr: &String) -> String {
-    String::from(pcr) + "/"
-}
+        evict(pcr.clone(), &cache, &mut conn, &config).await?;
+        assert!(history(pcr.clone(), &key, &mut conn, &config)
+            .await?
+            .0
+            .is_empty());
+        Ok(())
+    }
 
-fn get_locked_key(pcr: &String, key: &String) -> String {
-    get_locked_prefix(&pcr) + key
-}
+    #[tokio::test]
+    async fn test_soft_delete() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let key = String::from("test_soft_delete");
+        let cache = BlobCache::default();
+        let value = String::from("This is a test value");
+        store(pcr.clone(), &key, 10000, &value, &mut conn, &config).await?;
+        let before = usage(pcr.clone(), &mut conn, &config).await?;
 
-fn get_locked_prefix(pcr: &String) -> String {
-    String::from(pcr) + ".lock" + "/"
-}
+        soft_delete(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert!(!exists(pcr.clone(), &key, &mut conn, &config).await?.0);
+        // trashed bytes still count, and go no later than the key would have expired
+        assert_eq!(before, usage(pcr.clone(), &mut conn, &config).await?);
+        let purge_at: i64 = conn
+            .zscore(TRASH_INDEX_KEY, get_trash_key(&pcr, &key, &config))
+            .await?;
+        assert!(purge_at <= Utc::now().timestamp_millis() + 10000);
+        restore(pcr.clone(), &key, &mut conn, &config).await?;
+        assert_eq!(before, usage(pcr.clone(), &mut conn, &config).await?);
+        let (loaded, _, _, _) = load(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert_eq!(value, loaded);
+        let ttl: i64 = conn.pttl(get_namespaced_key(&pcr, &key, &config)).await?;
+        assert!(ttl > 0 && ttl <= 10000);
 
-pub fn get_unique_lock_id() -> io::Result<Vec<u8>> {
-    let file = File::open("/dev/urandom")?;
-    let mut buf = Vec::with_capacity(20);
-    match file.take(20).read_to_end(&mut buf) {
-        Ok(20) => Ok(buf),
-        Ok(_containers) => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Can't read enough random bytes",
-        )),
-        Err(e) => Err(e),
+        // nothing is kept once the retention is over
+        config.trash_retention = 0;
+        soft_delete(pcr.clone(), &key, &cache, &mut conn, &config).await?;
+        assert!(purge_trash_batch(&cache, &mut conn, &config).await?.0 >= 1);
+        let res = restore(pcr.clone(), &key, &mut conn, &config).await;
+        assert!(matches!(res, Err(StorageError::NotFound)));
+        assert!(usage(pcr.clone(), &mut conn, &config).await? < before);
+        Ok(())
     }
-}
 
-pub async fn lock(
-    pcr: String,
-    key: &String,
-    conn: &mut redis::aio::Connection,
-    config: &Config,
-) -> Result<(Vec<u8>, i64), Box<dyn Error>> {
-    for _ in 0..config.retry_count {
-        if exists_locked(pcr.clone(), key, conn).await? {
-            sleep(Duration::from_millis(config.retry_delay)); // TODO: change to async
-        } else {
-            let val = get_unique_lock_id()?;
-            if store_locked(pcr, key, &val, conn, config).await? {
-                return Ok((val, config.operation_b_cost));
-            } else {
-                break;
-            }
+    #[test]
+    fn test_validate_key() {
+        let config: Config = Config::default();
+        assert!(validate_key("dir/key", &config).is_ok());
+        assert!(validate_key(&"k".repeat(config.max_key_bytes), &config).is_ok());
+        for key in [
+            String::new(),
+            "k".repeat(config.max_key_bytes + 1),
+            String::from("new\nline"),
+            String::from("nul\0"),
+        ] {
+            assert!(matches!(
+                validate_key(&key, &config),
+                Err(StorageError::BadKey)
+            ));
         }
     }
-    Err("Can't obtain lock".into())
-}
 
-pub async fn unlock(
-    pcr: String,
-    key: &String,
-    lock_id: &[u8],
-    conn: &mut redis::aio::Connection,
-    config: &Config,
-) -> Result<i64, Box<dyn Error>> {
-    if load_locked(pcr.clone(), key, conn).await?.eq(lock_id) {
-        match delete_locked(pcr, key, conn).await {
-            Ok(()) => {
-                return Ok(config.operation_b_cost);
-            }
-            Err(err) => {
-                return Err(err);
-            }
+    #[tokio::test]
+    async fn test_store_bad_key() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let res = store(
+            String::from("pcr"),
+            &String::new(),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await;
+        assert!(matches!(res, Err(StorageError::BadKey)));
+        for key in ["/", "//", "a\nb"] {
+            assert!(matches!(
+                validate_key(key, &config),
+                Err(StorageError::BadKey)
+            ));
         }
-    } else {
-        return Err("lock_id mismatch".into());
+        assert!(validate_key("/a/", &config).is_ok());
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_delete() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_delete"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        delete(
+            String::from("pcr"),
+            &String::from("test_delete"),
+            &BlobCache::default(),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let check = exists(
+            String::from("pcr"),
+            &String::from("test_delete"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(false, check.0);
+        Ok(())
+    }
 
     #[tokio::test]
-    async fn test_connection() -> Result<(), Box<dyn Error>> {
-        connect().await?;
+    async fn test_stat() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_stat"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let info = stat(
+            String::from("pcr"),
+            &String::from("test_stat"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!("test_stat", info.0.key);
+        assert_eq!("This is a test value".len(), info.0.size);
+        assert_eq!(true, info.0.is_terminal);
+        assert!(info.0.ttl > 0 && info.0.ttl <= 1000);
+        assert!(!info.0.ipfs);
+        assert_eq!(None, info.0.cid);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_store() -> Result<(), Box<dyn Error>> {
+    async fn test_stat_batch() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         store(
             String::from("pcr"),
-            &String::from("test_store"),
+            &String::from("test_stat_batch/a"),
             1000,
             &String::from("This is a test value"),
             &mut conn,
             &config,
         )
         .await?;
+        let keys = vec![
+            String::from("test_stat_batch/a"),
+            String::from("test_stat_batch/missing"),
+        ];
+        let (infos, cost) = stat_batch(String::from("pcr"), &keys, &mut conn, &config).await?;
+        assert_eq!(1, infos.len());
+        assert_eq!("test_stat_batch/a", infos[0].key);
+        assert_eq!("This is a test value".len(), infos[0].size);
+        assert_eq!(2 * config.operation_b_cost, cost);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_load() -> Result<(), Box<dyn Error>> {
+    async fn test_exists_batch() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         store(
             String::from("pcr"),
-            &String::from("test_load"),
+            &String::from("test_exists_batch/a"),
             1000,
             &String::from("This is a test value"),
             &mut conn,
             &config,
         )
         .await?;
-        let val = load(
+        let keys = vec![
+            String::from("test_exists_batch/a"),
+            String::from("test_exists_batch/missing"),
+        ];
+        let (found, cost) = exists_batch(String::from("pcr"), &keys, &mut conn, &config).await?;
+        assert_eq!(Some(&true), found.get("test_exists_batch/a"));
+        assert_eq!(Some(&false), found.get("test_exists_batch/missing"));
+        assert_eq!(2 * config.operation_c_cost, cost);
+
+        let too_many = vec![String::from("k"); config.max_batch_keys + 1];
+        assert!(matches!(
+            exists_batch(String::from("pcr"), &too_many, &mut conn, &config).await,
+            Err(StorageError::TooManyKeys)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_stat_ipfs_size() -> Result<(), Box<dyn Error>> {
+        // needs a local ipfs node as well as redis
+        let mut config: Config = Config::default();
+        config.ipfs_url = String::from("http://127.0.0.1:5001/api/v0/");
+        let mut conn = connect(&config).await?;
+        let value = "x".repeat(config.mem_threshold + 1);
+        store(
             String::from("pcr"),
-            &String::from("test_load"),
+            &String::from("test_stat_ipfs_size"),
+            1000,
+            &value,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let info = stat(
+            String::from("pcr"),
+            &String::from("test_stat_ipfs_size"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(info.0.ipfs);
+        assert_eq!(value.len(), info.0.size);
+        delete(
+            String::from("pcr"),
+            &String::from("test_stat_ipfs_size"),
+            &BlobCache::default(),
             &mut conn,
             &config,
         )
         .await?;
-        assert_eq!(val.0, String::from("This is a test value"));
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_store_expiry() -> Result<(), Box<dyn Error>> {
+    async fn test_store_fs_blob() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.blob_store = BlobBackend::Fs;
+        config.blob_dir = std::env::temp_dir()
+            .join("test_store_fs_blob")
+            .to_string_lossy()
+            .into_owned();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let key = String::from("test_store_fs_blob");
+        let value = "x".repeat(config.mem_threshold + 1);
+        store(pcr.clone(), &key, 1000, &value, &mut conn, &config).await?;
+        let (info, _) = stat(pcr.clone(), &key, &mut conn, &config).await?;
+        assert!(info.ipfs);
+        assert_eq!(
+            value,
+            load(pcr.clone(), &key, &BlobCache::default(), &mut conn, &config)
+                .await?
+                .0
+        );
+        delete(pcr.clone(), &key, &BlobCache::default(), &mut conn, &config).await?;
+        let cid = info.cid.unwrap_or_default();
+        assert!(!std::path::Path::new(&config.blob_dir)
+            .join(&cid[..2])
+            .join(&cid)
+            .exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stat_skips_blob_store() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.blob_store = BlobBackend::Fs;
+        config.blob_dir = std::env::temp_dir()
+            .join("test_stat_skips_blob_store")
+            .to_string_lossy()
+            .into_owned();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let key = String::from("test_stat_skips_blob_store");
+        let value = "x".repeat(config.mem_threshold + 1);
+        store(pcr.clone(), &key, 1000, &value, &mut conn, &config).await?;
+        // with the blob out of reach, stat still answers from the stored metadata
+        config.blob_dir = std::env::temp_dir()
+            .join("test_stat_skips_blob_store_missing")
+            .to_string_lossy()
+            .into_owned();
+        let (info, _) = stat(pcr.clone(), &key, &mut conn, &config).await?;
+        assert!(info.ipfs);
+        assert_eq!(value.len(), info.size);
+        assert!(
+            load(pcr.clone(), &key, &BlobCache::default(), &mut conn, &config)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_operation_cost_tiers() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let key = String::from("test_operation_cost_tiers");
         store(
-            String::from("pcr"),
-            &String::from("test_store_expiry"),
+            pcr.clone(),
+            &key,
             1000,
             &String::from("This is a test value"),
             &mut conn,
             &config,
         )
         .await?;
-        sleep(Duration::from_millis(1000));
-        load(
+
+        let load_result =
+            load(pcr.clone(), &key, &BlobCache::default(), &mut conn, &config).await?;
+        assert_eq!(config.operation_b_cost, load_result.1);
+        let stat_result = stat(pcr.clone(), &key, &mut conn, &config).await?;
+        assert_eq!(config.operation_b_cost, stat_result.1);
+        let exists_result = exists(pcr.clone(), &key, &mut conn, &config).await?;
+        assert_eq!(config.operation_c_cost, exists_result.1);
+        let list_result = list(pcr.clone(), &key, true, &mut conn, &config).await?;
+        assert_eq!(config.operation_a_cost, list_result.1);
+        let lock_result = lock(pcr.clone(), &key, None, &mut conn, &config).await?;
+        assert_eq!(config.operation_b_cost, lock_result.2);
+        let unlock_result = unlock(pcr.clone(), &key, &lock_result.0, &mut conn, &config).await?;
+        assert_eq!(config.operation_b_cost, unlock_result.1);
+        let delete_result =
+            delete(pcr.clone(), &key, &BlobCache::default(), &mut conn, &config).await?;
+        assert_eq!(config.operation_c_cost, delete_result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_cost_saturates() {
+        let config: Config = Config::default();
+        assert_eq!(i64::MAX, store_cost(i64::MAX / 2, i64::MAX, &config));
+        assert_eq!(i64::MAX, store_cost(1 << 40, 1 << 40, &config));
+        assert_eq!(
+            20 * 5 * config.memory_cost + config.operation_c_cost,
+            store_cost(20, 5000, &config)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+
+        lock(
             String::from("pcr"),
-            &String::from("test_store_expiry"),
+            &String::from("test_lock"),
+            None,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        lock(
+            String::from("pcr"),
+            &String::from("test_lock"),
+            None,
             &mut conn,
             &config,
         )
         .await
-        .expect_err("should not load");
+        .expect_err("lock not obtained");
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_store_keepttl() -> Result<(), Box<dyn Error>> {
+    async fn test_lock_custom_expiry() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
-        store(
+        let mut conn = connect(&config).await?;
+
+        lock(
             String::from("pcr"),
-            &String::from("test_store_keepttl"),
-            1000,
-            &String::from("This is a test value"),
+            &String::from("test_lock_custom_expiry"),
+            Some(200),
             &mut conn,
             &config,
         )
         .await?;
-        sleep(Duration::from_millis(400));
-        store(
+        sleep(Duration::from_millis(200));
+        lock(
             String::from("pcr"),
-            &String::from("test_store_keepttl"),
-            -1,
-            &String::from("This is a test value"),
+            &String::from("test_lock_custom_expiry"),
+            Some(200),
             &mut conn,
             &config,
         )
         .await?;
-        sleep(Duration::from_millis(400));
-        load(
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lock_fence() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+
+        let first = lock(
             String::from("pcr"),
-            &String::from("test_store_keepttl"),
+            &String::from("test_lock_fence"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        sleep(Duration::from_millis(400));
-        load(
+        unlock(
             String::from("pcr"),
-            &String::from("test_store_keepttl"),
+            &String::from("test_lock_fence"),
+            &first.0,
             &mut conn,
             &config,
         )
-        .await
-        .expect_err("should not load");
+        .await?;
+        let second = lock(
+            String::from("pcr"),
+            &String::from("test_lock_fence"),
+            None,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(second.1 > first.1);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_store_zeroexpiry() -> Result<(), Box<dyn Error>> {
+    async fn test_lock_expiry() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
-        store(
+        let mut conn = connect(&config).await?;
+
+        lock(
             String::from("pcr"),
-            &String::from("test_store_zeroexpiry"),
-            0,
-            &String::from("This is a test value"),
+            &String::from("test_lock_expiry"),
+            None,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        sleep(Duration::from_millis(config.lock_expiry));
+        lock(
+            String::from("pcr"),
+            &String::from("test_lock_expiry"),
+            None,
             &mut conn,
             &config,
         )
-        .await
-        .expect_err("should not store zero expiry");
+        .await?;
         Ok(())
     }
+
     #[tokio::test]
-    async fn test_exists() -> Result<(), Box<dyn Error>> {
+    async fn test_unlock() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
-        store(
+        let mut conn = connect(&config).await?;
+
+        let lock_id = lock(
             String::from("pcr"),
-            &String::from("test_exists"),
-            1000,
-            &String::from("This is a test value"),
+            &String::from("test_unlock"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        let check = exists(
+        unlock(
             String::from("pcr"),
-            &String::from("test_exists"),
+            &String::from("test_unlock"),
+            &lock_id.0,
             &mut conn,
             &config,
         )
         .await?;
-        assert_eq!(true, check.0);
-        let check = exists(
+        lock(
             String::from("pcr"),
-            &String::from("not_in_db"),
+            &String::from("test_unlock"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        assert_eq!(false, check.0);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_delete() -> Result<(), Box<dyn Error>> {
+    async fn test_unlock_results() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
-        store(
+        let mut conn = connect(&config).await?;
+
+        let lock_id = lock(
             String::from("pcr"),
-            &String::from("test_delete"),
-            1000,
-            &String::from("This is a test value"),
+            &String::from("test_unlock_results"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        delete(
+        let released = unlock(
             String::from("pcr"),
-            &String::from("test_delete"),
+            &String::from("test_unlock_results"),
+            &lock_id.0,
             &mut conn,
             &config,
         )
         .await?;
-        let check = exists(
+        assert_eq!(UnlockResult::Released, released.0);
+        let expired = unlock(
             String::from("pcr"),
-            &String::from("test_delete"),
+            &String::from("test_unlock_results"),
+            &lock_id.0,
             &mut conn,
             &config,
         )
         .await?;
-        assert_eq!(false, check.0);
-        Ok(())
-    }
+        assert_eq!(UnlockResult::Expired, expired.0);
 
-    #[tokio::test]
-    async fn test_stat() -> Result<(), Box<dyn Error>> {
-        let config: Config = Config::default();
-        let mut conn = connect().await?;
-        store(
+        lock(
             String::from("pcr"),
-            &String::from("test_stat"),
-            1000,
-            &String::from("This is a test value"),
+            &String::from("test_unlock_results"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        let info = stat(
+        let held = unlock(
             String::from("pcr"),
-            &String::from("test_stat"),
+            &String::from("test_unlock_results"),
+            &lock_id.0,
             &mut conn,
             &config,
         )
         .await?;
-        assert_eq!("test_stat", info.0.key);
-        assert_eq!("This is a test value".len(), info.0.size);
-        assert_eq!(true, info.0.is_terminal);
+        assert_eq!(UnlockResult::HeldByOther, held.0);
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_lock() -> Result<(), Box<dyn Error>> {
+    async fn test_lock_many() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("pcr");
+        let a = String::from("test_lock_many_a");
+        let b = String::from("test_lock_many_b");
+        let c = String::from("test_lock_many_c");
 
-        lock(
-            String::from("pcr"),
-            &String::from("test_lock"),
+        let (lock_id, fences, _) = lock_many(
+            pcr.clone(),
+            &[b.clone(), a.clone(), b.clone()],
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        lock(
-            String::from("pcr"),
-            &String::from("test_lock"),
+        assert_eq!(vec![&a, &b], fences.keys().collect::<Vec<_>>());
+
+        // b is held, so c isn't taken either
+        let res = lock_many(
+            pcr.clone(),
+            &[b.clone(), c.clone()],
+            None,
             &mut conn,
             &config,
         )
-        .await
-        .expect_err("lock not obtained");
+        .await;
+        assert!(matches!(res, Err(StorageError::LockHeld)));
+        assert!(!exists_locked(pcr.clone(), &c, &mut conn, &config).await?);
+        let res = lock_many(pcr.clone(), &[], None, &mut conn, &config).await;
+        assert!(matches!(res, Err(StorageError::NoKeys)));
+
+        let (results, _) = unlock_many(
+            pcr.clone(),
+            &[a.clone(), b.clone(), c.clone()],
+            &lock_id,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(UnlockResult::Released, results[&a]);
+        assert_eq!(UnlockResult::Released, results[&b]);
+        assert_eq!(UnlockResult::Expired, results[&c]);
         Ok(())
     }
 
+    async fn evict(
+        pcr: String,
+        cache: &BlobCache,
+        conn: &mut ConnectionManager,
+        config: &Config,
+    ) -> Result<i64, StorageError> {
+        let mut eviction = Eviction::default();
+        while !evict_step(&pcr, &mut eviction, cache, conn, config).await? {}
+        Ok(eviction.removed)
+    }
+
+    async fn namespaces(
+        conn: &mut ConnectionManager,
+        config: &Config,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut namespaces = BTreeSet::new();
+        let mut cursor = Some(0);
+        while let Some(next) = cursor {
+            cursor = namespaces_step(next, &mut namespaces, conn, config).await?;
+        }
+        Ok(namespaces.into_iter().collect())
+    }
+
+    async fn memory_usage(
+        pcr: String,
+        conn: &mut ConnectionManager,
+        config: &Config,
+    ) -> Result<MemoryUsage, StorageError> {
+        let mut usage = MemoryUsage::default();
+        let mut cursor = Some(0);
+        while let Some(next) = cursor {
+            cursor = memory_usage_step(&pcr, next, &mut usage, conn, config).await?;
+        }
+        Ok(usage)
+    }
+
     #[tokio::test]
-    async fn test_lock_expiry() -> Result<(), Box<dyn Error>> {
+    async fn test_memory_usage() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_memory_usage");
+        let cache = BlobCache::default();
+        evict(pcr.clone(), &cache, &mut conn, &config).await?;
+        assert_eq!(
+            MemoryUsage::default(),
+            memory_usage(pcr.clone(), &mut conn, &config).await?
+        );
+        for key in ["a", "b", "c", "d"] {
+            store(
+                pcr.clone(),
+                &String::from(key),
+                10000,
+                &String::from("This is a test value"),
+                &mut conn,
+                &config,
+            )
+            .await?;
+        }
+        lock(pcr.clone(), &String::from("a"), None, &mut conn, &config).await?;
+        let full = memory_usage(pcr.clone(), &mut conn, &config).await?;
+        // the values, the lock and its fence, and the usage bookkeeping
+        assert!(full.keys >= 6);
+        assert_eq!(full.keys, full.sampled);
+        assert!(full.bytes > 0);
+
+        config.memory_usage_sample_keys = 2;
+        let sampled = memory_usage(pcr.clone(), &mut conn, &config).await?;
+        // the scan may stop early, but the four values are tracked either way
+        assert!(sampled.keys >= 4 && sampled.keys <= full.keys);
+        assert_eq!(2, sampled.sampled);
+        assert!(sampled.bytes > 0);
+        evict(pcr, &cache, &mut conn, &config).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_locks() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_list_locks");
+        let key = String::from("key");
+        lock(pcr.clone(), &key, Some(10000), &mut conn, &config).await?;
 
-        lock(
+        let locks = list_locks(pcr.clone(), &mut conn, &config).await?;
+        assert_eq!(1, locks.len());
+        assert_eq!(key, locks[0].key);
+        assert!(locks[0].ttl > 0 && locks[0].ttl <= 10000);
+
+        assert!(force_unlock(pcr.clone(), &key, &mut conn, &config).await?);
+        assert!(!force_unlock(pcr.clone(), &key, &mut conn, &config).await?);
+        assert!(list_locks(pcr.clone(), &mut conn, &config)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlock_stale_lock_id() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.lock_expiry = 200;
+        let mut conn = connect(&config).await?;
+
+        let stale = lock(
             String::from("pcr"),
-            &String::from("test_lock_expiry"),
+            &String::from("test_unlock_stale_lock_id"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
+        // the lock expires before the holder gets around to unlocking it
         sleep(Duration::from_millis(config.lock_expiry));
         lock(
             String::from("pcr"),
-            &String::from("test_lock_expiry"),
+            &String::from("test_unlock_stale_lock_id"),
+            None,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let res = unlock(
+            String::from("pcr"),
+            &String::from("test_unlock_stale_lock_id"),
+            &stale.0,
             &mut conn,
             &config,
         )
         .await?;
+        assert_eq!(UnlockResult::HeldByOther, res.0);
+        assert!(
+            exists_locked(
+                String::from("pcr"),
+                &String::from("test_unlock_stale_lock_id"),
+                &mut conn,
+                &config,
+            )
+            .await?
+        );
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_unlock() -> Result<(), Box<dyn Error>> {
-        let config: Config = Config::default();
-        let mut conn = connect().await?;
+    async fn test_extend_lock() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        config.lock_expiry = 500;
+        let mut conn = connect(&config).await?;
 
         let lock_id = lock(
             String::from("pcr"),
-            &String::from("test_unlock"),
+            &String::from("test_extend_lock"),
+            None,
             &mut conn,
             &config,
         )
         .await?;
-        unlock(
+        sleep(Duration::from_millis(300));
+        extend_lock(
             String::from("pcr"),
-            &String::from("test_unlock"),
+            &String::from("test_extend_lock"),
             &lock_id.0,
             &mut conn,
             &config,
         )
         .await?;
-        lock(
+        sleep(Duration::from_millis(300));
+        // still held past the original expiry
+        assert!(
+            exists_locked(
+                String::from("pcr"),
+                &String::from("test_extend_lock"),
+                &mut conn,
+                &config,
+            )
+            .await?
+        );
+        let err = extend_lock(
             String::from("pcr"),
-            &String::from("test_unlock"),
+            &String::from("test_extend_lock"),
+            &[0u8; 20],
             &mut conn,
             &config,
         )
-        .await?;
+        .await
+        .expect_err("should not extend a lock held by someone else");
+        assert!(matches!(err, StorageError::LockMismatch));
         Ok(())
     }
 
     #[tokio::test]
     async fn test_list_recursive() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         store(
             String::from("pcr"),
             &String::from("test_list_recursive_0"),
@@ -708,10 +5503,274 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_list_matching() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        for key in [
+            "test_list_matching/a/2024-01",
+            "test_list_matching/b/2024-02",
+            "test_list_matching/b/2023-12",
+        ] {
+            store(
+                String::from("pcr"),
+                &String::from(key),
+                1000,
+                &String::from("This is a test value"),
+                &mut conn,
+                &config,
+            )
+            .await?;
+        }
+        let (keys, _) = list_matching(
+            String::from("pcr"),
+            "test_list_matching/*/2024-*",
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(
+            vec![
+                "test_list_matching/a/2024-01",
+                "test_list_matching/b/2024-02"
+            ],
+            keys
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_pattern() {
+        assert!(check_pattern("logs/*/2024-*").is_ok());
+        assert!(check_pattern("a[bc]\\*").is_ok());
+        assert!(check_pattern("").is_err());
+        assert!(check_pattern("a[bc").is_err());
+        assert!(check_pattern("a\\").is_err());
+    }
+
+    #[test]
+    fn test_span_key() {
+        let mut config: Config = Config::default();
+        assert_eq!("<redacted>", span_key("a/b", &config));
+        config.trace_redact_keys = false;
+        assert_eq!("a/b", span_key("a/b", &config));
+    }
+
+    #[test]
+    fn test_key_helpers() {
+        let mut config: Config = Config::default();
+        let pcr = String::from("pcr");
+        let key = String::from("a/b");
+        assert_eq!("pcr/a/b", get_namespaced_key(&pcr, &key, &config));
+        assert_eq!("pcr.lock/a/b", get_locked_key(&pcr, &key, &config));
+        config.key_separator = String::from("::");
+        for (prefix, full) in [
+            (
+                get_namespace_prefix(&pcr, &config),
+                get_namespaced_key(&pcr, &key, &config),
+            ),
+            (
+                get_locked_prefix(&pcr, &config),
+                get_locked_key(&pcr, &key, &config),
+            ),
+            (
+                get_fence_prefix(&pcr, &config),
+                get_fence_key(&pcr, &key, &config),
+            ),
+            (
+                get_trash_prefix(&pcr, &config),
+                get_trash_key(&pcr, &key, &config),
+            ),
+            (
+                get_history_prefix(&pcr, &config),
+                get_history_key(&pcr, &key, &config),
+            ),
+        ] {
+            assert!(prefix.ends_with("::"));
+            assert_eq!(full, prefixed_key(&prefix, &key));
+        }
+    }
+
+    #[test]
+    fn test_collapse_children() {
+        let keys: Vec<String> = ["a/b/c", "ab/d", "a/e"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        assert_eq!(vec!["a/", "ab/"], collapse_children("a", &keys, "/"));
+        assert_eq!(vec!["a/b/", "a/e"], collapse_children("a/", &keys, "/"));
+        assert_eq!(vec!["a/b/c"], collapse_children("a/b/", &keys, "/"));
+        assert_eq!(vec!["ab/"], collapse_children("ab", &keys, "/"));
+        let keys: Vec<String> = ["a::b::c", "a::e"].iter().map(|k| k.to_string()).collect();
+        assert_eq!(
+            vec!["a::b::", "a::e"],
+            collapse_children("a::", &keys, "::")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_collapses_directories() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        for key in [
+            "test_list_collapse/a/b/c",
+            "test_list_collapse/ab/d",
+            "test_list_collapse/a/e",
+        ] {
+            store(
+                String::from("pcr"),
+                &String::from(key),
+                1000,
+                &String::from("This is a test value"),
+                &mut conn,
+                &config,
+            )
+            .await?;
+        }
+        let (keys, _) = list(
+            String::from("pcr"),
+            &String::from("test_list_collapse/a"),
+            false,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(
+            vec!["test_list_collapse/a/", "test_list_collapse/ab/"],
+            keys
+        );
+        let (keys, _) = list(
+            String::from("pcr"),
+            &String::from("test_list_collapse/a/"),
+            false,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(
+            vec!["test_list_collapse/a/b/", "test_list_collapse/a/e"],
+            keys
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_sorted_checksum() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        for key in [
+            "test_list_sorted/b",
+            "test_list_sorted/a",
+            "test_list_sorted/c",
+        ] {
+            store(
+                String::from("pcr"),
+                &String::from(key),
+                1000,
+                &String::from("This is a test value"),
+                &mut conn,
+                &config,
+            )
+            .await?;
+        }
+        let first = list(
+            String::from("pcr"),
+            &String::from("test_list_sorted/"),
+            true,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(
+            vec![
+                "test_list_sorted/a",
+                "test_list_sorted/b",
+                "test_list_sorted/c"
+            ],
+            first.0
+        );
+        let second = list(
+            String::from("pcr"),
+            &String::from("test_list_sorted/"),
+            true,
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert_eq!(list_checksum(&first.0), list_checksum(&second.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_scan_batches() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        // a few keys per SCAN, so the listing takes many pipelined round trips
+        config.scan_count = 2;
+        let mut conn = connect(&config).await?;
+        let pcr = String::from("test_list_scan_batches");
+        let mut expected = Vec::new();
+        for i in 0..50 {
+            let key = format!("k{:02}", i);
+            store(
+                pcr.clone(),
+                &key,
+                10000,
+                &String::from("value"),
+                &mut conn,
+                &config,
+            )
+            .await?;
+            expected.push(key);
+        }
+        let (keys, _) = list(pcr.clone(), &String::from("*"), true, &mut conn, &config).await?;
+        assert_eq!(expected, keys);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tree() -> Result<(), Box<dyn Error>> {
+        let config: Config = Config::default();
+        let mut conn = connect(&config).await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_tree/a/b/c"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        store(
+            String::from("pcr"),
+            &String::from("test_tree/a/d"),
+            1000,
+            &String::from("This is a test value"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        let (tree, truncated, _) = tree(
+            String::from("pcr"),
+            &String::from("test_tree/"),
+            &mut conn,
+            &config,
+        )
+        .await?;
+        assert!(!truncated);
+        let a = &tree["test_tree"].children["a"];
+        assert!(!a.is_terminal);
+        assert_eq!(2, a.children.len());
+        assert!(!a.children["b"].is_terminal);
+        assert!(a.children["b"].children["c"].is_terminal);
+        assert!(a.children["b"].children["c"].children.is_empty());
+        assert!(a.children["d"].is_terminal);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_store_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
 
         use std::time::Instant;
         let now = Instant::now();
@@ -738,7 +5797,7 @@ mod tests {
     #[tokio::test]
     async fn test_load_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         let mut i = 0;
         store(
             String::from("test_load_benchmark_namespace"),
@@ -770,6 +5829,7 @@ mod tests {
             let _val = load(
                 String::from("test_load_benchmark_namespace"),
                 &String::from("test_load_benchmark_key"),
+                &BlobCache::default(),
                 &mut conn,
                 &config,
             )
@@ -785,7 +5845,7 @@ mod tests {
     #[tokio::test]
     async fn test_exists_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         let mut i = 0;
         store(
             String::from("test_exist_benchmark_namespace"),
@@ -832,7 +5892,7 @@ mod tests {
     #[tokio::test]
     async fn test_list_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         let mut i = 0;
         store(
             String::from("test_list_benchmark_namespace"),
@@ -880,7 +5940,7 @@ mod tests {
     #[tokio::test]
     async fn test_stat_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         store(
             String::from("test_stat_benchmark_namespace"),
             &(String::from("test_stat_benchmark_key")),
@@ -914,7 +5974,7 @@ mod tests {
     #[tokio::test]
     async fn test_delete_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         let mut i = 0;
         store(
             String::from("test_delete_benchmark_namespace"),
@@ -946,6 +6006,7 @@ mod tests {
             let _val = delete(
                 String::from("test_delete_benchmark_namespace"),
                 &(String::from("test_delete_benchmark_key") + &i.to_string()),
+                &BlobCache::default(),
                 &mut conn,
                 &config,
             )
@@ -961,7 +6022,7 @@ mod tests {
     #[tokio::test]
     async fn test_lock_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         let mut i = 0;
         store(
             String::from("test_lock_benchmark_namespace"),
@@ -993,6 +6054,7 @@ mod tests {
             let _val = lock(
                 String::from("test_lock_benchmark_namespace"),
                 &(String::from("test_lock_benchmark_key") + &i.to_string()),
+                None,
                 &mut conn,
                 &config,
             )
@@ -1008,7 +6070,7 @@ mod tests {
     #[tokio::test]
     async fn test_unlock_benchmark() -> Result<(), Box<dyn Error>> {
         let config: Config = Config::default();
-        let mut conn = connect().await?;
+        let mut conn = connect(&config).await?;
         let mut i = 0;
         let mut lock_id: Vec<Vec<u8>>;
         lock_id = Vec::new();
@@ -1035,6 +6097,7 @@ mod tests {
                 lock(
                     String::from("test_unlock_benchmark_namespace"),
                     &(String::from("test_unlock_benchmark_key") + &i.to_string()),
+                    None,
                     &mut conn,
                     &config,
                 )
@@ -1064,4 +6127,91 @@ mod tests {
         println!("test_unlock_benchmark {} calls Elapsed: {:.2?}", i, elapsed);
         Ok(())
     }
+
+    #[test]
+    fn test_key_helpers_benchmark() {
+        let config: Config = Config::default();
+        let pcr = String::from("test_key_helpers_benchmark_namespace");
+        let keys: Vec<String> = (0..100000)
+            .map(|i| String::from("test_key_helpers_benchmark_key") + &i.to_string())
+            .collect();
+
+        use std::time::Instant;
+        // the chained `+` the helpers used to build keys with
+        let now = Instant::now();
+        for key in &keys {
+            let _val = String::from(&pcr) + &config.key_separator + key;
+        }
+        let elapsed = now.elapsed();
+        println!(
+            "test_key_helpers_benchmark chained {} keys Elapsed: {:.2?}",
+            keys.len(),
+            elapsed
+        );
+
+        let now = Instant::now();
+        for key in &keys {
+            let _val = get_namespaced_key(&pcr, key, &config);
+        }
+        let elapsed = now.elapsed();
+        println!(
+            "test_key_helpers_benchmark concat {} keys Elapsed: {:.2?}",
+            keys.len(),
+            elapsed
+        );
+
+        let now = Instant::now();
+        let prefix = get_namespace_prefix(&pcr, &config);
+        for key in &keys {
+            let _val = prefixed_key(&prefix, key);
+        }
+        let elapsed = now.elapsed();
+        println!(
+            "test_key_helpers_benchmark prefixed {} keys Elapsed: {:.2?}",
+            keys.len(),
+            elapsed
+        );
+    }
+
+    #[test]
+    fn test_storage_format_benchmark() -> Result<(), Box<dyn Error>> {
+        let mut config: Config = Config::default();
+        let data = StorageData {
+            ipfs: false,
+            value: String::from("This is a test value"),
+            modified: Utc::now().timestamp_millis(),
+            compressed: false,
+            digest: None,
+            size: Some(20),
+            backend: None,
+            version: Some(1),
+            metadata: HashMap::new(),
+        };
+
+        use std::time::Instant;
+        for format in [
+            StorageFormat::Json,
+            StorageFormat::Msgpack,
+            StorageFormat::Compact,
+        ] {
+            config.storage_format = format;
+            let now = Instant::now();
+            let mut i = 0;
+            let mut raw = Vec::new();
+            while i < 100000 {
+                raw = encode(&data, &config)?;
+                let _val = decode(&raw)?;
+                i = i + 1;
+            }
+            let elapsed = now.elapsed();
+            println!(
+                "test_storage_format_benchmark {:?} {} bytes {} calls Elapsed: {:.2?}",
+                format,
+                raw.len(),
+                i,
+                elapsed
+            );
+        }
+        Ok(())
+    }
 }
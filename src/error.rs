@@ -0,0 +1,166 @@
+use derive_more::Display;
+use std::error::Error;
+use std::io;
+
+#[derive(Debug, Display)]
+pub enum StorageError {
+    #[display(fmt = "key not found")]
+    NotFound,
+    #[display(fmt = "can't obtain lock")]
+    LockHeld,
+    #[display(fmt = "lock_id mismatch")]
+    LockMismatch,
+    #[display(fmt = "expiry cannot be zero")]
+    BadExpiry,
+    #[display(fmt = "expiry outside the allowed range")]
+    ExpiryOutOfRange,
+    #[display(fmt = "storage quota exceeded")]
+    QuotaExceeded,
+    #[display(fmt = "value too large")]
+    ValueTooLarge,
+    #[display(fmt = "key is empty, too long or contains control characters")]
+    BadKey,
+    #[display(fmt = "too many keys in one request")]
+    TooManyKeys,
+    #[display(fmt = "no keys in the request")]
+    NoKeys,
+    #[display(fmt = "invalid list pattern")]
+    BadPattern,
+    #[display(fmt = "too many requests")]
+    RateLimited,
+    #[display(fmt = "operation quota exceeded for this window")]
+    OpQuotaExceeded,
+    #[display(fmt = "stored value is not at the expected version")]
+    PreconditionFailed,
+    #[display(fmt = "keys kept changing while the transaction ran")]
+    Conflict,
+    #[display(fmt = "key already exists")]
+    AlreadyExists,
+    #[display(fmt = "import entry has neither a value nor a cid")]
+    BadEntry,
+    #[display(fmt = "invalid transaction op")]
+    BadTxOp,
+    #[display(fmt = "value is not an integer")]
+    NotAnInteger,
+    #[display(fmt = "blob store error: {}", _0)]
+    Blob(String),
+    #[display(fmt = "blob content failed integrity check")]
+    Integrity,
+    #[display(fmt = "redis error: {}", _0)]
+    Redis(redis::RedisError),
+    #[display(fmt = "serialization error: {}", _0)]
+    Serde(serde_json::Error),
+    #[display(fmt = "msgpack encode error: {}", _0)]
+    Encode(rmp_serde::encode::Error),
+    #[display(fmt = "msgpack decode error: {}", _0)]
+    Decode(rmp_serde::decode::Error),
+    #[display(fmt = "io error: {}", _0)]
+    Io(io::Error),
+}
+
+impl StorageError {
+    /// Stable, non-sensitive identifier returned to clients alongside the status.
+    pub fn code(&self) -> &'static str {
+        match self {
+            StorageError::NotFound => "not_found",
+            StorageError::LockHeld => "lock_held",
+            StorageError::LockMismatch => "lock_mismatch",
+            StorageError::BadExpiry => "bad_expiry",
+            StorageError::ExpiryOutOfRange => "expiry_out_of_range",
+            StorageError::QuotaExceeded => "quota_exceeded",
+            StorageError::ValueTooLarge => "value_too_large",
+            StorageError::BadKey => "bad_key",
+            StorageError::TooManyKeys => "too_many_keys",
+            StorageError::NoKeys => "no_keys",
+            StorageError::BadPattern => "bad_pattern",
+            StorageError::RateLimited => "rate_limited",
+            StorageError::OpQuotaExceeded => "op_quota_exceeded",
+            StorageError::PreconditionFailed => "precondition_failed",
+            StorageError::Conflict => "conflict",
+            StorageError::AlreadyExists => "already_exists",
+            StorageError::BadEntry => "bad_entry",
+            StorageError::BadTxOp => "bad_tx_op",
+            StorageError::NotAnInteger => "not_an_integer",
+            StorageError::Blob(_) => "blob_store_unavailable",
+            StorageError::Integrity => "integrity",
+            StorageError::Redis(_)
+            | StorageError::Serde(_)
+            | StorageError::Encode(_)
+            | StorageError::Decode(_)
+            | StorageError::Io(_) => "internal",
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::Redis(e) => Some(e),
+            StorageError::Serde(e) => Some(e),
+            StorageError::Encode(e) => Some(e),
+            StorageError::Decode(e) => Some(e),
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<redis::RedisError> for StorageError {
+    fn from(e: redis::RedisError) -> Self {
+        StorageError::Redis(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Serde(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for StorageError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        StorageError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for StorageError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        StorageError::Decode(e)
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// Returned by `Context::body_json` once a request body goes over `max_body_bytes`.
+#[derive(Debug, Display)]
+#[display(fmt = "request body too large")]
+pub struct BodyTooLarge;
+
+impl Error for BodyTooLarge {}
+
+/// Returned by `Context::body_bytes` when the whole body hasn't arrived within
+/// `body_read_timeout_ms`, however steadily it was trickling in.
+#[derive(Debug, Display)]
+#[display(fmt = "timed out reading the request body")]
+pub struct BodyReadTimeout;
+
+impl Error for BodyReadTimeout {}
+
+/// Returned by `get_pcr` for a pcr missing from a non-empty `allowed_pcrs`.
+#[derive(Debug, Display)]
+#[display(fmt = "pcr not allowed")]
+pub struct PcrNotAllowed;
+
+impl Error for PcrNotAllowed {}
+
+/// Returned by `blob::get` when the fetched content doesn't hash to the digest
+/// recorded when it was added.
+#[derive(Debug, Display)]
+#[display(fmt = "ipfs content does not match its digest")]
+pub struct IntegrityError;
+
+impl Error for IntegrityError {}
@@ -1,18 +1,79 @@
-use crate::{database, Config};
+use crate::cache::{BlobCache, ReadCache};
+use crate::error::{BodyReadTimeout, BodyTooLarge, PcrNotAllowed, StorageError};
+use crate::logging::{self, LogHandle};
+use crate::ratelimit::{OpCounter, RateLimiter};
+use crate::{database, ipfs, Config};
 use crate::{Context, Response};
-use hyper::StatusCode;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{header, HeaderMap, StatusCode};
+use percent_encoding::percent_decode_str;
+use route_recognizer::Params;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use tokio::sync::Mutex;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::Instant;
+use tracing::{debug, error, info};
+/// length of a hex encoded sha384 pcr
+const PCR_HEX_LEN: usize = 96;
+/// room for the key and the rest of the JSON around a value
+const MAX_BODY_OVERHEAD: usize = 64 * 1024;
+/// how long an api token hash read from Redis is used before reading it again
+const API_TOKEN_TTL: Duration = Duration::from_secs(10);
+/// namespaces whose api token hash is kept in memory at once
+const MAX_CACHED_TOKENS: usize = 10000;
+/// how long a per pcr rate limit read from Redis is used before reading it again
+const RATE_LIMIT_TTL: Duration = Duration::from_secs(10);
+/// namespaces whose rate limit is kept in memory at once
+const MAX_CACHED_RATE_LIMITS: usize = 10000;
+/// how often an idle event stream sends a comment to check the client is still there
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// content type of values sent raw as text
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+/// metadata entry sent as the Content-Type of a value returned raw
+const CONTENT_TYPE_METADATA: &str = "content_type";
+/// config fields `/admin/config` doesn't reveal, besides the tokens in `api_tokens`
+const SECRET_CONFIG_FIELDS: &[&str] = &["ipfs_secret", "s3_secret_key", "admin_token"];
+/// what `/admin/config` shows in place of a secret that is set
+const REDACTED: &str = "<redacted>";
+
 pub struct AppState {
-    pub conn: Mutex<redis::aio::Connection>,
+    /// multiplexed, so each handler works on a clone of it rather than taking turns
+    pub conn: redis::aio::ConnectionManager,
     pub config: Config,
     pub cost_map: Mutex<HashMap<String, i64>>,
+    pub log_handle: LogHandle,
+    /// set once startup finishes and cleared when shutdown begins
+    pub ready: AtomicBool,
+    pub blob_cache: BlobCache,
+    pub rate_limiter: RateLimiter,
+    pub op_counter: OpCounter,
+    /// api token hashes read from Redis, `None` for namespaces without one
+    pub api_tokens: ReadCache<Option<String>>,
+    /// per pcr rate limits read from Redis, `None` for namespaces without one
+    pub rate_limits: ReadCache<Option<u64>>,
+    /// a permit per request being handled, up to `config.max_in_flight`
+    pub in_flight: Arc<Semaphore>,
 }
 #[derive(Serialize)]
 pub struct PingResponse {
     version: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    commit: String,
+}
+#[derive(Serialize)]
+pub struct HealthResponse {
+    redis: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipfs: Option<String>,
 }
 #[derive(Deserialize)]
 pub struct LoadRequest {
@@ -21,14 +82,65 @@ pub struct LoadRequest {
 #[derive(Serialize)]
 pub struct LoadResponse {
     value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
 }
 
 #[derive(Deserialize)]
 pub struct StoreRequest {
     key: String,
     value: String,
+    /// relative expiry in milliseconds, -1 to keep the existing one
+    #[serde(default)]
+    expiry: i64,
+    /// absolute unix millisecond expiry, overrides `expiry`
+    #[serde(default)]
+    expire_at_ms: Option<i64>,
+    /// only store if the current value has this version, failing with 412 otherwise
+    #[serde(default)]
+    if_version: Option<u64>,
+    #[serde(default)]
+    storage: database::StorageMode,
+    /// headers to keep with the value, such as `content_type`, replaced on every store
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct SwapRequest {
+    key: String,
+    value: String,
+    expiry: i64,
+}
+#[derive(Serialize)]
+pub struct SwapResponse {
+    /// the value the key held before, absent if it didn't exist
+    previous: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CasTouchRequest {
+    key: String,
+    expected: String,
+    new: String,
     expiry: i64,
 }
+#[derive(Serialize)]
+pub struct CasTouchResponse {
+    updated: bool,
+}
+
+#[derive(Deserialize)]
+pub struct CasDeleteRequest {
+    key: String,
+    expected_value: String,
+}
+#[derive(Serialize)]
+pub struct CasDeleteResponse {
+    deleted: bool,
+}
 
 #[derive(Deserialize)]
 pub struct ExistsRequest {
@@ -41,38 +153,255 @@ pub struct ExistsResponse {
 
 #[derive(Deserialize)]
 pub struct ListRequest {
+    #[serde(default)]
     prefix: String,
+    #[serde(default)]
     is_recursive: bool,
+    /// glob matched against whole keys instead of listing `prefix`
+    #[serde(default)]
+    pattern: Option<String>,
 }
 #[derive(Serialize)]
 pub struct ListResponse {
     keys_list: Vec<String>,
+    checksum: String,
+}
+#[derive(Deserialize)]
+pub struct TreeRequest {
+    prefix: String,
+}
+#[derive(Serialize)]
+pub struct TreeResponse {
+    tree: BTreeMap<String, database::TreeNode>,
+    truncated: bool,
 }
 #[derive(Deserialize)]
 pub struct StatRequest {
     key: String,
 }
 
+#[derive(Deserialize)]
+pub struct TxRequest {
+    ops: Vec<database::TxOp>,
+}
+
+#[derive(Serialize)]
+pub struct TxResponse {
+    results: Vec<database::TxResult>,
+}
+
+#[derive(Deserialize)]
+pub struct StatBatchRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExistsBatchRequest {
+    keys: Vec<String>,
+}
+
 #[derive(Deserialize)]
 pub struct DeleteRequest {
     key: String,
+    /// move the key to the trash, where /restore can bring it back until it is purged
+    #[serde(default)]
+    soft: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreRequest {
+    key: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportRequest {
+    /// include offloaded values themselves rather than their CIDs
+    #[serde(default)]
+    materialize: bool,
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    imported: u64,
+    skipped: u64,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryRequest {
+    key: String,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    versions: Vec<database::HistoryEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreVersionRequest {
+    key: String,
+    version: u64,
+    /// relative expiry in milliseconds, -1 to keep the existing one
+    expiry: i64,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryDepthRequest {
+    /// previous values kept per key, null to stop keeping them
+    history_depth: Option<usize>,
 }
 #[derive(Deserialize)]
 pub struct LockRequest {
     key: String,
+    expiry_ms: Option<u64>,
+    /// keep retrying for up to this many milliseconds, at most `max_lock_expiry`,
+    /// before giving up
+    wait_ms: Option<u64>,
 }
 #[derive(Serialize)]
 pub struct LockResponse {
+    #[serde(with = "hex")]
+    lock_id: Vec<u8>,
+    /// Fencing token, increasing with every acquisition of the key. Systems written
+    /// to while holding the lock must reject tokens lower than the highest seen.
+    fence: u64,
+}
+
+#[derive(Deserialize)]
+pub struct LockManyRequest {
+    keys: Vec<String>,
+    expiry_ms: Option<u64>,
+    /// keep retrying for up to this many milliseconds, at most `max_lock_expiry`,
+    /// before giving up
+    wait_ms: Option<u64>,
+}
+#[derive(Serialize)]
+pub struct LockManyResponse {
+    #[serde(with = "hex")]
     lock_id: Vec<u8>,
+    /// fencing token per key, as returned by `lock`
+    fences: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockManyRequest {
+    keys: Vec<String>,
+    #[serde(with = "hex")]
+    lock_id: Vec<u8>,
+}
+#[derive(Serialize)]
+pub struct UnlockManyResponse {
+    results: BTreeMap<String, database::UnlockResult>,
 }
 
 #[derive(Deserialize)]
 pub struct UnlockRequest {
     key: String,
+    #[serde(with = "hex")]
+    lock_id: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+pub struct ExtendLockRequest {
+    key: String,
+    #[serde(with = "hex")]
     lock_id: Vec<u8>,
 }
 
-fn internal_server_error() -> Response {
+#[derive(Serialize)]
+pub struct UnlockResponse {
+    result: database::UnlockResult,
+}
+
+#[derive(Serialize)]
+pub struct UsageResponse {
+    bytes: i64,
+    /// operations handled in the current `ops_window`
+    ops: u64,
+}
+
+#[derive(Deserialize)]
+pub struct LogLevelRequest {
+    level: String,
+}
+
+#[derive(Deserialize)]
+pub struct WatchRequest {
+    key: String,
+    /// capped at `config.max_watch_timeout`
+    timeout_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct WatchResponse {
+    /// false when the timeout passed without the key changing
+    changed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    /// the key after the change, absent once it is gone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info: Option<database::KeyInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct EvictRequest {
+    pcr: String,
+}
+
+#[derive(Serialize)]
+pub struct EvictResponse {
+    removed: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ListLocksRequest {
+    pcr: String,
+}
+
+#[derive(Deserialize)]
+pub struct MemoryUsageRequest {
+    /// every namespace when absent
+    #[serde(default)]
+    pcr: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ListLocksResponse {
+    locks: Vec<database::LockInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct ForceUnlockRequest {
+    pcr: String,
+    key: String,
+}
+
+#[derive(Serialize)]
+pub struct ForceUnlockResponse {
+    released: bool,
+}
+
+#[derive(Serialize)]
+pub struct NamespacesResponse {
+    namespaces: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MemThresholdRequest {
+    /// in bytes, null to go back to the configured default
+    mem_threshold: Option<usize>,
+}
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct LogLevelResponse {
+    level: String,
+}
+
+pub fn internal_server_error() -> Response {
     let mut resp = Response::default();
     *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
     return resp;
@@ -85,12 +414,455 @@ fn bad_request_error() -> Response {
 }
 
 fn bad_request_response(e: Box<dyn Error>) -> Response {
+    // body_json stops reading oversized bodies, which isn't the client's JSON being wrong
+    if e.is::<BodyTooLarge>() {
+        return payload_too_large_error();
+    }
+    if e.is::<PcrNotAllowed>() {
+        return forbidden_error();
+    }
+    if e.is::<BodyReadTimeout>() {
+        let mut resp = Response::default();
+        *resp.status_mut() = StatusCode::REQUEST_TIMEOUT;
+        return resp;
+    }
     hyper::Response::builder()
         .status(StatusCode::BAD_REQUEST)
         .body(format!("could not parse JSON: {}", e).into())
         .unwrap_or(bad_request_error())
 }
 
+fn forbidden_error() -> Response {
+    let mut resp = Response::default();
+    *resp.status_mut() = StatusCode::FORBIDDEN;
+    return resp;
+}
+
+/// 401 asking for a bearer token, for requests to a namespace that has one.
+fn bearer_challenge() -> Response {
+    hyper::Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, "Bearer")
+        .body(hyper::Body::empty())
+        .unwrap_or(unauthorized_error())
+}
+
+/// 503 for requests shed because `max_in_flight` are already being handled.
+pub fn overloaded_error() -> Response {
+    hyper::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::RETRY_AFTER, "1")
+        .body(hyper::Body::empty())
+        .unwrap_or(internal_server_error())
+}
+
+/// Api token hashes read from Redis, reread every `API_TOKEN_TTL`.
+pub fn api_token_cache() -> ReadCache<Option<String>> {
+    ReadCache::new(API_TOKEN_TTL, MAX_CACHED_TOKENS)
+}
+
+/// Per pcr rate limits read from Redis, reread every `RATE_LIMIT_TTL`.
+pub fn rate_limit_cache() -> ReadCache<Option<u64>> {
+    ReadCache::new(RATE_LIMIT_TTL, MAX_CACHED_RATE_LIMITS)
+}
+
+/// Permits for `max_in_flight` concurrent requests, as many as tokio allows when 0.
+pub fn in_flight_limit(max_in_flight: usize) -> Arc<Semaphore> {
+    Arc::new(match max_in_flight {
+        0 => Semaphore::new(Semaphore::MAX_PERMITS),
+        max => Semaphore::new(cmp::min(max, Semaphore::MAX_PERMITS)),
+    })
+}
+
+pub fn gateway_timeout_error() -> Response {
+    let mut resp = Response::default();
+    *resp.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    return resp;
+}
+
+fn unauthorized_error() -> Response {
+    let mut resp = Response::default();
+    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+    return resp;
+}
+
+fn lock_held_response(config: &Config) -> Response {
+    // Retry-After is in whole seconds, so round the retry delay up
+    let retry_after = cmp::max((config.retry_delay + 999) / 1000, 1);
+    let body = error_body(&StorageError::LockHeld);
+    hyper::Response::builder()
+        .status(StatusCode::LOCKED)
+        .header(header::RETRY_AFTER, retry_after.to_string())
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .unwrap_or(internal_server_error())
+}
+
+/// Sets the ETag of `resp` to the value's version, if it has one.
+fn with_etag(mut resp: Response, version: Option<u64>) -> Response {
+    if let Some(version) = version {
+        if let Ok(etag) = header::HeaderValue::from_str(&format!("\"{}\"", version)) {
+            resp.headers_mut().insert(header::ETAG, etag);
+        }
+    }
+    resp
+}
+
+fn too_many_requests_response(e: StorageError, wait: Duration) -> Response {
+    // Retry-After is in whole seconds, so round the wait up
+    let retry_after = cmp::max((wait.as_millis() + 999) / 1000, 1);
+    hyper::Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, retry_after.to_string())
+        .header("Content-Type", "application/json")
+        .body(error_body(&e).into())
+        .unwrap_or(internal_server_error())
+}
+
+/// The token in an `Authorization: Bearer <token>` header.
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+/// Checks the bearer token against the one set for the pcr in `api_tokens`, or
+/// else in Redis, returning the 401 to send instead of running the handler when it
+/// doesn't match. Namespaces without a token are let through unless
+/// `require_api_token` is set. Admin endpoints are left to `admin_token`, and
+/// requests without a valid pcr to the handler.
+pub async fn authenticate(req: &http::Request<hyper::Body>, state: &AppState) -> Option<Response> {
+    if req.uri().path().starts_with("/admin/") {
+        return None;
+    }
+    let pcr = get_pcr(req, &state.config).ok()?;
+    let configured = state
+        .config
+        .api_tokens
+        .iter()
+        .find(|(token_pcr, _)| token_pcr.eq_ignore_ascii_case(&pcr))
+        .map(|(_, token)| database::token_hash(token));
+    let now = std::time::Instant::now();
+    let cached = match configured {
+        Some(hash) => Some(Some(hash)),
+        None => state.api_tokens.get(&pcr, now),
+    };
+    let expected = match cached {
+        Some(hash) => hash,
+        None => {
+            let mut conn = state.conn.clone();
+            match database::api_token_hash(pcr.to_owned(), &mut conn).await {
+                Ok(hash) => {
+                    state.api_tokens.insert(&pcr, hash.clone(), now);
+                    hash
+                }
+                Err(e) => {
+                    // failing open would let anyone act as the pcr while Redis is down
+                    return Some(storage_error_response(
+                        e,
+                        "authenticate",
+                        &pcr,
+                        "",
+                        &state.config,
+                    ));
+                }
+            }
+        }
+    };
+    let presented = bearer_token(req.headers()).map(database::token_hash);
+    match (expected, presented) {
+        (Some(expected), Some(presented)) if expected == presented => None,
+        (None, _) if !state.config.require_api_token => None,
+        _ => {
+            debug!(pcr = %pcr, "bad api token");
+            Some(bearer_challenge())
+        }
+    }
+}
+
+/// The pcr a request is limited and has its operations counted for, `None` for admin
+/// endpoints and requests without a valid pcr.
+pub fn op_pcr(req: &http::Request<hyper::Body>, state: &AppState) -> Option<String> {
+    if req.uri().path().starts_with("/admin/") {
+        return None;
+    }
+    get_pcr(req, &state.config).ok()
+}
+
+/// Takes a token from the pcr's bucket and checks its operation quota, returning the
+/// 429 to send instead of running the handler when either runs out. Only called once
+/// `authenticate` let the request through, so the limits read from Redis are for
+/// namespaces the client may act as. Requests without an `op_pcr` are left to the
+/// handler.
+pub async fn rate_limit(req: &http::Request<hyper::Body>, state: &AppState) -> Option<Response> {
+    let pcr = op_pcr(req, state)?;
+    let now = std::time::Instant::now();
+    let mut conn = state.conn.clone();
+    let limit = match state.rate_limits.get(&pcr, now) {
+        Some(limit) => limit,
+        None => {
+            match database::rate_limit(pcr.to_owned(), &mut conn).await {
+                Ok(limit) => {
+                    state.rate_limits.insert(&pcr, limit, now);
+                    limit
+                }
+                Err(e) => {
+                    // fall back to the configured limit rather than failing the request
+                    error!(pcr = %pcr, "could not read rate limit: {}", e);
+                    None
+                }
+            }
+        }
+    };
+    let rate = limit.unwrap_or(state.config.rate_limit_per_sec);
+    if let Err(wait) = state
+        .rate_limiter
+        .acquire(&pcr, rate, state.config.rate_limit_burst, now)
+    {
+        debug!(pcr = %pcr, "rate limited");
+        return Some(too_many_requests_response(StorageError::RateLimited, wait));
+    }
+    if state.config.max_ops_per_window > 0 {
+        match database::ops(pcr.to_owned(), &mut conn).await {
+            Ok((count, left)) => {
+                if count + state.op_counter.pending(&pcr) >= state.config.max_ops_per_window {
+                    // ops not yet flushed start the window once they are
+                    let wait = match left {
+                        left if left > 0 => left as u64,
+                        _ => state.config.ops_window,
+                    };
+                    debug!(pcr = %pcr, "operation quota exceeded");
+                    return Some(too_many_requests_response(
+                        StorageError::OpQuotaExceeded,
+                        Duration::from_millis(wait),
+                    ));
+                }
+            }
+            Err(e) => {
+                // let the request through rather than failing it on bookkeeping
+                error!(pcr = %pcr, "could not read operation count: {}", e);
+            }
+        }
+    }
+    None
+}
+
+/// Logs `e` with what was being done and to which key, then maps it to a status
+/// and a JSON body carrying its error code.
+fn storage_error_response(
+    e: StorageError,
+    op: &str,
+    pcr: &str,
+    key: &str,
+    config: &Config,
+) -> Response {
+    let status = match e {
+        StorageError::NotFound => StatusCode::NOT_FOUND,
+        StorageError::LockHeld => StatusCode::LOCKED,
+        StorageError::LockMismatch => StatusCode::CONFLICT,
+        StorageError::BadExpiry
+        | StorageError::ExpiryOutOfRange
+        | StorageError::BadKey
+        | StorageError::TooManyKeys
+        | StorageError::NoKeys
+        | StorageError::BadPattern
+        | StorageError::BadTxOp
+        | StorageError::BadEntry
+        | StorageError::NotAnInteger => StatusCode::BAD_REQUEST,
+        StorageError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+        StorageError::ValueTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        StorageError::RateLimited | StorageError::OpQuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+        StorageError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+        StorageError::Conflict | StorageError::AlreadyExists => StatusCode::CONFLICT,
+        StorageError::Blob(_) | StorageError::Integrity => StatusCode::BAD_GATEWAY,
+        StorageError::Redis(_)
+        | StorageError::Serde(_)
+        | StorageError::Encode(_)
+        | StorageError::Decode(_)
+        | StorageError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    // logged inside the request span so it can be matched to X-Request-Id
+    if status.is_server_error() {
+        error!(op, pcr, key, code = e.code(), "{}", e);
+    } else {
+        debug!(op, pcr, key, code = e.code(), "{}", e);
+    }
+    if status == StatusCode::LOCKED {
+        return lock_held_response(config);
+    }
+    hyper::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(error_body(&e).into())
+        .unwrap_or(internal_server_error())
+}
+
+/// `{"code": .., "message": ..}`, leaving internal error details to the logs.
+fn error_body(e: &StorageError) -> String {
+    let message = match e.code() {
+        "internal" => String::from("internal error"),
+        _ => e.to_string(),
+    };
+    let body = ErrorResponse {
+        code: e.code(),
+        message,
+    };
+    serde_json::to_string(&body).unwrap_or_default()
+}
+
+fn payload_too_large_error() -> Response {
+    let mut resp = Response::default();
+    *resp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+    return resp;
+}
+
+/// Whether the declared Content-Length rules out a value within `max_value_bytes`.
+/// JSON escaping takes at most 6 bytes per value byte, plus room for the key.
+fn value_body_too_large(req: &http::Request<hyper::body::Body>, config: &Config) -> bool {
+    let limit = config
+        .max_value_bytes
+        .saturating_mul(6)
+        .saturating_add(MAX_BODY_OVERHEAD);
+    match content_length(req) {
+        Some(len) => len > limit,
+        None => false,
+    }
+}
+
+fn content_length(req: &http::Request<hyper::body::Body>) -> Option<usize> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+}
+
+fn header_value(req: &http::Request<hyper::body::Body>, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// The key of a `/kv/*key` route, percent decoded so it can hold any character.
+fn path_key(params: &Params) -> Result<String, Box<dyn Error>> {
+    let key = params.find("key").ok_or("key not found in path")?;
+    Ok(percent_decode_str(key).decode_utf8()?.into_owned())
+}
+
+fn query_value(req: &http::Request<hyper::body::Body>, name: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned())
+}
+
+/// How long a request to `path` may take to produce its response, `None` for no
+/// limit. Streaming routes, whose bodies can rightly take longer than any fixed
+/// limit, have none, and routes that wait on purpose are given their longest wait
+/// on top.
+pub fn request_timeout(path: &str, config: &Config) -> Option<Duration> {
+    if config.request_timeout_ms == 0 {
+        return None;
+    }
+    let waited = match path {
+        "/import" | "/export" | "/store_stream" | "/load_stream" | "/subscribe" => return None,
+        "/watch" => config.max_watch_timeout,
+        // lock waits are clamped to max_lock_expiry
+        "/lock" | "/lock_many" => config.max_lock_expiry,
+        _ => 0,
+    };
+    Some(Duration::from_millis(
+        config.request_timeout_ms.saturating_add(waited),
+    ))
+}
+
+/// Whether `Accept-Encoding` lists gzip (or `*`) without refusing it with `q=0`.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// The Content-Type to send a value with when `Accept` prefers it raw, as
+/// `text/plain` or `application/octet-stream`, over `application/json`, which wraps
+/// it in a `LoadResponse`. `None` keeps the JSON form, as for clients that send no
+/// `Accept` or only `*/*`.
+fn raw_content_type(headers: &HeaderMap) -> Option<&'static str> {
+    let mut best: Option<(f32, Option<&'static str>)> = None;
+    for media in headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let mut parts = media.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let q = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let content_type = match name.as_str() {
+            "application/json" => None,
+            "text/plain" => Some(TEXT_CONTENT_TYPE),
+            "application/octet-stream" => Some("application/octet-stream"),
+            _ => continue,
+        };
+        // the first of equally preferred types wins
+        if q > 0.0 && best.map_or(true, |(best_q, _)| q > best_q) {
+            best = Some((q, content_type));
+        }
+    }
+    best.and_then(|(_, content_type)| content_type)
+}
+
+/// Gzips `resp` when its body is at least `min_bytes`, leaving small and already
+/// encoded responses alone.
+pub async fn gzip_response(resp: Response, min_bytes: usize) -> Response {
+    let size = resp.body().size_hint().exact().unwrap_or(0);
+    if min_bytes == 0
+        || (size as usize) < min_bytes
+        || resp.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => return internal_server_error(),
+    };
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+        Ok(compressed) => compressed,
+        Err(_) => return internal_server_error(),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        header::HeaderValue::from_static("gzip"),
+    );
+    parts.headers.append(
+        header::VARY,
+        header::HeaderValue::from_static("accept-encoding"),
+    );
+    Response::from_parts(parts, compressed.into())
+}
+
 fn json_response<T>(val: &T) -> Response
 where
     T: ?Sized + Serialize,
@@ -108,10 +880,35 @@ where
     }
 }
 
-fn get_pcr(req: &http::Request<hyper::body::Body>) -> Result<String, Box<dyn Error>> {
+/// The request's pcr, rejected with `PcrNotAllowed` when `allowed_pcrs` is set
+/// and doesn't list it.
+fn get_pcr(
+    req: &http::Request<hyper::body::Body>,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    let pcr = read_pcr(req)?;
+    if !config.allowed_pcrs.is_empty()
+        && !config
+            .allowed_pcrs
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&pcr))
+    {
+        return Err(Box::new(PcrNotAllowed));
+    }
+    Ok(pcr)
+}
+
+/// The request's pcr, without checking it against `allowed_pcrs`.
+pub fn read_pcr(req: &http::Request<hyper::body::Body>) -> Result<String, Box<dyn Error>> {
     match req.headers().get("pcr").ok_or(Err("pcr not found".into())) {
         Ok(value) => {
-            return Ok(String::from(value.to_str()?));
+            let pcr = value.to_str()?;
+            // the pcr becomes the namespace prefix, so anything but a fixed length hex
+            // string could collide with or reach into another tenant's keys
+            if !is_valid_pcr(pcr) {
+                return Err(format!("pcr must be {} hex characters", PCR_HEX_LEN).into());
+            }
+            return Ok(pcr.to_ascii_lowercase());
         }
         Err(e) => {
             return e;
@@ -119,254 +916,2487 @@ fn get_pcr(req: &http::Request<hyper::body::Body>) -> Result<String, Box<dyn Err
     }
 }
 
-async fn update_cost(pcr: String, cost: i64, cost_map: &Mutex<HashMap<String, i64>>) {
-    let mut map = cost_map.lock().await;
-    *map.entry(pcr.to_owned()).or_default() += cost;
+fn check_admin(req: &http::Request<hyper::body::Body>, config: &Config) -> Result<(), Response> {
+    if config.admin_token.is_empty() {
+        return Err(unauthorized_error());
+    }
+    // compared by digest, like api tokens, so how long it takes says nothing about
+    // how much of the token matched
+    let presented = req
+        .headers()
+        .get("admin-token")
+        .and_then(|value| value.to_str().ok())
+        .map(database::token_hash);
+    match presented {
+        Some(presented) if presented == database::token_hash(&config.admin_token) => Ok(()),
+        _ => Err(unauthorized_error()),
+    }
 }
 
-pub async fn ping(_ctx: Context) -> Response {
-    let resp = PingResponse {
-        version: "0.0.1".into(),
-    };
-    return json_response(&resp);
+/// The ids of the blobs the namespace's keys are offloaded to and the cost of
+/// reading them.
+async fn referenced_blobs(
+    pcr: &str,
+    state: &AppState,
+) -> Result<(HashSet<String>, i64), StorageError> {
+    let mut blobs = HashSet::new();
+    let mut cost: i64 = 0;
+    let mut cursor = 0;
+    let mut conn = state.conn.clone();
+    loop {
+        let (entries, next, batch_cost) =
+            database::export_batch(pcr.to_owned(), cursor, &mut conn, &state.config).await?;
+        blobs.extend(entries.into_iter().filter_map(|entry| entry.cid));
+        cost = cost.saturating_add(batch_cost);
+        if next == 0 {
+            return Ok((blobs, cost));
+        }
+        cursor = next;
+    }
 }
 
-pub async fn load(mut ctx: Context) -> Response {
-    let body: LoadRequest = match ctx.body_json().await {
-        Ok(v) => v,
+/// Until when a lock request retries a held lock: `wait_ms`, at most
+/// `max_lock_expiry`, but never less than `retry_count` attempts take.
+fn lock_deadline(wait_ms: Option<u64>, config: &Config) -> Instant {
+    let retries = config
+        .retry_delay
+        .saturating_mul(config.retry_count.saturating_sub(1));
+    let wait = cmp::min(wait_ms.unwrap_or(0), config.max_lock_expiry);
+    Instant::now() + Duration::from_millis(cmp::max(wait, retries))
+}
+
+pub fn is_valid_pcr(pcr: &str) -> bool {
+    pcr.len() == PCR_HEX_LEN && pcr.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Moves the costs gathered since the last flush into Redis, putting them back to
+/// retry with the next flush if that fails.
+pub async fn flush_costs(state: &AppState) -> Result<(), StorageError> {
+    let costs = std::mem::take(&mut *state.cost_map.lock().await);
+    let mut ops = state.op_counter.take();
+    let mut conn = state.conn.clone();
+    if let Err(e) = database::add_costs(&costs, &mut conn).await {
+        for (pcr, cost) in costs {
+            update_cost(pcr, cost, &state.cost_map).await;
+        }
+        state.op_counter.restore(ops);
+        return Err(e);
+    }
+    if let Err(e) = database::add_ops(&mut ops, state.config.ops_window, &mut conn).await {
+        state.op_counter.restore(ops);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Removes soft deleted keys past their retention, returning how many.
+pub async fn purge_trash(state: &AppState) -> Result<i64, StorageError> {
+    let mut purged = 0;
+    let mut conn = state.conn.clone();
+    loop {
+        let (batch, more) =
+            database::purge_trash_batch(&state.blob_cache, &mut conn, &state.config).await?;
+        purged += batch;
+        if !more {
+            return Ok(purged);
+        }
+    }
+}
+
+async fn update_cost(pcr: String, cost: i64, cost_map: &Mutex<HashMap<String, i64>>) {
+    let mut map = cost_map.lock().await;
+    let total = map.entry(pcr.to_owned()).or_default();
+    *total = total.saturating_add(cost);
+}
+
+pub async fn ping(_ctx: Context) -> Response {
+    let resp = PingResponse {
+        version: env!("CARGO_PKG_VERSION").into(),
+        commit: env!("GIT_HASH").into(),
+    };
+    return json_response(&resp);
+}
+
+pub async fn health(ctx: Context) -> Response {
+    let mut healthy = true;
+    let mut conn = ctx.state.conn.clone();
+    let redis = match database::ping(&mut conn).await {
+        Ok(()) => String::from("ok"),
+        Err(e) => {
+            healthy = false;
+            e.to_string()
+        }
+    };
+    let ipfs = if ctx.state.config.health_check_ipfs {
+        match ipfs::version(&ctx.state.config).await {
+            Ok(()) => Some(String::from("ok")),
+            Err(e) => {
+                healthy = false;
+                Some(e.to_string())
+            }
+        }
+    } else {
+        None
+    };
+    let mut resp = json_response(&HealthResponse { redis, ipfs });
+    if !healthy {
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    return resp;
+}
+
+pub async fn ready(ctx: Context) -> Response {
+    let mut resp = Response::default();
+    if !ctx.state.ready.load(Ordering::SeqCst) {
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        return resp;
+    }
+    let mut conn = ctx.state.conn.clone();
+    if database::ping(&mut conn).await.is_err() {
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    return resp;
+}
+
+pub async fn load(mut ctx: Context) -> Response {
+    let body: LoadRequest = match ctx.body_json().await {
+        Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
-    let load_result =
-        match database::load(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await {
-            Ok(value) => value,
-            Err(_) => {
-                return internal_server_error();
-            }
-        };
+    let mut conn = ctx.state.conn.clone();
+    let load_result = match database::load(
+        pcr.to_owned(),
+        &body.key,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "load", &pcr, &body.key, &ctx.state.config);
+        }
+    };
     update_cost(pcr, load_result.1, &ctx.state.cost_map).await;
-    let resp = LoadResponse {
-        value: load_result.0,
+    let mut resp = match raw_content_type(ctx.req.headers()) {
+        Some(content_type) => raw_value_response(load_result.0, content_type, &load_result.3),
+        None => json_response(&LoadResponse {
+            value: load_result.0,
+            version: load_result.2,
+            metadata: load_result.3,
+        }),
     };
-    return json_response(&resp);
+    resp.headers_mut()
+        .insert(header::VARY, header::HeaderValue::from_static("Accept"));
+    with_etag(resp, load_result.2)
+}
+
+/// `GET /kv/*key`: the value as a plain text body, or `application/octet-stream`
+/// when `Accept` prefers it, for clients that can only send a GET, such as browsers
+/// and caching proxies.
+pub async fn kv_get(ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let key = match path_key(&ctx.params) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+    let load_result = match database::load(
+        pcr.to_owned(),
+        &key,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "kv_get", &pcr, &key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, load_result.1, &ctx.state.cost_map).await;
+    let content_type = raw_content_type(ctx.req.headers()).unwrap_or(TEXT_CONTENT_TYPE);
+    let resp = raw_value_response(load_result.0, content_type, &load_result.3);
+    with_etag(resp, load_result.2)
+}
+
+/// The value as the body, sent as the content type stored in its metadata when it
+/// has a valid one and as `content_type` otherwise.
+fn raw_value_response(
+    value: String,
+    content_type: &'static str,
+    metadata: &HashMap<String, String>,
+) -> Response {
+    let content_type =
+        stored_content_type(metadata).unwrap_or(header::HeaderValue::from_static(content_type));
+    hyper::Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(value.into())
+        .unwrap_or(internal_server_error())
+}
+
+fn stored_content_type(metadata: &HashMap<String, String>) -> Option<header::HeaderValue> {
+    metadata
+        .get(CONTENT_TYPE_METADATA)
+        .and_then(|value| header::HeaderValue::from_str(value).ok())
+}
+
+/// Sends the value itself as the body rather than wrapped in JSON, streaming large
+/// offloaded values instead of holding them in memory.
+pub async fn load_stream(mut ctx: Context) -> Response {
+    let body: LoadRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+    let load_result = match database::load_stream(
+        pcr.to_owned(),
+        &body.key,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "load_stream", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, load_result.1, &ctx.state.cost_map).await;
+    hyper::Response::builder()
+        .header("Content-Type", "application/octet-stream")
+        .body(load_result.0)
+        .unwrap_or(internal_server_error())
 }
 
 pub async fn store(mut ctx: Context) -> Response {
+    if value_body_too_large(&ctx.req, &ctx.state.config) {
+        return payload_too_large_error();
+    }
     let body: StoreRequest = match ctx.body_json().await {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
-    let cost = match database::store(
+    let mut conn = ctx.state.conn.clone();
+    let options = database::StoreOptions {
+        storage: body.storage,
+        expire_at_ms: body.expire_at_ms,
+        if_version: body.if_version,
+        metadata: body.metadata,
+    };
+    let result = match database::store_with_options(
         pcr.to_owned(),
         &body.key,
         body.expiry,
         &body.value,
+        &options,
+        &ctx.state.blob_cache,
         &mut conn,
         &ctx.state.config,
     )
     .await
     {
         Ok(value) => value,
-        Err(_) => {
-            return internal_server_error();
+        Err(e) => {
+            return storage_error_response(e, "store", &pcr, &body.key, &ctx.state.config);
         }
     };
-    update_cost(pcr, cost, &ctx.state.cost_map).await;
-    return Response::default();
+    update_cost(pcr, result.cost, &ctx.state.cost_map).await;
+    return json_response(&result);
 }
 
-pub async fn exists(mut ctx: Context) -> Response {
-    let body: ExistsRequest = match ctx.body_json().await {
+pub async fn swap(mut ctx: Context) -> Response {
+    if value_body_too_large(&ctx.req, &ctx.state.config) {
+        return payload_too_large_error();
+    }
+    let body: SwapRequest = match ctx.body_json().await {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
-
-    let exists_result =
-        match database::exists(pcr.to_owned(), &body.key, &mut *conn, &ctx.state.config).await {
-            Ok(value) => value,
-            Err(_) => {
-                return internal_server_error();
-            }
-        };
-    update_cost(pcr, exists_result.1, &ctx.state.cost_map).await;
-    let resp = ExistsResponse {
-        value: exists_result.0,
+    let mut conn = ctx.state.conn.clone();
+    let swap_result = match database::swap(
+        pcr.to_owned(),
+        &body.key,
+        body.expiry,
+        &body.value,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "swap", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, swap_result.1, &ctx.state.cost_map).await;
+    let resp = SwapResponse {
+        previous: swap_result.0,
     };
     return json_response(&resp);
 }
 
-pub async fn list(mut ctx: Context) -> Response {
-    let body: ListRequest = match ctx.body_json().await {
+/// Stores the raw request body under the `key` header for `expiry` milliseconds,
+/// uploading it to the blob store as it arrives rather than buffering it first.
+pub async fn store_stream(mut ctx: Context) -> Response {
+    if content_length(&ctx.req).unwrap_or_default() > ctx.state.config.max_value_bytes {
+        return payload_too_large_error();
+    }
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
-        Ok(v) => v,
+    let key = match header_value(&ctx.req, "key") {
+        Some(v) => v,
+        None => {
+            return bad_request_response("key header not found".into());
+        }
+    };
+    let expiry = match header_value(&ctx.req, "expiry").and_then(|v| v.parse::<i64>().ok()) {
+        Some(v) => v,
+        None => {
+            return bad_request_response("expiry header must be a number".into());
+        }
+    };
+    if let Err(e) = database::validate_expiry(expiry, &ctx.state.config)
+        .and_then(|_| database::validate_key(&key, &ctx.state.config))
+    {
+        return storage_error_response(e, "store_stream", &pcr, &key, &ctx.state.config);
+    }
+    let body = std::mem::take(ctx.req.body_mut());
+    let offloaded = match database::offload_stream(body, &ctx.state.config).await {
+        Ok(value) => value,
         Err(e) => {
-            return bad_request_response(e);
+            return storage_error_response(e, "store_stream", &pcr, &key, &ctx.state.config);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
-
-    let list_result = match database::list(
+    let mut conn = ctx.state.conn.clone();
+    let result = match database::store_offloaded(
         pcr.to_owned(),
-        &body.prefix,
-        body.is_recursive,
-        &mut *conn,
+        &key,
+        expiry,
+        offloaded,
+        &ctx.state.blob_cache,
+        &mut conn,
         &ctx.state.config,
     )
     .await
     {
         Ok(value) => value,
-        Err(_) => {
-            return internal_server_error();
+        Err(e) => {
+            return storage_error_response(e, "store_stream", &pcr, &key, &ctx.state.config);
         }
     };
-    update_cost(pcr, list_result.1, &ctx.state.cost_map).await;
-    let resp = ListResponse {
-        keys_list: list_result.0,
-    };
-    return json_response(&resp);
+    update_cost(pcr, result.cost, &ctx.state.cost_map).await;
+    return json_response(&result);
 }
 
-pub async fn stat(mut ctx: Context) -> Response {
-    let body: StatRequest = match ctx.body_json().await {
+/// `PUT /kv/*key`: stores the body as the value, with the expiry in milliseconds
+/// from an `expiry` header or query parameter. The Content-Type is kept to send the
+/// value back with.
+pub async fn kv_put(mut ctx: Context) -> Response {
+    if content_length(&ctx.req).unwrap_or_default() > ctx.state.config.max_value_bytes {
+        return payload_too_large_error();
+    }
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let key = match path_key(&ctx.params) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
-
-    let stat_result =
-        match database::stat(pcr.to_owned(), &body.key, &mut *conn, &ctx.state.config).await {
-            Ok(value) => value,
-            Err(_) => {
-                return internal_server_error();
-            }
-        };
-    update_cost(pcr, stat_result.1, &ctx.state.cost_map).await;
-    return json_response(&stat_result.0);
+    let expiry = match header_value(&ctx.req, "expiry")
+        .or_else(|| query_value(&ctx.req, "expiry"))
+        .and_then(|v| v.parse::<i64>().ok())
+    {
+        Some(v) => v,
+        None => {
+            return bad_request_response(
+                "expiry header or query parameter must be a number".into(),
+            );
+        }
+    };
+    let value = match ctx.body_bytes().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let value = match String::from_utf8(value) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e.into());
+        }
+    };
+    let mut options = database::StoreOptions::default();
+    if let Some(content_type) = header_value(&ctx.req, "content-type") {
+        options
+            .metadata
+            .insert(String::from(CONTENT_TYPE_METADATA), content_type);
+    }
+    let mut conn = ctx.state.conn.clone();
+    let result = match database::store_with_options(
+        pcr.to_owned(),
+        &key,
+        expiry,
+        &value,
+        &options,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "kv_put", &pcr, &key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, result.cost, &ctx.state.cost_map).await;
+    return json_response(&result);
 }
 
-pub async fn delete(mut ctx: Context) -> Response {
-    let body: DeleteRequest = match ctx.body_json().await {
+pub async fn cas_touch(mut ctx: Context) -> Response {
+    if value_body_too_large(&ctx.req, &ctx.state.config) {
+        return payload_too_large_error();
+    }
+    let body: CasTouchRequest = match ctx.body_json().await {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
-
-    let delete_result =
-        match database::delete(pcr.to_owned(), &body.key, &mut *conn, &ctx.state.config).await {
-            Ok(value) => value,
-            Err(_) => {
-                return internal_server_error();
-            }
-        };
-    update_cost(pcr, delete_result, &ctx.state.cost_map).await;
-    return Response::default();
+    let mut conn = ctx.state.conn.clone();
+    let cas_result = match database::cas_touch(
+        pcr.to_owned(),
+        &body.key,
+        &body.expected,
+        &body.new,
+        body.expiry,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "cas_touch", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, cas_result.1, &ctx.state.cost_map).await;
+    let resp = CasTouchResponse {
+        updated: cas_result.0,
+    };
+    return json_response(&resp);
 }
 
-pub async fn lock(mut ctx: Context) -> Response {
-    let body: LockRequest = match ctx.body_json().await {
+pub async fn exists(mut ctx: Context) -> Response {
+    let body: ExistsRequest = match ctx.body_json().await {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
+    let mut conn = ctx.state.conn.clone();
 
-    let lock_result =
-        match database::lock(pcr.to_owned(), &body.key, &mut *conn, &ctx.state.config).await {
+    let exists_result =
+        match database::exists(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await {
             Ok(value) => value,
-            Err(_) => {
-                return internal_server_error();
+            Err(e) => {
+                return storage_error_response(e, "exists", &pcr, &body.key, &ctx.state.config);
             }
         };
-    update_cost(pcr, lock_result.1, &ctx.state.cost_map).await;
-    let resp = LockResponse {
-        lock_id: lock_result.0,
+    update_cost(pcr, exists_result.1, &ctx.state.cost_map).await;
+    let resp = ExistsResponse {
+        value: exists_result.0,
     };
     return json_response(&resp);
 }
 
-pub async fn unlock(mut ctx: Context) -> Response {
-    let body: UnlockRequest = match ctx.body_json().await {
+/// Which of `keys` exist, as a map from each key to whether it does.
+pub async fn exists_batch(mut ctx: Context) -> Response {
+    let body: ExistsBatchRequest = match ctx.body_json().await {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let pcr = match get_pcr(&ctx.req) {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
         Ok(v) => v,
         Err(e) => {
             return bad_request_response(e);
         }
     };
-    let mut conn = ctx.state.conn.lock().await;
+    let mut conn = ctx.state.conn.clone();
 
-    let unlock_result = match database::unlock(
+    let exists_result = match database::exists_batch(
         pcr.to_owned(),
-        &body.key,
-        &body.lock_id,
-        &mut *conn,
+        &body.keys,
+        &mut conn,
         &ctx.state.config,
     )
     .await
     {
         Ok(value) => value,
-        Err(_) => {
-            return internal_server_error();
+        Err(e) => {
+            return storage_error_response(e, "exists_batch", &pcr, "", &ctx.state.config);
         }
     };
-    update_cost(pcr, unlock_result, &ctx.state.cost_map).await;
-    return Response::default();
+    update_cost(pcr, exists_result.1, &ctx.state.cost_map).await;
+    return json_response(&exists_result.0);
+}
+
+pub async fn list(mut ctx: Context) -> Response {
+    let body: ListRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let list_result = match &body.pattern {
+        Some(pattern) => {
+            database::list_matching(pcr.to_owned(), pattern, &mut conn, &ctx.state.config).await
+        }
+        None => {
+            database::list(
+                pcr.to_owned(),
+                &body.prefix,
+                body.is_recursive,
+                &mut conn,
+                &ctx.state.config,
+            )
+            .await
+        }
+    };
+    let list_result = match list_result {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "list", &pcr, &body.prefix, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, list_result.1, &ctx.state.cost_map).await;
+    let checksum = database::list_checksum(&list_result.0);
+    let etag = format!("\"{}\"", checksum);
+    let unchanged = match ctx.req.headers().get(header::IF_NONE_MATCH) {
+        Some(value) => {
+            value.as_bytes() == etag.as_bytes() || value.as_bytes() == checksum.as_bytes()
+        }
+        None => false,
+    };
+    if unchanged {
+        return hyper::Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(hyper::Body::empty())
+            .unwrap_or(internal_server_error());
+    }
+    let resp = ListResponse {
+        keys_list: list_result.0,
+        checksum,
+    };
+    let mut resp = json_response(&resp);
+    if let Ok(value) = header::HeaderValue::from_str(&etag) {
+        resp.headers_mut().insert(header::ETAG, value);
+    }
+    return resp;
+}
+
+pub async fn tree(mut ctx: Context) -> Response {
+    let body: TreeRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let tree_result =
+        match database::tree(pcr.to_owned(), &body.prefix, &mut conn, &ctx.state.config).await {
+            Ok(value) => value,
+            Err(e) => {
+                return storage_error_response(e, "tree", &pcr, &body.prefix, &ctx.state.config);
+            }
+        };
+    update_cost(pcr, tree_result.2, &ctx.state.cost_map).await;
+    let resp = TreeResponse {
+        tree: tree_result.0,
+        truncated: tree_result.1,
+    };
+    return json_response(&resp);
+}
+
+pub async fn stat(mut ctx: Context) -> Response {
+    let body: StatRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let stat_result =
+        match database::stat(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await {
+            Ok(value) => value,
+            Err(e) => {
+                return storage_error_response(e, "stat", &pcr, &body.key, &ctx.state.config);
+            }
+        };
+    update_cost(pcr, stat_result.1, &ctx.state.cost_map).await;
+    let version = stat_result.0.version;
+    with_etag(json_response(&stat_result.0), version)
+}
+
+/// `HEAD /load` with the key in a `key` header: the value's metadata as headers,
+/// without reading the value or its blob.
+pub async fn load_head(ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let key = match header_value(&ctx.req, "key") {
+        Some(v) => v,
+        None => {
+            return bad_request_response("key header not found".into());
+        }
+    };
+    head_response(&ctx, pcr, &key, "load_head").await
+}
+
+/// `HEAD /kv/*key`, the same as `HEAD /load` with the key in the path.
+pub async fn kv_head(ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let key = match path_key(&ctx.params) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    head_response(&ctx, pcr, &key, "kv_head").await
+}
+
+async fn head_response(ctx: &Context, pcr: String, key: &String, op: &str) -> Response {
+    let mut conn = ctx.state.conn.clone();
+
+    let stat_result = match database::stat(pcr.to_owned(), key, &mut conn, &ctx.state.config).await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, op, &pcr, key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, stat_result.1, &ctx.state.cost_map).await;
+    let info = stat_result.0;
+    let mut resp = hyper::Response::builder()
+        .header("X-Value-Size", info.size)
+        .header("X-Modified", info.modified)
+        .header("X-Ipfs", info.ipfs.to_string())
+        .body(hyper::Body::empty())
+        .unwrap_or(internal_server_error());
+    if let Some(content_type) = stored_content_type(&info.metadata) {
+        resp.headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+    }
+    with_etag(resp, info.version)
+}
+
+pub async fn stat_batch(mut ctx: Context) -> Response {
+    let body: StatBatchRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let stat_result = match database::stat_batch(
+        pcr.to_owned(),
+        &body.keys,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "stat_batch", &pcr, "", &ctx.state.config);
+        }
+    };
+    update_cost(pcr, stat_result.1, &ctx.state.cost_map).await;
+    return json_response(&stat_result.0);
+}
+
+pub async fn delete(mut ctx: Context) -> Response {
+    let body: DeleteRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let delete_result = if body.soft {
+        database::soft_delete(
+            pcr.to_owned(),
+            &body.key,
+            &ctx.state.blob_cache,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await
+    } else {
+        database::delete(
+            pcr.to_owned(),
+            &body.key,
+            &ctx.state.blob_cache,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await
+    };
+    let delete_result = match delete_result {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "delete", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, delete_result, &ctx.state.cost_map).await;
+    return Response::default();
+}
+
+/// `DELETE /kv/*key`, moving the key to the trash instead with `?soft=true`.
+pub async fn kv_delete(ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let key = match path_key(&ctx.params) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let soft = query_value(&ctx.req, "soft").map_or(false, |v| v == "true");
+    let mut conn = ctx.state.conn.clone();
+
+    let delete_result = if soft {
+        database::soft_delete(
+            pcr.to_owned(),
+            &key,
+            &ctx.state.blob_cache,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await
+    } else {
+        database::delete(
+            pcr.to_owned(),
+            &key,
+            &ctx.state.blob_cache,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await
+    };
+    let delete_result = match delete_result {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "kv_delete", &pcr, &key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, delete_result, &ctx.state.cost_map).await;
+    return Response::default();
+}
+
+/// Brings back a key soft deleted within the last `trash_retention` milliseconds.
+pub async fn restore(mut ctx: Context) -> Response {
+    let body: RestoreRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let restore_result =
+        match database::restore(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await {
+            Ok(value) => value,
+            Err(e) => {
+                return storage_error_response(e, "restore", &pcr, &body.key, &ctx.state.config);
+            }
+        };
+    update_cost(pcr, restore_result, &ctx.state.cost_map).await;
+    return Response::default();
+}
+
+/// Lists the previous values kept for a key, newest first.
+pub async fn history(mut ctx: Context) -> Response {
+    let body: HistoryRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let history_result =
+        match database::history(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await {
+            Ok(value) => value,
+            Err(e) => {
+                return storage_error_response(e, "history", &pcr, &body.key, &ctx.state.config);
+            }
+        };
+    update_cost(pcr, history_result.1, &ctx.state.cost_map).await;
+    return json_response(&HistoryResponse {
+        versions: history_result.0,
+    });
+}
+
+/// Makes a value from the key's history its current value again.
+pub async fn restore_version(mut ctx: Context) -> Response {
+    let body: RestoreVersionRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let result = match database::restore_version(
+        pcr.to_owned(),
+        &body.key,
+        body.version,
+        body.expiry,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(
+                e,
+                "restore_version",
+                &pcr,
+                &body.key,
+                &ctx.state.config,
+            );
+        }
+    };
+    update_cost(pcr, result.cost, &ctx.state.cost_map).await;
+    return json_response(&result);
+}
+
+pub async fn cas_delete(mut ctx: Context) -> Response {
+    let body: CasDeleteRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let cas_result = match database::cas_delete(
+        pcr.to_owned(),
+        &body.key,
+        &body.expected_value,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "cas_delete", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, cas_result.1, &ctx.state.cost_map).await;
+    let resp = CasDeleteResponse {
+        deleted: cas_result.0,
+    };
+    return json_response(&resp);
+}
+
+/// Runs a list of get/set/del/incr ops atomically, failing with 412 and writing
+/// nothing if any of their preconditions doesn't hold.
+pub async fn tx(mut ctx: Context) -> Response {
+    let body: TxRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let tx_result = match database::transaction(
+        pcr.to_owned(),
+        &body.ops,
+        &ctx.state.blob_cache,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "tx", &pcr, "", &ctx.state.config);
+        }
+    };
+    update_cost(pcr, tx_result.1, &ctx.state.cost_map).await;
+    return json_response(&TxResponse {
+        results: tx_result.0,
+    });
+}
+
+pub async fn lock(mut ctx: Context) -> Response {
+    let body: LockRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let deadline = lock_deadline(body.wait_ms, &ctx.state.config);
+
+    let mut conn = ctx.state.conn.clone();
+    let lock_result = loop {
+        let result = database::lock(
+            pcr.to_owned(),
+            &body.key,
+            body.expiry_ms,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await;
+        match result {
+            Ok(value) => break value,
+            Err(StorageError::LockHeld) if Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(ctx.state.config.retry_delay)).await;
+            }
+            Err(e) => {
+                return storage_error_response(e, "lock", &pcr, &body.key, &ctx.state.config);
+            }
+        }
+    };
+    update_cost(pcr, lock_result.2, &ctx.state.cost_map).await;
+    let resp = LockResponse {
+        lock_id: lock_result.0,
+        fence: lock_result.1,
+    };
+    return json_response(&resp);
+}
+
+/// Locks every key in the request under one lock_id, all or nothing.
+pub async fn lock_many(mut ctx: Context) -> Response {
+    let body: LockManyRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let deadline = lock_deadline(body.wait_ms, &ctx.state.config);
+
+    let mut conn = ctx.state.conn.clone();
+    let lock_result = loop {
+        let result = database::lock_many(
+            pcr.to_owned(),
+            &body.keys,
+            body.expiry_ms,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await;
+        match result {
+            Ok(value) => break value,
+            Err(StorageError::LockHeld) if Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(ctx.state.config.retry_delay)).await;
+            }
+            Err(e) => {
+                return storage_error_response(e, "lock_many", &pcr, "", &ctx.state.config);
+            }
+        }
+    };
+    update_cost(pcr, lock_result.2, &ctx.state.cost_map).await;
+    let resp = LockManyResponse {
+        lock_id: lock_result.0,
+        fences: lock_result.1,
+    };
+    return json_response(&resp);
+}
+
+pub async fn unlock_many(mut ctx: Context) -> Response {
+    let body: UnlockManyRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let unlock_result = match database::unlock_many(
+        pcr.to_owned(),
+        &body.keys,
+        &body.lock_id,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "unlock_many", &pcr, "", &ctx.state.config);
+        }
+    };
+    update_cost(pcr, unlock_result.1, &ctx.state.cost_map).await;
+    let resp = UnlockManyResponse {
+        results: unlock_result.0,
+    };
+    return json_response(&resp);
+}
+
+pub async fn unlock(mut ctx: Context) -> Response {
+    let body: UnlockRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let unlock_result = match database::unlock(
+        pcr.to_owned(),
+        &body.key,
+        &body.lock_id,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "unlock", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, unlock_result.1, &ctx.state.cost_map).await;
+    let resp = UnlockResponse {
+        result: unlock_result.0,
+    };
+    return json_response(&resp);
+}
+
+pub async fn extend_lock(mut ctx: Context) -> Response {
+    let body: ExtendLockRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let extend_result = match database::extend_lock(
+        pcr.to_owned(),
+        &body.key,
+        &body.lock_id,
+        &mut conn,
+        &ctx.state.config,
+    )
+    .await
+    {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "extend_lock", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(pcr, extend_result, &ctx.state.cost_map).await;
+    return Response::default();
+}
+
+pub async fn usage(ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    let bytes = match database::usage(pcr.to_owned(), &mut conn, &ctx.state.config).await {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "usage", &pcr, "", &ctx.state.config);
+        }
+    };
+    let ops = match database::ops(pcr.to_owned(), &mut conn).await {
+        Ok((count, _)) => count + ctx.state.op_counter.pending(&pcr),
+        Err(e) => {
+            return storage_error_response(e, "usage", &pcr, "", &ctx.state.config);
+        }
+    };
+    return json_response(&UsageResponse { bytes, ops });
+}
+
+pub async fn get_log_level(ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let level = match logging::current_level(&ctx.state.log_handle) {
+        Ok(value) => value,
+        Err(_) => {
+            return internal_server_error();
+        }
+    };
+    let resp = LogLevelResponse { level };
+    return json_response(&resp);
+}
+
+pub async fn set_log_level(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: LogLevelRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    if let Err(e) = logging::set_level(&ctx.state.log_handle, &body.level) {
+        return bad_request_response(e);
+    }
+    return Response::default();
+}
+
+/// Streams `KeyEvent`s for the namespace as server-sent events until the client
+/// goes away.
+pub async fn subscribe(ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pubsub = match database::subscribe(&pcr, &ctx.state.config).await {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "subscribe", &pcr, "", &ctx.state.config);
+        }
+    };
+    update_cost(
+        pcr.to_owned(),
+        ctx.state.config.operation_b_cost,
+        &ctx.state.cost_map,
+    )
+    .await;
+
+    let (mut sender, body) = hyper::Body::channel();
+    let state = ctx.state.clone();
+    tokio::spawn(async move {
+        let mut messages = Box::pin(pubsub.into_on_message());
+        // a quiet namespace would otherwise never notice the client leaving
+        let mut keep_alive = tokio::time::interval(SSE_KEEP_ALIVE);
+        loop {
+            let chunk = tokio::select! {
+                msg = messages.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    };
+                    let event = match database::key_event(&pcr, &msg, &state.config) {
+                        Some(event) => event,
+                        None => continue,
+                    };
+                    match serde_json::to_string(&event) {
+                        Ok(data) => format!("data: {}\n\n", data),
+                        Err(_) => continue,
+                    }
+                }
+                _ = keep_alive.tick() => String::from(": keep-alive\n\n"),
+            };
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+        debug!(pcr = %pcr, "subscription closed");
+    });
+    hyper::Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap_or(internal_server_error())
+}
+
+/// Streams every key in the namespace as newline delimited JSON `ExportEntry`s,
+/// one SCAN batch at a time.
+pub async fn export(mut ctx: Context) -> Response {
+    let body: ExportRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+
+    let (mut sender, stream) = hyper::Body::channel();
+    let state = ctx.state.clone();
+    tokio::spawn(async move {
+        let mut cursor = 0;
+        let mut cost: i64 = 0;
+        let mut conn = state.conn.clone();
+        loop {
+            let batch =
+                database::export_batch(pcr.to_owned(), cursor, &mut conn, &state.config).await;
+            let batch = match batch {
+                Ok((mut entries, next, batch_cost)) if body.materialize => {
+                    materialize_all(&mut entries, &state)
+                        .await
+                        .map(|_| (entries, next, batch_cost))
+                }
+                batch => batch,
+            };
+            let (entries, next, batch_cost) = match batch {
+                Ok(value) => value,
+                Err(e) => {
+                    // the client sees a truncated body rather than a clean end
+                    error!(pcr = %pcr, "export failed: {}", e);
+                    sender.abort();
+                    break;
+                }
+            };
+            cost = cost.saturating_add(batch_cost);
+            let mut chunk = Vec::new();
+            for entry in &entries {
+                if serde_json::to_writer(&mut chunk, entry).is_ok() {
+                    chunk.push(b'\n');
+                }
+            }
+            if !chunk.is_empty() && sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        update_cost(pcr, cost, &state.cost_map).await;
+    });
+    hyper::Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(stream)
+        .unwrap_or(internal_server_error())
+}
+
+/// Fetches the offloaded values of `entries` in place of their CIDs.
+async fn materialize_all(
+    entries: &mut [database::ExportEntry],
+    state: &AppState,
+) -> Result<(), StorageError> {
+    for entry in entries {
+        database::materialize(entry, &state.blob_cache, &state.config).await?;
+    }
+    Ok(())
+}
+
+/// Recreates the keys in a newline delimited JSON export sent as the raw body. A
+/// `mode` header of `overwrite` replaces existing keys, which are otherwise skipped.
+/// Entries are imported as they arrive, so a failure part way leaves the ones before
+/// it in place.
+pub async fn import(mut ctx: Context) -> Response {
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mode = match header_value(&ctx.req, "mode").as_deref() {
+        None | Some("skip") => database::ImportMode::Skip,
+        Some("overwrite") => database::ImportMode::Overwrite,
+        Some(_) => {
+            return bad_request_response("mode must be skip or overwrite".into());
+        }
+    };
+    let is_admin = check_admin(&ctx.req, &ctx.state.config).is_ok();
+    // blobs the namespace references, found the first time an entry names one
+    let mut referenced: Option<HashSet<String>> = None;
+    let mut body = std::mem::take(ctx.req.body_mut());
+    let mut pending: Vec<u8> = Vec::new();
+    let mut resp = ImportResponse {
+        imported: 0,
+        skipped: 0,
+    };
+    let mut cost: i64 = 0;
+    let mut conn = ctx.state.conn.clone();
+    let mut done = false;
+    while !done {
+        match body.data().await {
+            Some(Ok(chunk)) => pending.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                return bad_request_response(e.into());
+            }
+            // whatever is left is the last line, even without a trailing newline
+            None => {
+                pending.push(b'\n');
+                done = true;
+            }
+        }
+        let end = match pending.iter().rposition(|&b| b == b'\n') {
+            Some(end) => end,
+            None if pending.len() > ctx.state.config.max_body_bytes => {
+                return payload_too_large_error();
+            }
+            None => continue,
+        };
+        let rest = pending.split_off(end + 1);
+        let lines = std::mem::replace(&mut pending, rest);
+        for line in lines.split(|&b| b == b'\n') {
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            let entry: database::ExportEntry = match serde_json::from_slice(line) {
+                Ok(v) => v,
+                Err(e) => {
+                    return bad_request_response(e.into());
+                }
+            };
+            let key = entry.key.clone();
+            // pinning by CID hands out whatever the blob holds, so it is only for admins
+            // or blobs the namespace already has
+            if let (Some(cid), false) = (&entry.cid, is_admin) {
+                if referenced.is_none() {
+                    match referenced_blobs(&pcr, &ctx.state).await {
+                        Ok((blobs, scan_cost)) => {
+                            referenced = Some(blobs);
+                            cost = cost.saturating_add(scan_cost);
+                        }
+                        Err(e) => {
+                            update_cost(pcr.to_owned(), cost, &ctx.state.cost_map).await;
+                            return storage_error_response(
+                                e,
+                                "import",
+                                &pcr,
+                                &key,
+                                &ctx.state.config,
+                            );
+                        }
+                    }
+                }
+                if !referenced
+                    .as_ref()
+                    .map_or(false, |blobs| blobs.contains(cid))
+                {
+                    update_cost(pcr.to_owned(), cost, &ctx.state.cost_map).await;
+                    return forbidden_error();
+                }
+            }
+            let result = database::import_entry(
+                pcr.to_owned(),
+                entry,
+                mode,
+                &ctx.state.blob_cache,
+                &mut conn,
+                &ctx.state.config,
+            )
+            .await;
+            match result {
+                Ok((true, entry_cost)) => {
+                    resp.imported += 1;
+                    cost = cost.saturating_add(entry_cost);
+                }
+                Ok((false, _)) => resp.skipped += 1,
+                Err(e) => {
+                    update_cost(pcr.to_owned(), cost, &ctx.state.cost_map).await;
+                    return storage_error_response(e, "import", &pcr, &key, &ctx.state.config);
+                }
+            }
+        }
+    }
+    update_cost(pcr, cost, &ctx.state.cost_map).await;
+    return json_response(&resp);
+}
+
+/// Waits for the next change to a key, or for the timeout to pass.
+pub async fn watch(mut ctx: Context) -> Response {
+    let body: WatchRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    // subscribe before waiting so a change made meanwhile isn't missed
+    let pubsub = match database::watch(&pcr, &body.key, &ctx.state.config).await {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "watch", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    update_cost(
+        pcr.to_owned(),
+        ctx.state.config.operation_b_cost,
+        &ctx.state.cost_map,
+    )
+    .await;
+
+    let timeout = cmp::min(body.timeout_ms, ctx.state.config.max_watch_timeout);
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    let mut messages = Box::pin(pubsub.into_on_message());
+    let event = loop {
+        let msg = match tokio::time::timeout_at(deadline, messages.next()).await {
+            Ok(Some(msg)) => msg,
+            _ => {
+                return json_response(&WatchResponse {
+                    changed: false,
+                    event: None,
+                    info: None,
+                });
+            }
+        };
+        if let Some(event) = database::key_event(&pcr, &msg, &ctx.state.config) {
+            break event;
+        }
+    };
+
+    let mut conn = ctx.state.conn.clone();
+    let info = match database::stat(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await {
+        Ok(value) => Some(value.0),
+        Err(StorageError::NotFound) => None,
+        Err(e) => {
+            return storage_error_response(e, "watch", &pcr, &body.key, &ctx.state.config);
+        }
+    };
+    return json_response(&WatchResponse {
+        changed: true,
+        event: Some(event.event),
+        info,
+    });
+}
+
+/// Redis memory used per namespace, for the pcr in the body or for every namespace.
+pub async fn memory_usage(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: MemoryUsageRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcrs = match body.pcr {
+        Some(pcr) => {
+            if !is_valid_pcr(&pcr) {
+                return bad_request_response(
+                    format!("pcr must be {} hex characters", PCR_HEX_LEN).into(),
+                );
+            }
+            vec![pcr.to_ascii_lowercase()]
+        }
+        None => match all_namespaces(&ctx.state).await {
+            Ok(value) => value,
+            Err(e) => {
+                return storage_error_response(e, "memory_usage", "", "", &ctx.state.config);
+            }
+        },
+    };
+    let mut usage = BTreeMap::new();
+    for pcr in pcrs {
+        match namespace_memory_usage(&pcr, &ctx.state).await {
+            Ok(value) => usage.insert(pcr, value),
+            Err(e) => {
+                return storage_error_response(e, "memory_usage", &pcr, "", &ctx.state.config);
+            }
+        };
+    }
+    return json_response(&usage);
+}
+
+/// The memory used by one namespace, a SCAN page at a time.
+async fn namespace_memory_usage(
+    pcr: &String,
+    state: &AppState,
+) -> Result<database::MemoryUsage, StorageError> {
+    let mut usage = database::MemoryUsage::default();
+    let mut cursor = Some(0);
+    let mut conn = state.conn.clone();
+    while let Some(next) = cursor {
+        cursor =
+            database::memory_usage_step(pcr, next, &mut usage, &mut conn, &state.config).await?;
+    }
+    Ok(usage)
+}
+
+/// Every namespace that holds a key, a SCAN page at a time.
+async fn all_namespaces(state: &AppState) -> Result<Vec<String>, StorageError> {
+    let mut namespaces = BTreeSet::new();
+    let mut cursor = Some(0);
+    let mut conn = state.conn.clone();
+    while let Some(next) = cursor {
+        cursor = database::namespaces_step(next, &mut namespaces, &mut conn, &state.config).await?;
+    }
+    Ok(namespaces.into_iter().collect())
+}
+
+pub async fn namespaces(ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let namespaces = match all_namespaces(&ctx.state).await {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "namespaces", "", "", &ctx.state.config);
+        }
+    };
+    return json_response(&NamespacesResponse { namespaces });
+}
+
+/// The configuration the server is running with.
+pub async fn get_config(ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    match redacted_config(&ctx.state.config) {
+        Ok(config) => json_response(&config),
+        Err(e) => {
+            error!("could not serialize the config: {}", e);
+            internal_server_error()
+        }
+    }
+}
+
+/// `config` as JSON with the secrets that are set replaced by `REDACTED`, so empty
+/// ones still show as unset.
+fn redacted_config(config: &Config) -> Result<serde_json::Value, serde_json::Error> {
+    let mut value = serde_json::to_value(config)?;
+    let redact = |secret: &mut serde_json::Value| {
+        if secret.as_str().map_or(false, |s| !s.is_empty()) {
+            *secret = REDACTED.into();
+        }
+    };
+    for field in SECRET_CONFIG_FIELDS {
+        if let Some(secret) = value.get_mut(*field) {
+            redact(secret);
+        }
+    }
+    if let Some(serde_json::Value::Object(tokens)) = value.get_mut("api_tokens") {
+        tokens.values_mut().for_each(redact);
+    }
+    Ok(value)
+}
+
+/// Removes everything stored for the pcr in the body, for offboarding a tenant.
+pub async fn evict(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: EvictRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    if !is_valid_pcr(&body.pcr) {
+        return bad_request_response(format!("pcr must be {} hex characters", PCR_HEX_LEN).into());
+    }
+    let pcr = body.pcr.to_ascii_lowercase();
+    let mut eviction = database::Eviction::default();
+    let mut conn = ctx.state.conn.clone();
+    loop {
+        match database::evict_step(
+            &pcr,
+            &mut eviction,
+            &ctx.state.blob_cache,
+            &mut conn,
+            &ctx.state.config,
+        )
+        .await
+        {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => {
+                return storage_error_response(e, "evict", &pcr, "", &ctx.state.config);
+            }
+        }
+    }
+    let removed = eviction.removed;
+    info!(pcr = %pcr, removed, "evicted namespace");
+    return json_response(&EvictResponse { removed });
+}
+
+/// Lists the locks held in the namespace of the pcr in the body.
+pub async fn list_locks(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: ListLocksRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    if !is_valid_pcr(&body.pcr) {
+        return bad_request_response(format!("pcr must be {} hex characters", PCR_HEX_LEN).into());
+    }
+    let pcr = body.pcr.to_ascii_lowercase();
+    let mut conn = ctx.state.conn.clone();
+
+    let locks = match database::list_locks(pcr.to_owned(), &mut conn, &ctx.state.config).await {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "list_locks", &pcr, "", &ctx.state.config);
+        }
+    };
+    return json_response(&ListLocksResponse { locks });
+}
+
+/// Releases a lock regardless of its lock_id, for clearing one left by a job that
+/// died while holding it.
+pub async fn force_unlock(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: ForceUnlockRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    if !is_valid_pcr(&body.pcr) {
+        return bad_request_response(format!("pcr must be {} hex characters", PCR_HEX_LEN).into());
+    }
+    let pcr = body.pcr.to_ascii_lowercase();
+    let mut conn = ctx.state.conn.clone();
+
+    let released =
+        match database::force_unlock(pcr.to_owned(), &body.key, &mut conn, &ctx.state.config).await
+        {
+            Ok(value) => value,
+            Err(e) => {
+                return storage_error_response(
+                    e,
+                    "force_unlock",
+                    &pcr,
+                    &body.key,
+                    &ctx.state.config,
+                );
+            }
+        };
+    info!(pcr = %pcr, key = %body.key, released, "force unlocked");
+    return json_response(&ForceUnlockResponse { released });
+}
+
+/// Sets the offload threshold for the namespace in the `pcr` header.
+pub async fn set_mem_threshold(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: MemThresholdRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    if let Err(e) = database::set_mem_threshold(pcr.to_owned(), body.mem_threshold, &mut conn).await
+    {
+        return storage_error_response(e, "set_mem_threshold", &pcr, "", &ctx.state.config);
+    }
+    return Response::default();
+}
+
+#[derive(Serialize)]
+pub struct CostFlushResponse {
+    costs: HashMap<String, i64>,
+}
+
+/// Returns the cost accumulated by every pcr since the last call and resets it, for
+/// the billing poller.
+pub async fn flush_cost(ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    // include what hasn't reached Redis yet
+    if let Err(e) = flush_costs(&ctx.state).await {
+        return storage_error_response(e, "flush_cost", "", "", &ctx.state.config);
+    }
+    let mut conn = ctx.state.conn.clone();
+    let costs = match database::take_costs(&mut conn).await {
+        Ok(value) => value,
+        Err(e) => {
+            return storage_error_response(e, "flush_cost", "", "", &ctx.state.config);
+        }
+    };
+    return json_response(&CostFlushResponse { costs });
+}
+
+#[derive(Deserialize)]
+pub struct RateLimitRequest {
+    /// requests per second, `None` to go back to `config.rate_limit_per_sec`
+    rate_limit: Option<u64>,
+}
+
+/// Sets the request rate for the namespace in the `pcr` header. Servers pick the
+/// change up within a few seconds.
+pub async fn set_rate_limit(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: RateLimitRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    if let Err(e) = database::set_rate_limit(pcr.to_owned(), body.rate_limit, &mut conn).await {
+        return storage_error_response(e, "set_rate_limit", &pcr, "", &ctx.state.config);
+    }
+    ctx.state.rate_limits.remove(&pcr);
+    return Response::default();
+}
+
+#[derive(Deserialize)]
+pub struct ApiTokenRequest {
+    /// bearer token clients of the namespace must present, null to remove it
+    api_token: Option<String>,
+}
+
+/// Sets the API token for the namespace in the `pcr` header. A token in
+/// `api_tokens` takes precedence over it.
+pub async fn set_api_token(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: ApiTokenRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    if let Err(e) = database::set_api_token(pcr.to_owned(), body.api_token, &mut conn).await {
+        return storage_error_response(e, "set_api_token", &pcr, "", &ctx.state.config);
+    }
+    // other instances pick the change up once their cached hash goes stale
+    ctx.state.api_tokens.remove(&pcr);
+    return Response::default();
+}
+
+/// Sets how many previous values the namespace in the `pcr` header keeps per key.
+pub async fn set_history_depth(mut ctx: Context) -> Response {
+    if let Err(resp) = check_admin(&ctx.req, &ctx.state.config) {
+        return resp;
+    }
+    let body: HistoryDepthRequest = match ctx.body_json().await {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let pcr = match get_pcr(&ctx.req, &ctx.state.config) {
+        Ok(v) => v,
+        Err(e) => {
+            return bad_request_response(e);
+        }
+    };
+    let mut conn = ctx.state.conn.clone();
+
+    if let Err(e) = database::set_history_depth(pcr.to_owned(), body.history_depth, &mut conn).await
+    {
+        return storage_error_response(e, "set_history_depth", &pcr, "", &ctx.state.config);
+    }
+    return Response::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+    use std::sync::Arc;
+    use tracing_subscriber::{reload, EnvFilter};
+
+    const TEST_PCR: &str = "000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
+
+    async fn test_state(config: Config) -> Result<Arc<AppState>, Box<dyn Error>> {
+        let (_, log_handle) = reload::Layer::new(EnvFilter::new("info"));
+        let in_flight = in_flight_limit(config.max_in_flight);
+        Ok(Arc::new(AppState {
+            conn: database::connect(&config).await?,
+            config,
+            cost_map: Mutex::new(HashMap::new()),
+            log_handle,
+            ready: AtomicBool::new(true),
+            blob_cache: BlobCache::default(),
+            rate_limiter: RateLimiter::default(),
+            op_counter: OpCounter::default(),
+            api_tokens: api_token_cache(),
+            rate_limits: rate_limit_cache(),
+            in_flight,
+        }))
+    }
+
+    fn test_context(
+        state: &Arc<AppState>,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: serde_json::Value,
+    ) -> Result<Context, Box<dyn Error>> {
+        let mut req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(path)
+            .header("pcr", TEST_PCR);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.body(Body::from(body.to_string()))?;
+        Ok(Context::new(state.clone(), req, Params::new()))
+    }
+
+    async fn body_json(resp: Response) -> Result<serde_json::Value, Box<dyn Error>> {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    fn pcr_request(pcr: &str) -> Result<hyper::Request<Body>, Box<dyn Error>> {
+        Ok(hyper::Request::builder()
+            .header("pcr", pcr)
+            .body(Body::empty())?)
+    }
+
+    #[test]
+    fn test_get_pcr() -> Result<(), Box<dyn Error>> {
+        assert_eq!(TEST_PCR, read_pcr(&pcr_request(TEST_PCR)?)?);
+        // upper case hex maps onto the same namespace
+        assert_eq!(
+            TEST_PCR.replace('0', "a"),
+            read_pcr(&pcr_request(&TEST_PCR.replace('0', "A"))?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_pcr_allowlist() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        assert!(get_pcr(&pcr_request(TEST_PCR)?, &config).is_ok());
+        config.allowed_pcrs = vec![TEST_PCR.to_ascii_uppercase()];
+        assert_eq!(TEST_PCR, get_pcr(&pcr_request(TEST_PCR)?, &config)?);
+        let err = get_pcr(&pcr_request(&"1".repeat(PCR_HEX_LEN))?, &config).unwrap_err();
+        assert!(err.is::<PcrNotAllowed>());
+        assert_eq!(StatusCode::FORBIDDEN, bad_request_response(err).status());
+        Ok(())
+    }
+
+    #[test]
+    fn test_redacted_config() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.s3_secret_key = "secret".to_string();
+        config
+            .api_tokens
+            .insert(TEST_PCR.to_string(), "token".to_string());
+        let value = redacted_config(&config)?;
+        assert_eq!(REDACTED, value["s3_secret_key"]);
+        assert_eq!(REDACTED, value["api_tokens"][TEST_PCR]);
+        // unset secrets are shown as such
+        assert_eq!("", value["admin_token"]);
+        assert_eq!(config.max_tree_keys, value["max_tree_keys"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_flight_limit() {
+        let limit = in_flight_limit(1);
+        let permit = limit.clone().try_acquire_owned();
+        assert!(permit.is_ok());
+        assert!(limit.clone().try_acquire_owned().is_err());
+        drop(permit);
+        assert!(limit.clone().try_acquire_owned().is_ok());
+        assert_eq!(
+            Semaphore::MAX_PERMITS,
+            in_flight_limit(0).available_permits()
+        );
+    }
+
+    #[test]
+    fn test_lock_deadline() {
+        let mut config = Config::default();
+        config.retry_count = 3;
+        config.retry_delay = 100;
+        config.max_lock_expiry = 1000;
+        let now = Instant::now();
+        // never shorter than the retries, never longer than max_lock_expiry
+        assert!(lock_deadline(None, &config) >= now + Duration::from_millis(200));
+        assert!(lock_deadline(Some(10), &config) < now + Duration::from_millis(1000));
+        assert!(lock_deadline(Some(60000), &config) >= now + Duration::from_millis(1000));
+        assert!(lock_deadline(Some(60000), &config) < now + Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn test_overloaded_error() {
+        let resp = overloaded_error();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!("1", resp.headers()[header::RETRY_AFTER]);
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let mut config = Config::default();
+        config.request_timeout_ms = 1000;
+        config.max_watch_timeout = 5000;
+        assert_eq!(
+            Some(Duration::from_millis(1000)),
+            request_timeout("/load", &config)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(6000)),
+            request_timeout("/watch", &config)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(1000 + config.max_lock_expiry)),
+            request_timeout("/lock", &config)
+        );
+        assert_eq!(None, request_timeout("/import", &config));
+        assert_eq!(None, request_timeout("/subscribe", &config));
+        config.request_timeout_ms = 0;
+        assert_eq!(None, request_timeout("/load", &config));
+    }
+
+    #[test]
+    fn test_bearer_token() -> Result<(), Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, bearer_token(&headers));
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse()?);
+        assert_eq!(Some("secret"), bearer_token(&headers));
+        headers.insert(header::AUTHORIZATION, "bearer secret".parse()?);
+        assert_eq!(Some("secret"), bearer_token(&headers));
+        headers.insert(header::AUTHORIZATION, "Basic c2VjcmV0".parse()?);
+        assert_eq!(None, bearer_token(&headers));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config
+            .api_tokens
+            .insert(TEST_PCR.to_string(), "secret".to_string());
+        let state = test_state(config).await?;
+        let request = |token: Option<&str>, path: &str| {
+            let mut req = hyper::Request::builder().uri(path).header("pcr", TEST_PCR);
+            if let Some(token) = token {
+                req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            req.body(Body::empty())
+        };
+        assert!(authenticate(&request(Some("secret"), "/load")?, &state)
+            .await
+            .is_none());
+        let resp = authenticate(&request(Some("wrong"), "/load")?, &state)
+            .await
+            .ok_or("wrong token accepted")?;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert!(resp.headers().contains_key(header::WWW_AUTHENTICATE));
+        assert!(authenticate(&request(None, "/load")?, &state)
+            .await
+            .is_some());
+        // admin endpoints only check admin_token
+        assert!(authenticate(&request(None, "/admin/rate_limit")?, &state)
+            .await
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_admin() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        let request = |token: &str| {
+            hyper::Request::builder()
+                .uri("/admin/namespaces")
+                .header("admin-token", token)
+                .body(Body::empty())
+        };
+        // disabled without a token, even for an empty one
+        assert!(check_admin(&request("")?, &config).is_err());
+        config.admin_token = "secret".to_string();
+        assert!(check_admin(&request("secret")?, &config).is_ok());
+        assert!(check_admin(&request("secre")?, &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_pcr_rejects_collisions() -> Result<(), Box<dyn Error>> {
+        // "a/b" + "c" and "a" + "b/c" would otherwise both map to "a/b/c"
+        assert!(read_pcr(&pcr_request("a/b")?).is_err());
+        assert!(read_pcr(&pcr_request("a")?).is_err());
+        let nested = format!("{}/{}", &TEST_PCR[..47], &TEST_PCR[..48]);
+        assert!(read_pcr(&pcr_request(&nested)?).is_err());
+        // a lock namespace suffix can't be smuggled in either
+        let locked = format!("{}.lock", &TEST_PCR[..91]);
+        assert!(read_pcr(&pcr_request(&locked)?).is_err());
+        assert!(read_pcr(&pcr_request(&TEST_PCR[..95])?).is_err());
+        assert!(read_pcr(&pcr_request(&(TEST_PCR.to_string() + "0"))?).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() -> Result<(), Box<dyn Error>> {
+        let mut config = Config::default();
+        config.max_body_bytes = 64;
+        let state = test_state(config).await?;
+        let resp = store(test_context(
+            &state,
+            "/store",
+            &[],
+            serde_json::json!({"key": "test_body_too_large", "value": "x".repeat(100), "expiry": 1000}),
+        )?)
+        .await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, resp.status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_cost_saturates() {
+        let cost_map = Mutex::new(HashMap::new());
+        update_cost(String::from("pcr"), i64::MAX - 1, &cost_map).await;
+        update_cost(String::from("pcr"), i64::MAX - 1, &cost_map).await;
+        assert_eq!(i64::MAX, cost_map.lock().await["pcr"]);
+    }
+
+    #[tokio::test]
+    async fn test_storage_error_body() -> Result<(), Box<dyn Error>> {
+        let config = Config::default();
+        let resp = storage_error_response(StorageError::NotFound, "load", "pcr", "k", &config);
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!("not_found", body_json(resp).await?["code"]);
+
+        let e = StorageError::Io(std::io::Error::new(std::io::ErrorKind::Other, "secret"));
+        let resp = storage_error_response(e, "load", "pcr", "k", &config);
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, resp.status());
+        let body = body_json(resp).await?;
+        assert_eq!("internal", body["code"]);
+        assert_eq!("internal error", body["message"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("br, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_raw_content_type() {
+        let raw = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, value.parse().unwrap());
+            raw_content_type(&headers)
+        };
+        assert_eq!(Some(TEXT_CONTENT_TYPE), raw("text/plain"));
+        assert_eq!(
+            Some("application/octet-stream"),
+            raw("application/json;q=0.5, application/octet-stream")
+        );
+        assert_eq!(None, raw("application/json, text/plain"));
+        assert_eq!(None, raw("*/*"));
+        assert_eq!(None, raw("text/plain;q=0"));
+        assert_eq!(None, raw_content_type(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_response() -> Result<(), Box<dyn Error>> {
+        let body = "x".repeat(2048);
+        let resp = gzip_response(Response::new(body.clone().into()), 1024).await;
+        assert_eq!("gzip", resp.headers()[header::CONTENT_ENCODING]);
+        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&bytes[..]), &mut decoded)?;
+        assert_eq!(body, decoded);
+
+        let resp = gzip_response(Response::new("small".into()), 1024).await;
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lock_id_hex_round_trip() -> Result<(), Box<dyn Error>> {
+        let state = test_state(Config::default()).await?;
+        let resp = lock(test_context(
+            &state,
+            "/lock",
+            &[],
+            serde_json::json!({"key": "test_lock_id_hex_round_trip"}),
+        )?)
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let lock_id = body_json(resp).await?["lock_id"]
+            .as_str()
+            .ok_or("lock_id is not a string")?
+            .to_string();
+        assert_eq!(40, lock_id.len());
+
+        let resp = unlock(test_context(
+            &state,
+            "/unlock",
+            &[],
+            serde_json::json!({"key": "test_lock_id_hex_round_trip", "lock_id": lock_id}),
+        )?)
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("released", body_json(resp).await?["result"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_store_response() -> Result<(), Box<dyn Error>> {
+        let state = test_state(Config::default()).await?;
+        let resp = store(test_context(
+            &state,
+            "/store",
+            &[],
+            serde_json::json!({"key": "test_store_response", "value": "value", "expiry": 10000}),
+        )?)
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = body_json(resp).await?;
+        assert_eq!(5, body["size"]);
+        assert_eq!(false, body["ipfs"]);
+        assert!(body["modified"].as_i64().unwrap_or_default() > 0);
+        assert!(body["cost"].as_i64().unwrap_or_default() >= state.config.operation_c_cost);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_not_modified() -> Result<(), Box<dyn Error>> {
+        let state = test_state(Config::default()).await?;
+        let resp = store(test_context(
+            &state,
+            "/store",
+            &[],
+            serde_json::json!({"key": "test_list_not_modified/a", "value": "value", "expiry": 10000}),
+        )?)
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        let list_body =
+            serde_json::json!({"prefix": "test_list_not_modified/", "is_recursive": true});
+        let resp = list(test_context(&state, "/list", &[], list_body.clone())?).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let checksum = body_json(resp).await?["checksum"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let resp = list(test_context(
+            &state,
+            "/list",
+            &[("if-none-match", checksum.as_str())],
+            list_body,
+        )?)
+        .await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_get() -> Result<(), Box<dyn Error>> {
+        let state = test_state(Config::default()).await?;
+        let resp = store(test_context(
+            &state,
+            "/store",
+            &[],
+            serde_json::json!({"key": "test_kv_get/a b", "value": "value", "expiry": 10000}),
+        )?)
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        let mut ctx = test_context(&state, "/kv/test_kv_get/a%20b", &[], serde_json::json!({}))?;
+        ctx.params
+            .insert(String::from("key"), String::from("test_kv_get/a%20b"));
+        let resp = kv_get(ctx).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(resp.headers().contains_key(header::ETAG));
+        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+        assert_eq!(&b"value"[..], &bytes[..]);
+
+        let mut ctx = test_context(&state, "/kv/test_kv_get/c", &[], serde_json::json!({}))?;
+        ctx.params
+            .insert(String::from("key"), String::from("test_kv_get/c"));
+        assert_eq!(StatusCode::NOT_FOUND, kv_get(ctx).await.status());
+        Ok(())
+    }
+
+    fn kv_context(
+        state: &Arc<AppState>,
+        key: &str,
+        query: &str,
+        body: &str,
+    ) -> Result<Context, Box<dyn Error>> {
+        let req = hyper::Request::builder()
+            .uri(format!("/kv/{}{}", key, query))
+            .header("pcr", TEST_PCR)
+            .body(Body::from(body.to_string()))?;
+        let mut params = Params::new();
+        params.insert(String::from("key"), String::from(key));
+        Ok(Context::new(state.clone(), req, params))
+    }
+
+    #[tokio::test]
+    async fn test_kv_routes() -> Result<(), Box<dyn Error>> {
+        let state = test_state(Config::default()).await?;
+        let key = "test_kv_routes/a";
+        // the expiry is required
+        let resp = kv_put(kv_context(&state, key, "", "value")?).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let resp = kv_put(kv_context(&state, key, "?expiry=10000", "value")?).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(5, body_json(resp).await?["size"]);
+
+        let resp = kv_head(kv_context(&state, key, "", "")?).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("5", resp.headers()["X-Value-Size"]);
+        let resp = kv_get(kv_context(&state, key, "", "")?).await;
+        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+        assert_eq!(&b"value"[..], &bytes[..]);
+
+        let resp = kv_delete(kv_context(&state, key, "", "")?).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = kv_head(kv_context(&state, key, "", "")?).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata() -> Result<(), Box<dyn Error>> {
+        let state = test_state(Config::default()).await?;
+        let key = "test_metadata/index.html";
+        let resp = store(test_context(
+            &state,
+            "/store",
+            &[],
+            serde_json::json!({
+                "key": key,
+                "value": "<p>hi</p>",
+                "expiry": 10000,
+                "metadata": {"content_type": "text/html", "cache_control": "no-cache"}
+            }),
+        )?)
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        let resp = load(test_context(
+            &state,
+            "/load",
+            &[],
+            serde_json::json!({"key": key}),
+        )?)
+        .await;
+        let body = body_json(resp).await?;
+        assert_eq!("text/html", body["metadata"]["content_type"]);
+        assert_eq!("no-cache", body["metadata"]["cache_control"]);
+        let resp = kv_get(kv_context(&state, key, "", "")?).await;
+        assert_eq!("text/html", resp.headers()[header::CONTENT_TYPE]);
+        let resp = kv_head(kv_context(&state, key, "", "")?).await;
+        assert_eq!("text/html", resp.headers()[header::CONTENT_TYPE]);
+        Ok(())
+    }
 }
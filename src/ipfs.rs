@@ -1,10 +1,12 @@
 use crate::Config;
 use base64::{engine::general_purpose, Engine as _};
+use hyper::body::HttpBody;
 use hyper::{header, Body, Client, Request};
 use hyper_tls::HttpsConnector;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io::Write;
+use tracing::{debug, error};
 use url::Url;
 #[derive(Serialize, Deserialize, Debug)]
 struct AddResponse {
@@ -12,21 +14,56 @@ struct AddResponse {
     Hash: String,
     Size: String,
 }
-pub async fn add(data: String, config: &Config) -> Result<String, Box<dyn Error>> {
-    println!("adding to ipfs {}", data);
+/// Adds and pins `payload`, returning its CID.
+pub async fn add(payload: Vec<u8>, config: &Config) -> Result<String, Box<dyn Error>> {
+    debug!(bytes = payload.len(), "adding to ipfs");
+    add_stream(payload.into(), config).await
+}
+
+/// Like `add`, but forwards `payload` into the multipart upload as it arrives
+/// rather than assembling the whole request in memory first.
+pub async fn add_stream(mut payload: Body, config: &Config) -> Result<String, Box<dyn Error>> {
     let boundary = "----WebKitFormBoundaryP7QTR7KAEBq0gxMo";
-    let mut bodydata = Vec::new();
-    write!(bodydata, "--{}\r\n", boundary)?;
+    let mut head = Vec::new();
+    write!(head, "--{}\r\n", boundary)?;
     write!(
-        bodydata,
+        head,
         "Content-Disposition: form-data; name=\"file\"; filename=\"blob\"\r\n"
     )?;
-    write!(bodydata, "Content-Type: application/octet-stream\r\n")?;
-    write!(bodydata, "\r\n")?;
-    write!(bodydata, "{}", data)?;
-    write!(bodydata, "\r\n")?;
-    write!(bodydata, "--{}--\r\n", boundary)?;
-    let url = Url::parse(&(config.ipfs_url.clone() + "add"))?;
+    write!(head, "Content-Type: application/octet-stream\r\n")?;
+    write!(head, "\r\n")?;
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    let (mut sender, bodydata) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(head.into()).await.is_err() {
+            return;
+        }
+        while let Some(chunk) = payload.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                // fail the upload rather than add a truncated value
+                Err(_) => return sender.abort(),
+            };
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send_data(tail.into()).await;
+    });
+    let mut url = Url::parse(&(config.ipfs_url.clone() + "add"))?;
+    // the daemon picks v0 by default, get and delete take either version
+    if config.ipfs_cid_version != 0 {
+        url.query_pairs_mut()
+            .append_pair("cid-version", &config.ipfs_cid_version.to_string());
+    }
+    // left to the daemon when empty. a hash other than sha2-256 implies CIDv1
+    if !config.ipfs_hash.is_empty() {
+        url.query_pairs_mut().append_pair("hash", &config.ipfs_hash);
+    }
+    if !config.ipfs_chunker.is_empty() {
+        url.query_pairs_mut()
+            .append_pair("chunker", &config.ipfs_chunker);
+    }
 
     let https = HttpsConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(https);
@@ -43,22 +80,57 @@ pub async fn add(data: String, config: &Config) -> Result<String, Box<dyn Error>
                     .encode(format!("{}:{}", config.ipfs_key, config.ipfs_secret))
             ),
         )
-        .body(bodydata.into())?;
+        .body(bodydata)?;
     let resp = client.request(request).await?;
-    println!("response {:?}", resp);
     if resp.status() == http::StatusCode::OK {
         let bytes = hyper::body::to_bytes(resp.into_body()).await?;
         let value: AddResponse = serde_json::from_slice(&bytes)?;
-        println!("addedto ipfs {}", value.Hash);
+        debug!(cid = %value.Hash, "added to ipfs");
         return Ok(value.Hash);
     }
+    error!(status = %resp.status(), "ipfs add failed");
     return Err("NON 200 status".into());
 }
 
-pub async fn delete(key: String, config: &Config) -> Result<(), Box<dyn Error>> {
+pub async fn delete(key: &str, config: &Config) -> Result<(), Box<dyn Error>> {
     let mut url = Url::parse(&(config.ipfs_url.clone() + "pin/rm"))?;
-    println!("deleting from ipfs {}", key);
-    url.query_pairs_mut().append_pair("arg", &key);
+    debug!(cid = %key, "deleting from ipfs");
+    url.query_pairs_mut().append_pair("arg", key);
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let request = Request::post(url.as_str())
+        .header(
+            header::AUTHORIZATION,
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD_NO_PAD
+                    .encode(format!("{}:{}", config.ipfs_key, config.ipfs_secret))
+            ),
+        )
+        .body(Body::empty())?;
+    let resp = client.request(request).await?;
+
+    let status = resp.status();
+    if status == http::StatusCode::OK || status == http::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    // unpinning something that isn't pinned comes back as a 500 with this message,
+    // which means the delete already happened
+    let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+    if is_not_pinned(&bytes) {
+        debug!(cid = %key, "already unpinned");
+        return Ok(());
+    }
+    error!(cid = %key, status = %status, "ipfs delete failed");
+    return Err("NON 200 status".into());
+}
+
+/// Pins `key`, fetching it from the network if the node doesn't have it yet.
+pub async fn pin(key: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut url = Url::parse(&(config.ipfs_url.clone() + "pin/add"))?;
+    debug!(cid = %key, "pinning in ipfs");
+    url.query_pairs_mut().append_pair("arg", key);
 
     let https = HttpsConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(https);
@@ -77,14 +149,56 @@ pub async fn delete(key: String, config: &Config) -> Result<(), Box<dyn Error>>
     if resp.status() == http::StatusCode::OK {
         return Ok(());
     }
+    error!(cid = %key, status = %resp.status(), "ipfs pin failed");
+    return Err("NON 200 status".into());
+}
+
+#[derive(Deserialize, Debug)]
+struct StatResponse {
+    #[serde(rename = "Size")]
+    size: usize,
+}
+
+/// The size of the content of `key` as added, without fetching it.
+pub async fn stat(key: &str, config: &Config) -> Result<usize, Box<dyn Error>> {
+    let mut url = Url::parse(&(config.ipfs_url.clone() + "files/stat"))?;
+    debug!(cid = %key, "stating in ipfs");
+    url.query_pairs_mut()
+        .append_pair("arg", &format!("/ipfs/{}", key));
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let request = Request::post(url.as_str())
+        .header(
+            header::AUTHORIZATION,
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD_NO_PAD
+                    .encode(format!("{}:{}", config.ipfs_key, config.ipfs_secret))
+            ),
+        )
+        .body(Body::empty())?;
+    let resp = client.request(request).await?;
+
+    if resp.status() == http::StatusCode::OK {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await?;
+        let stat: StatResponse = serde_json::from_slice(&bytes)?;
+        return Ok(stat.size);
+    }
+    error!(cid = %key, status = %resp.status(), "ipfs stat failed");
     return Err("NON 200 status".into());
 }
 
-pub async fn get(key: String, config: &Config) -> Result<String, Box<dyn Error>> {
-    println!("getting from ipfs {}", key);
+fn is_not_pinned(body: &[u8]) -> bool {
+    String::from_utf8_lossy(body).contains("not pinned")
+}
+
+/// Fetches the content of `key` as added.
+pub async fn get(key: &str, config: &Config) -> Result<Vec<u8>, Box<dyn Error>> {
+    debug!(cid = %key, "getting from ipfs");
     let mut url = Url::parse(&(config.ipfs_url.clone() + "cat"))?;
 
-    url.query_pairs_mut().append_pair("arg", &key);
+    url.query_pairs_mut().append_pair("arg", key);
 
     let https = HttpsConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(https);
@@ -99,10 +213,87 @@ pub async fn get(key: String, config: &Config) -> Result<String, Box<dyn Error>>
         )
         .body(Body::empty())?;
     let resp = client.request(request).await?;
-    println!("response {:?}", resp);
     if resp.status() == http::StatusCode::OK {
         let bytes = hyper::body::to_bytes(resp.into_body()).await?;
-        return Ok(String::from_utf8(bytes.to_vec())?);
+        return Ok(bytes.to_vec());
+    }
+    error!(cid = %key, status = %resp.status(), "ipfs get failed");
+    return Err("NON 200 status".into());
+}
+
+/// Like `get`, but hands back the response body as it arrives instead of buffering it.
+pub async fn get_stream(key: &str, config: &Config) -> Result<Body, Box<dyn Error>> {
+    debug!(cid = %key, "streaming from ipfs");
+    let mut url = Url::parse(&(config.ipfs_url.clone() + "cat"))?;
+
+    url.query_pairs_mut().append_pair("arg", key);
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let request = Request::post(url.as_str())
+        .header(
+            header::AUTHORIZATION,
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD_NO_PAD
+                    .encode(format!("{}:{}", config.ipfs_key, config.ipfs_secret))
+            ),
+        )
+        .body(Body::empty())?;
+    let resp = client.request(request).await?;
+    if resp.status() == http::StatusCode::OK {
+        return Ok(resp.into_body());
+    }
+    error!(cid = %key, status = %resp.status(), "ipfs get failed");
+    return Err("NON 200 status".into());
+}
+
+pub async fn version(config: &Config) -> Result<(), Box<dyn Error>> {
+    let url = Url::parse(&(config.ipfs_url.clone() + "version"))?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let request = Request::post(url.as_str())
+        .header(
+            header::AUTHORIZATION,
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD_NO_PAD
+                    .encode(format!("{}:{}", config.ipfs_key, config.ipfs_secret))
+            ),
+        )
+        .body(Body::empty())?;
+    let resp = client.request(request).await?;
+
+    if resp.status() == http::StatusCode::OK {
+        return Ok(());
     }
     return Err("NON 200 status".into());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_not_pinned() {
+        assert!(is_not_pinned(
+            br#"{"Message":"not pinned or pinned indirectly","Code":0,"Type":"error"}"#
+        ));
+        assert!(!is_not_pinned(
+            br#"{"Message":"invalid path","Code":0,"Type":"error"}"#
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_double_delete() -> Result<(), Box<dyn Error>> {
+        // needs a local ipfs node
+        let mut config = Config::default();
+        config.ipfs_url = String::from("http://127.0.0.1:5001/api/v0/");
+        let cid = add(b"test_double_delete".to_vec(), &config).await?;
+        delete(&cid, &config).await?;
+        delete(&cid, &config).await?;
+        Ok(())
+    }
+}
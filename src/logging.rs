@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::Duration;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Layer, Registry};
+
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// target of access log events, so `RUST_LOG` can filter them apart from the rest
+pub const ACCESS_TARGET: &str = "access";
+
+/// Level access log events are emitted at.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+/// One handled request, logged once its response is ready.
+pub struct AccessLog<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    /// `None` for requests without a valid pcr
+    pub pcr: Option<&'a str>,
+    pub status: u16,
+    pub duration: Duration,
+    pub request_id: &'a str,
+}
+
+pub fn access(level: AccessLogLevel, entry: &AccessLog) {
+    macro_rules! emit {
+        ($level:expr) => {
+            tracing::event!(
+                target: ACCESS_TARGET,
+                $level,
+                method = entry.method,
+                path = entry.path,
+                pcr = entry.pcr.unwrap_or("-"),
+                status = entry.status,
+                duration_ms = entry.duration.as_secs_f64() * 1000.0,
+                request_id = entry.request_id,
+                "request"
+            )
+        };
+    }
+    match level {
+        AccessLogLevel::Trace => emit!(Level::TRACE),
+        AccessLogLevel::Debug => emit!(Level::DEBUG),
+        AccessLogLevel::Info => emit!(Level::INFO),
+        AccessLogLevel::Warn => emit!(Level::WARN),
+        AccessLogLevel::Error => emit!(Level::ERROR),
+    }
+}
+
+/// Installs the global subscriber, reading the initial filter from `RUST_LOG`
+/// (default `info`), and returns a handle to change it at runtime. Spans are
+/// also exported to the OTLP collector at `otlp_endpoint` unless it is empty.
+pub fn init(otlp_endpoint: &str) -> Result<LogHandle, Box<dyn Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(otel_layer(otlp_endpoint)?)
+        .init();
+    Ok(handle)
+}
+
+#[cfg(feature = "otel")]
+fn otel_layer<S>(endpoint: &str) -> Result<Option<Box<dyn Layer<S> + Send + Sync>>, Box<dyn Error>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+{
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+
+    if endpoint.is_empty() {
+        return Ok(None);
+    }
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(Some(
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed(),
+    ))
+}
+
+#[cfg(not(feature = "otel"))]
+fn otel_layer<S>(endpoint: &str) -> Result<Option<Box<dyn Layer<S> + Send + Sync>>, Box<dyn Error>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+{
+    if endpoint.is_empty() {
+        return Ok(None);
+    }
+    Err(OTEL_DISABLED.into())
+}
+
+#[cfg(not(feature = "otel"))]
+const OTEL_DISABLED: &str = "otlp_endpoint is set but built without the otel feature";
+
+/// Sends the spans still batched to the collector.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+pub fn current_level(handle: &LogHandle) -> Result<String, reload::Error> {
+    handle.with_current(|filter| filter.to_string())
+}
+
+pub fn set_level(handle: &LogHandle, level: &str) -> Result<(), Box<dyn Error>> {
+    let filter = EnvFilter::try_new(level)?;
+    handle.reload(filter)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_level() -> Result<(), Box<dyn Error>> {
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let subscriber = tracing_subscriber::registry().with(filter).with(
+            fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, || -> Result<(), Box<dyn Error>> {
+            tracing::debug!("debug_before_change");
+            set_level(&handle, "debug")?;
+            assert_eq!("debug", current_level(&handle)?);
+            tracing::debug!("debug_after_change");
+            set_level(&handle, "info")?;
+            assert_eq!("info", current_level(&handle)?);
+            tracing::debug!("debug_after_reset");
+            Ok(())
+        })?;
+        let logs = String::from_utf8(buf.0.lock().unwrap().clone())?;
+        assert!(!logs.contains("debug_before_change"));
+        assert!(logs.contains("debug_after_change"));
+        assert!(!logs.contains("debug_after_reset"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_access() -> Result<(), Box<dyn Error>> {
+        let buf = SharedBuf::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(EnvFilter::new("info"))
+            .with(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(move || writer.clone()),
+            );
+        let entry = AccessLog {
+            method: "POST",
+            path: "/load",
+            pcr: None,
+            status: 404,
+            duration: Duration::from_millis(3),
+            request_id: "request-id",
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            access(AccessLogLevel::Info, &entry);
+            access(
+                AccessLogLevel::Debug,
+                &AccessLog {
+                    status: 500,
+                    ..entry
+                },
+            );
+        });
+        let logs = String::from_utf8(buf.0.lock().unwrap().clone())?;
+        assert!(logs.contains("access"));
+        assert!(logs.contains("path=\"/load\""));
+        assert!(logs.contains("status=404"));
+        assert!(logs.contains("request_id=\"request-id\""));
+        assert!(!logs.contains("status=500"));
+        Ok(())
+    }
+}
@@ -1,36 +1,129 @@
 use serde_derive::{Deserialize, Serialize};
+use std::cmp;
 use std::collections::HashMap;
 use std::error::Error;
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use tracing::{error, info, info_span, warn, Instrument};
+use uuid::Uuid;
 
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
-use hyper::{body::to_bytes, server::conn::Http, service::service_fn, Body, Request};
+use hyper::{
+    body::HttpBody, header::HeaderValue, server::conn::Http, service::service_fn, Body, Request,
+};
 
+use cache::BlobCache;
+use error::{BodyReadTimeout, BodyTooLarge};
+use ratelimit::{OpCounter, RateLimiter};
 use route_recognizer::Params;
 use router::Router;
 
 use oyster::MolluskStream;
+mod blob;
+mod cache;
+mod config;
 mod database;
+mod error;
 mod handler;
 mod ipfs;
+mod logging;
+mod ratelimit;
 mod router;
 type Response = hyper::Response<hyper::Body>;
 
+const USAGE: &str =
+    "usage: oyster-storage-rs <key file> [config file]\n       oyster-storage-rs --generate-config <path>";
+const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// HTTP versions connections are served with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 with prior knowledge, so requests can be multiplexed over a connection
+    Http2,
+    /// HTTP/1.1, switching to HTTP/2 for clients that open with its preface
+    Auto,
+}
+
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     retry_delay: u64,
     retry_count: u64,
     lock_expiry: u64,
+    max_lock_expiry: u64,
+    min_expiry_ms: i64,
+    max_expiry_ms: i64,
     operation_a_cost: i64,
     operation_b_cost: i64,
     operation_c_cost: i64,
     memory_cost: i64,
+    redis_db: u8,
     ipfs_url: String,
     mem_threshold: usize,
+    storage_format: database::StorageFormat,
+    allowed_pcrs: Vec<String>,
     ipfs_key: String,
     ipfs_secret: String,
+    ipfs_compress: bool,
+    ipfs_cid_version: u8,
+    ipfs_hash: String,
+    ipfs_chunker: String,
+    blob_store: blob::BlobBackend,
+    blob_dir: String,
+    ipfs_cache_bytes: usize,
+    stream_min_bytes: usize,
+    notify_keyspace_events: String,
+    max_watch_timeout: u64,
+    s3_endpoint: String,
+    s3_region: String,
+    s3_bucket: String,
+    s3_access_key: String,
+    s3_secret_key: String,
+    admin_token: String,
+    api_tokens: HashMap<String, String>,
+    require_api_token: bool,
+    max_tree_keys: usize,
+    scan_count: usize,
+    max_batch_keys: usize,
+    shutdown_timeout: u64,
+    http_protocol: HttpProtocol,
+    request_timeout_ms: u64,
+    max_in_flight: usize,
+    header_read_timeout_ms: u64,
+    body_read_timeout_ms: u64,
+    keep_alive: bool,
+    keep_alive_timeout_ms: u64,
+    startup_connect_timeout: u64,
+    access_log: bool,
+    access_log_level: logging::AccessLogLevel,
+    otlp_endpoint: String,
+    trace_redact_keys: bool,
+    health_check_ipfs: bool,
+    max_bytes_per_pcr: i64,
+    max_value_bytes: usize,
+    max_key_bytes: usize,
+    key_separator: String,
+    max_body_bytes: usize,
+    rate_limit_per_sec: u64,
+    rate_limit_burst: u64,
+    max_ops_per_window: u64,
+    cost_flush_interval: u64,
+    trash_retention: u64,
+    trash_purge_interval: u64,
+    max_history_depth: usize,
+    memory_usage_sample_keys: u64,
+    ops_window: u64,
+    compress_min_bytes: usize,
 }
 
 /// `Config` implements `Default`
@@ -40,14 +133,70 @@ impl ::std::default::Default for Config {
             retry_delay: 200, // in millisecond
             retry_count: 5,
             lock_expiry: 30000,         // in millesecond
+            max_lock_expiry: 300000,    // in millesecond
+            min_expiry_ms: 1000,        // in millisecond
+            max_expiry_ms: 31536000000, // in millisecond, 0 for unlimited
             operation_a_cost: 17637500, // (in 10^-15 $) list
-            operation_b_cost: 3527500,  // (in 10^-15 $) store, load, stat
-            operation_c_cost: 1763750,  // (in 10^-15 $) exists
+            operation_b_cost: 3527500,  // (in 10^-15 $) load, stat, lock, unlock
+            operation_c_cost: 1763750,  // (in 10^-15 $) store, delete, exists
             memory_cost: 879583,
+            redis_db: 0,
             ipfs_url: "".to_string(),
             mem_threshold: 1000, // in bytes
+            storage_format: database::StorageFormat::Json,
+            allowed_pcrs: Vec::new(), // any pcr is accepted when empty
             ipfs_key: "".to_string(),
             ipfs_secret: "".to_string(),
+            ipfs_compress: true,
+            ipfs_cid_version: 0, // 0 leaves it to the daemon
+            ipfs_hash: "".to_string(),
+            ipfs_chunker: "".to_string(),
+            blob_store: blob::BlobBackend::Ipfs,
+            blob_dir: "./blobs".to_string(),
+            ipfs_cache_bytes: 67108864, // in bytes, 0 to disable
+            stream_min_bytes: 1048576,  // in bytes
+            notify_keyspace_events: "".to_string(), // left alone when empty
+            max_watch_timeout: 60000,   // in millisecond
+            s3_endpoint: "".to_string(),
+            s3_region: "us-east-1".to_string(),
+            s3_bucket: "".to_string(),
+            s3_access_key: "".to_string(),
+            s3_secret_key: "".to_string(),
+            admin_token: "".to_string(), // admin endpoints are disabled when empty
+            api_tokens: HashMap::new(),  // bearer token per pcr, overriding any set in redis
+            require_api_token: false,    // pcrs without a token are rejected when set
+            max_tree_keys: 10000,
+            scan_count: 1000, // keys redis looks at per SCAN in /list, /tree and /admin/namespaces
+            max_batch_keys: 1000,
+            shutdown_timeout: 30000,            // in millisecond
+            http_protocol: HttpProtocol::Http1, // "http2" or "auto" to multiplex requests
+            request_timeout_ms: 30000,          // in millisecond, 0 for unlimited
+            max_in_flight: 1024,                // requests handled at once, 0 for unlimited
+            header_read_timeout_ms: 10000,      // in millisecond, 0 for unlimited
+            body_read_timeout_ms: 10000,        // in millisecond, 0 for unlimited
+            keep_alive: true,                   // http1 only
+            keep_alive_timeout_ms: 60000,       // in millisecond, 0 for unlimited
+            startup_connect_timeout: 60000,     // in millisecond
+            access_log: true,
+            access_log_level: logging::AccessLogLevel::Info,
+            otlp_endpoint: "".to_string(), // spans aren't exported when empty
+            trace_redact_keys: true,
+            health_check_ipfs: false,
+            max_bytes_per_pcr: 0,           // in bytes, 0 for unlimited
+            max_value_bytes: 10485760,      // in bytes
+            max_key_bytes: 1024,            // in bytes
+            key_separator: "/".to_string(), // not backward compatible with stored data
+            max_body_bytes: 67108864,       // in bytes
+            rate_limit_per_sec: 0,          // requests per pcr, 0 for unlimited
+            rate_limit_burst: 0,            // requests, at least rate_limit_per_sec
+            max_ops_per_window: 0,          // operations per pcr, 0 for unlimited
+            cost_flush_interval: 5000,      // in millisecond
+            trash_retention: 604800000,     // in millisecond
+            trash_purge_interval: 60000,    // in millisecond
+            max_history_depth: 10,          // previous values kept per key
+            memory_usage_sample_keys: 1000, // keys measured per namespace, 0 for all
+            ops_window: 3600000,            // in millisecond
+            compress_min_bytes: 1024,       // in bytes, 0 to never compress
         }
     } // cost per Byte per millisecond (in 10^-23 $)
 }
@@ -60,55 +209,371 @@ pub struct Context {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = std::env::args().collect();
-    let key: [u8; 64] = std::fs::read(&args[1])?.try_into().unwrap();
-    let config: Config = confy::load_path("./config.toml")?;
-    let conn = database::connect().await?;
+    if args.get(1).map(String::as_str) == Some("--generate-config") {
+        let path = args.get(2).ok_or(USAGE)?;
+        config::generate(path)?;
+        println!("wrote the default config to {}", path);
+        return Ok(());
+    }
+    let key_path = args.get(1).ok_or(USAGE)?;
+    let key = read_key(key_path)?;
+    // the config path comes from the command line, then CONFIG_PATH, then the working directory
+    let config_path = args
+        .get(2)
+        .cloned()
+        .or_else(|| std::env::var("CONFIG_PATH").ok())
+        .unwrap_or_else(|| String::from(DEFAULT_CONFIG_PATH));
+    // environment variables override the file, which overrides the defaults
+    let config: Config =
+        config::with_env_overrides(confy::load_path(&config_path)?, std::env::vars())?;
+    if let Err(problems) = config.validate() {
+        // printed as is rather than returned, which would debug format the newlines
+        eprintln!("invalid config {}:", config_path);
+        for problem in problems {
+            eprintln!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+    let log_handle = logging::init(&config.otlp_endpoint)?;
+    let mut conn = connect_with_retry(&config).await?;
+    if !config.notify_keyspace_events.is_empty() {
+        if let Err(e) =
+            database::enable_keyspace_events(&config.notify_keyspace_events, &mut conn).await
+        {
+            warn!(
+                "could not enable keyspace notifications, /subscribe and /watch need them: {}",
+                e
+            );
+        }
+    }
     let cost_map: HashMap<String, i64> = HashMap::new();
+    let blob_cache = BlobCache::new(config.ipfs_cache_bytes);
+    let in_flight = handler::in_flight_limit(config.max_in_flight);
     let server = TcpListener::bind("127.0.0.1:8080").await?;
     let app_state = Arc::new(handler::AppState {
-        conn: Mutex::new(conn),
+        conn,
         config: config,
         cost_map: Mutex::new(cost_map),
+        log_handle,
+        ready: AtomicBool::new(false),
+        blob_cache,
+        rate_limiter: RateLimiter::default(),
+        op_counter: OpCounter::default(),
+        api_tokens: handler::api_token_cache(),
+        rate_limits: handler::rate_limit_cache(),
+        in_flight,
     });
     let mut router: router::Router = router::Router::new();
     router.get("/ping", Box::new(handler::ping));
+    router.get("/health", Box::new(handler::health));
+    router.get("/ready", Box::new(handler::ready));
     router.post("/load", Box::new(handler::load));
+    router.post("/load_stream", Box::new(handler::load_stream));
+    router.head("/load", Box::new(handler::load_head));
+    router.get("/kv/*key", Box::new(handler::kv_get));
+    router.put("/kv/*key", Box::new(handler::kv_put));
+    router.delete("/kv/*key", Box::new(handler::kv_delete));
+    router.head("/kv/*key", Box::new(handler::kv_head));
     router.post("/store", Box::new(handler::store));
+    router.put("/store_stream", Box::new(handler::store_stream));
+    router.post("/swap", Box::new(handler::swap));
+    router.post("/cas_touch", Box::new(handler::cas_touch));
     router.post("/exists", Box::new(handler::exists));
+    router.post("/exists_batch", Box::new(handler::exists_batch));
     router.post("/list", Box::new(handler::list));
+    router.post("/tree", Box::new(handler::tree));
     router.post("/stat", Box::new(handler::stat));
+    router.post("/stat_batch", Box::new(handler::stat_batch));
     router.post("/delete", Box::new(handler::delete));
+    router.post("/cas_delete", Box::new(handler::cas_delete));
+    router.post("/restore", Box::new(handler::restore));
+    router.post("/history", Box::new(handler::history));
+    router.post("/export", Box::new(handler::export));
+    router.put("/import", Box::new(handler::import));
+    router.post("/restore_version", Box::new(handler::restore_version));
+    router.post("/tx", Box::new(handler::tx));
     router.post("/lock", Box::new(handler::lock));
     router.post("/unlock", Box::new(handler::unlock));
+    router.post("/extend_lock", Box::new(handler::extend_lock));
+    router.post("/lock_many", Box::new(handler::lock_many));
+    router.post("/unlock_many", Box::new(handler::unlock_many));
+    router.get("/usage", Box::new(handler::usage));
+    router.get("/subscribe", Box::new(handler::subscribe));
+    router.post("/watch", Box::new(handler::watch));
+    router.get("/log_level", Box::new(handler::get_log_level));
+    router.put("/log_level", Box::new(handler::set_log_level));
+    router.put("/admin/mem_threshold", Box::new(handler::set_mem_threshold));
+    router.put("/admin/rate_limit", Box::new(handler::set_rate_limit));
+    router.put("/admin/history_depth", Box::new(handler::set_history_depth));
+    router.put("/admin/api_token", Box::new(handler::set_api_token));
+    router.get("/admin/namespaces", Box::new(handler::namespaces));
+    router.get("/admin/config", Box::new(handler::get_config));
+    router.post("/admin/memory_usage", Box::new(handler::memory_usage));
+    router.post("/admin/evict", Box::new(handler::evict));
+    router.post("/admin/list_locks", Box::new(handler::list_locks));
+    router.post("/admin/force_unlock", Box::new(handler::force_unlock));
+    router.post("/cost/flush", Box::new(handler::flush_cost));
+
+    // costs are gathered in memory and added to the totals in Redis in the background,
+    // so at most one interval of billing is lost to a crash
+    let flush_state = app_state.clone();
+    let cost_flush = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(cmp::max(
+            flush_state.config.cost_flush_interval,
+            1,
+        )));
+        loop {
+            interval.tick().await;
+            if let Err(e) = handler::flush_costs(&flush_state).await {
+                error!("could not flush costs: {}", e);
+            }
+        }
+    });
+
+    // soft deleted keys are removed by this task rather than a Redis ttl, so their
+    // blobs are released along with them
+    let purge_state = app_state.clone();
+    let trash_purge = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(cmp::max(
+            purge_state.config.trash_purge_interval,
+            1,
+        )));
+        loop {
+            interval.tick().await;
+            match handler::purge_trash(&purge_state).await {
+                Ok(0) => {}
+                Ok(purged) => info!(purged, "purged soft deleted keys"),
+                Err(e) => error!("could not purge soft deleted keys: {}", e),
+            }
+        }
+    });
 
     let shared_router = Arc::new(router);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    app_state.ready.store(true, Ordering::SeqCst);
     loop {
-        let (stream, _) = server.accept().await?;
-        let router_capture = shared_router.clone();
-        let app_state = app_state.clone();
-
-        tokio::task::spawn(async move {
-            match MolluskStream::new_server(stream, key).await {
-                Ok(ss) => {
-                    if let Err(http_err) = Http::new()
-                        .http1_only(true)
-                        .http1_keep_alive(true)
-                        .serve_connection(
-                            ss,
-                            service_fn(move |req| {
-                                route(router_capture.clone(), req, app_state.clone())
-                            }),
-                        )
-                        .await
-                    {
-                        eprintln!("Error while serving HTTP connection: {}", http_err);
-                    }
+        tokio::select! {
+            res = server.accept() => {
+                let (stream, _) = res?;
+                connections.spawn(serve(
+                    stream,
+                    key,
+                    shared_router.clone(),
+                    app_state.clone(),
+                    shutdown_rx.clone(),
+                ));
+            }
+            // reap finished connections so the set only holds live ones
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // report not ready first so load balancers stop routing here, then stop accepting
+    // and ask open connections to finish their in-flight requests
+    app_state.ready.store(false, Ordering::SeqCst);
+    drop(server);
+    info!("shutting down, draining {} connections", connections.len());
+    let _ = shutdown_tx.send(true);
+    let mut drained = 0;
+    let drain = async {
+        while connections.join_next().await.is_some() {
+            drained += 1;
+        }
+    };
+    let drain_timeout = Duration::from_millis(app_state.config.shutdown_timeout);
+    if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+        warn!(
+            "drain timed out, aborting {} connections",
+            connections.len()
+        );
+    }
+    info!("drained {} connections", drained);
+    cost_flush.abort();
+    trash_purge.abort();
+    if let Err(e) = handler::flush_costs(&app_state).await {
+        error!("could not flush costs on shutdown: {}", e);
+    }
+    logging::shutdown();
+    Ok(())
+}
+
+fn read_key(path: &str) -> Result<[u8; 64], Box<dyn Error>> {
+    let key =
+        std::fs::read(path).map_err(|e| format!("could not read key file {}: {}", path, e))?;
+    let key: [u8; 64] = key.try_into().map_err(|key: Vec<u8>| {
+        format!(
+            "key file {} must contain exactly 64 bytes, found {}",
+            path,
+            key.len()
+        )
+    })?;
+    Ok(key)
+}
+
+/// Keeps trying to reach Redis for `config.startup_connect_timeout`, doubling the
+/// delay between attempts from `config.retry_delay`, so the service can start
+/// before Redis is up.
+async fn connect_with_retry(
+    config: &Config,
+) -> Result<redis::aio::ConnectionManager, Box<dyn Error>> {
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_millis(config.startup_connect_timeout);
+    let mut delay = Duration::from_millis(cmp::max(config.retry_delay, 1));
+    let mut attempt = 1;
+    loop {
+        match database::connect(config).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => {
+                let now = tokio::time::Instant::now();
+                if now >= deadline {
+                    error!("giving up connecting to redis after {} attempts", attempt);
+                    return Err(e.into());
+                }
+                warn!(
+                    "could not connect to redis (attempt {}), retrying in {:?}: {}",
+                    attempt, delay, e
+                );
+                tokio::time::sleep(cmp::min(delay, deadline - now)).await;
+                delay = cmp::min(delay * 2, MAX_CONNECT_RETRY_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    key: [u8; 64],
+    router: Arc<Router>,
+    app_state: Arc<handler::AppState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    match MolluskStream::new_server(stream, key).await {
+        Ok(ss) => {
+            let mut http = Http::new();
+            match app_state.config.http_protocol {
+                HttpProtocol::Http1 => {
+                    http.http1_only(true);
+                }
+                HttpProtocol::Http2 => {
+                    http.http2_only(true);
                 }
-                Err(e) => {
-                    eprintln!("Error while serving HTTP connection: {}", e);
+                // hyper serves both by default, telling them apart by the preface
+                HttpProtocol::Auto => {}
+            }
+            http.http1_keep_alive(app_state.config.keep_alive);
+            if app_state.config.header_read_timeout_ms > 0 {
+                http.http1_header_read_timeout(Duration::from_millis(
+                    app_state.config.header_read_timeout_ms,
+                ));
+            }
+            let keep_alive_timeout = match app_state.config.keep_alive_timeout_ms {
+                0 => None,
+                timeout => Some(Duration::from_millis(timeout)),
+            };
+            let activity = Arc::new(ConnActivity::new());
+            let service_activity = activity.clone();
+            let conn = http.serve_connection(
+                ss,
+                service_fn(move |req| {
+                    let activity = service_activity.clone();
+                    let handled = route(router.clone(), req, app_state.clone());
+                    async move {
+                        // finishes on drop too, for requests cut short by the client
+                        let _handling = activity.start();
+                        handled.await
+                    }
+                }),
+            );
+            tokio::pin!(conn);
+            let res = loop {
+                // checked again once the connection could have been idle for the timeout
+                let idle_check = async {
+                    match keep_alive_timeout {
+                        Some(timeout) => {
+                            tokio::time::sleep(timeout.saturating_sub(activity.idle_for())).await
+                        }
+                        None => futures::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    res = conn.as_mut() => break res,
+                    _ = shutdown.changed() => {
+                        conn.as_mut().graceful_shutdown();
+                        break conn.await;
+                    }
+                    _ = idle_check => {
+                        if activity.idle_for() >= keep_alive_timeout.unwrap_or(Duration::MAX) {
+                            conn.as_mut().graceful_shutdown();
+                            break conn.await;
+                        }
+                    }
                 }
+            };
+            if let Err(http_err) = res {
+                error!("error while serving HTTP connection: {}", http_err);
             }
-        });
+        }
+        Err(e) => {
+            error!("error while setting up connection: {}", e);
+        }
+    }
+}
+
+/// Requests being handled on a connection and when it last finished one, so it
+/// can be closed once idle for `keep_alive_timeout_ms`.
+struct ConnActivity {
+    in_flight: AtomicUsize,
+    last_done: std::sync::Mutex<tokio::time::Instant>,
+}
+
+impl ConnActivity {
+    fn new() -> ConnActivity {
+        ConnActivity {
+            in_flight: AtomicUsize::new(0),
+            last_done: std::sync::Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    /// Counts a request as being handled until the returned guard is dropped.
+    fn start(&self) -> Handling<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Handling(self)
+    }
+
+    /// How long the connection has gone without a request to handle, zero while
+    /// it has one.
+    fn idle_for(&self) -> Duration {
+        if self.in_flight.load(Ordering::SeqCst) > 0 {
+            return Duration::ZERO;
+        }
+        match self.last_done.lock() {
+            Ok(last_done) => last_done.elapsed(),
+            Err(_) => Duration::ZERO,
+        }
+    }
+}
+
+struct Handling<'a>(&'a ConnActivity);
+
+impl Drop for Handling<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut last_done) = self.0.last_done.lock() {
+            *last_done = tokio::time::Instant::now();
+        }
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res,
+        _ = sigterm.recv() => Ok(()),
     }
 }
 
@@ -117,22 +582,156 @@ async fn route(
     req: Request<hyper::Body>,
     app_state: Arc<handler::AppState>,
 ) -> Result<Response, Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let found_handler = router.route(req.uri().path(), req.method());
-    let resp = found_handler
-        .handler
-        .invoke(Context::new(app_state, req, found_handler.params))
-        .await;
+    let started = tokio::time::Instant::now();
+    let request_id = Uuid::new_v4().to_string();
+    let span = info_span!(
+        "request",
+        id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path()
+    );
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let pcr = handler::read_pcr(&req).ok();
+    let mut resp = respond(router, req, app_state.clone(), &span).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    if app_state.config.access_log {
+        logging::access(
+            app_state.config.access_log_level,
+            &logging::AccessLog {
+                method: &method,
+                path: &path,
+                pcr: pcr.as_deref(),
+                status: resp.status().as_u16(),
+                duration: started.elapsed(),
+                request_id: &request_id,
+            },
+        );
+    }
     Ok(resp)
 }
 
+/// Runs the request through load shedding, authentication, rate limiting and its
+/// handler, within `request_timeout_ms`.
+async fn respond(
+    router: Arc<Router>,
+    req: Request<hyper::Body>,
+    app_state: Arc<handler::AppState>,
+    span: &tracing::Span,
+) -> Response {
+    let accepts_gzip = handler::accepts_gzip(req.headers());
+    let compress_min_bytes = app_state.config.compress_min_bytes;
+    // shed load rather than queueing on redis without bound
+    let permit = match app_state.in_flight.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            warn!(parent: span, "too many requests in flight, shedding");
+            return handler::overloaded_error();
+        }
+    };
+    let timeout = handler::request_timeout(req.uri().path(), &app_state.config);
+    let state = app_state.clone();
+    // handlers run as their own task, so neither a timeout nor the client going away
+    // stops one part way through the steps of a write
+    let handled = tokio::spawn(
+        async move {
+            // held until the handler is done, even if its response is dropped on timeout
+            let _permit = permit;
+            let found_handler = router.route(req.uri().path(), req.method());
+            // unauthenticated requests don't take from the pcr's rate limit
+            let rejected = match handler::authenticate(&req, &state).await {
+                Some(resp) => Some(resp),
+                None => handler::rate_limit(&req, &state).await,
+            };
+            if let Some(resp) = rejected {
+                return resp;
+            }
+            let op_pcr = handler::op_pcr(&req, &state);
+            let resp = found_handler
+                .handler
+                .invoke(Context::new(state.clone(), req, found_handler.params))
+                .await;
+            // only operations that were carried out count against the quota
+            if let Some(pcr) = op_pcr.filter(|_| resp.status().is_success()) {
+                state.op_counter.record(&pcr);
+            }
+            resp
+        }
+        .instrument(span.clone()),
+    );
+    let joined = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, handled).await {
+            Ok(joined) => joined,
+            Err(_) => {
+                // the handler finishes in the background, only its response is dropped
+                warn!(parent: span, "request timed out after {:?}", timeout);
+                return handler::gateway_timeout_error();
+            }
+        },
+        None => handled.await,
+    };
+    let resp = match joined {
+        Ok(resp) => resp,
+        Err(e) => {
+            error!(parent: span, "handler failed: {}", e);
+            handler::internal_server_error()
+        }
+    };
+    if accepts_gzip {
+        handler::gzip_response(resp, compress_min_bytes).await
+    } else {
+        resp
+    }
+}
+
 impl Context {
     pub fn new(state: Arc<handler::AppState>, req: Request<Body>, params: Params) -> Context {
         Context { state, req, params }
     }
+    /// Reads and parses the JSON body, giving up with `BodyTooLarge` as soon as it
+    /// goes over `max_body_bytes` instead of buffering the whole thing.
     pub async fn body_json<T: serde::de::DeserializeOwned>(
         &mut self,
     ) -> Result<T, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let body = to_bytes(self.req.body_mut()).await?;
+        let body = self.body_bytes().await?;
         Ok(serde_json::from_slice(&body)?)
     }
+
+    /// Reads the whole body, with the same `max_body_bytes` limit as `body_json`.
+    pub async fn body_bytes(
+        &mut self,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let limit = self.state.config.max_body_bytes;
+        if let Some(len) = self
+            .req
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())
+        {
+            if len > limit {
+                return Err(Box::new(BodyTooLarge));
+            }
+        }
+        let read = async {
+            let mut body = Vec::new();
+            while let Some(chunk) = self.req.body_mut().data().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > limit {
+                    return Err(Box::new(BodyTooLarge).into());
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync + 'static>>(body)
+        };
+        // a deadline for the whole body, so trickling it in a byte at a time doesn't help
+        match self.state.config.body_read_timeout_ms {
+            0 => read.await,
+            timeout => tokio::time::timeout(Duration::from_millis(timeout), read)
+                .await
+                .map_err(|_| BodyReadTimeout)?,
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// buckets kept before full ones, which behave the same as missing ones, are dropped
+const MAX_BUCKETS: usize = 10000;
+
+/// Token buckets keyed by pcr, refilled at the request rate allowed for it and
+/// holding up to its burst.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Takes a token for `pcr`, or returns how long until one is available. A `rate`
+    /// of 0 requests per second means unlimited.
+    pub fn acquire(&self, pcr: &str, rate: u64, burst: u64, now: Instant) -> Result<(), Duration> {
+        if rate == 0 {
+            return Ok(());
+        }
+        let burst = cmp::max(burst, rate) as f64;
+        let rate = rate as f64;
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(_) => return Ok(()),
+        };
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * rate).min(burst)
+        };
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+        let bucket = buckets.entry(pcr.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Operations handled per pcr that are yet to be added to their window in Redis,
+/// gathered in memory and flushed along with the costs.
+#[derive(Default)]
+pub struct OpCounter {
+    pending: Mutex<HashMap<String, u64>>,
+}
+
+impl OpCounter {
+    /// Counts an operation handled for `pcr`.
+    pub fn record(&self, pcr: &str) {
+        if let Ok(mut pending) = self.pending.lock() {
+            *pending.entry(pcr.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Operations handled for `pcr` since the counts were last taken.
+    pub fn pending(&self, pcr: &str) -> u64 {
+        match self.pending.lock() {
+            Ok(pending) => pending.get(pcr).copied().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    /// Every pcr's pending count, starting over from none.
+    pub fn take(&self) -> HashMap<String, u64> {
+        match self.pending.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    /// Puts back counts from `take` that could not be flushed.
+    pub fn restore(&self, ops: HashMap<String, u64>) {
+        if let Ok(mut pending) = self.pending.lock() {
+            for (pcr, count) in ops {
+                *pending.entry(pcr).or_default() += count;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        // a burst of 2 at 1 request per second
+        assert!(limiter.acquire("a", 1, 2, now).is_ok());
+        assert!(limiter.acquire("a", 1, 2, now).is_ok());
+        let wait = limiter.acquire("a", 1, 2, now).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        // other pcrs have their own bucket
+        assert!(limiter.acquire("b", 1, 2, now).is_ok());
+        assert!(limiter
+            .acquire("a", 1, 2, now + Duration::from_secs(1))
+            .is_ok());
+        assert!(limiter.acquire("a", 0, 0, now).is_ok());
+    }
+
+    #[test]
+    fn test_op_counter() {
+        let counter = OpCounter::default();
+        counter.record("a");
+        counter.record("a");
+        assert_eq!(2, counter.pending("a"));
+        assert_eq!(0, counter.pending("b"));
+        let ops = counter.take();
+        assert_eq!(Some(&2), ops.get("a"));
+        assert_eq!(0, counter.pending("a"));
+        counter.record("a");
+        counter.restore(ops);
+        assert_eq!(3, counter.pending("a"));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{Context, Response};
 use async_trait::async_trait;
 use futures::future::Future;
-use hyper::{Method, StatusCode};
+use hyper::{header, Method, StatusCode};
 use route_recognizer::{Params, Router as InternalRouter};
 use std::collections::HashMap;
 
@@ -29,27 +29,60 @@ pub struct RouterMatch<'a> {
 
 pub struct Router {
     method_map: HashMap<Method, InternalRouter<Box<dyn Handler>>>,
+    /// methods registered per path, answered with 405 for any other method
+    allowed: HashMap<String, Vec<Method>>,
+    not_allowed: InternalRouter<MethodNotAllowed>,
 }
 
 impl Router {
     pub fn new() -> Router {
         Router {
             method_map: HashMap::default(),
+            allowed: HashMap::default(),
+            not_allowed: InternalRouter::new(),
         }
     }
 
     pub fn get(&mut self, path: &str, handler: Box<dyn Handler>) {
-        self.method_map
-            .entry(Method::GET)
-            .or_insert_with(InternalRouter::new)
-            .add(path, handler)
+        self.add(Method::GET, path, handler)
     }
 
     pub fn post(&mut self, path: &str, handler: Box<dyn Handler>) {
+        self.add(Method::POST, path, handler)
+    }
+
+    pub fn head(&mut self, path: &str, handler: Box<dyn Handler>) {
+        self.add(Method::HEAD, path, handler)
+    }
+
+    pub fn put(&mut self, path: &str, handler: Box<dyn Handler>) {
+        self.add(Method::PUT, path, handler)
+    }
+
+    pub fn delete(&mut self, path: &str, handler: Box<dyn Handler>) {
+        self.add(Method::DELETE, path, handler)
+    }
+
+    fn add(&mut self, method: Method, path: &str, handler: Box<dyn Handler>) {
         self.method_map
-            .entry(Method::POST)
+            .entry(method.clone())
             .or_insert_with(InternalRouter::new)
-            .add(path, handler)
+            .add(path, handler);
+        let methods = self.allowed.entry(path.to_string()).or_default();
+        methods.push(method);
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        // route-recognizer can't replace a route, so rebuild the fallback table. routes
+        // are only added at startup
+        let mut not_allowed = InternalRouter::new();
+        for (path, methods) in &self.allowed {
+            let allow = methods
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            not_allowed.add(path, MethodNotAllowed { allow });
+        }
+        self.not_allowed = not_allowed;
     }
 
     pub fn route(&self, path: &str, method: &Method) -> RouterMatch<'_> {
@@ -62,6 +95,11 @@ impl Router {
                 handler: &***val.handler(),
                 params: val.params().clone(),
             }
+        } else if let Ok(val) = self.not_allowed.recognize(path) {
+            RouterMatch {
+                handler: *val.handler(),
+                params: Params::new(),
+            }
         } else {
             RouterMatch {
                 handler: &not_found_handler,
@@ -78,6 +116,22 @@ async fn not_found_handler(_cx: Context) -> Response {
         .unwrap()
 }
 
+/// Answers a known path requested with a method it wasn't registered for.
+struct MethodNotAllowed {
+    allow: String,
+}
+
+#[async_trait]
+impl Handler for MethodNotAllowed {
+    async fn invoke(&self, _cx: Context) -> Response {
+        hyper::Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, &self.allow)
+            .body("METHOD NOT ALLOWED".into())
+            .unwrap()
+    }
+}
+
 pub trait IntoResponse: Send + Sized {
     fn into_response(self) -> Response;
 }